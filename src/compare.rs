@@ -0,0 +1,480 @@
+//! Lap against lap telemetry comparison aligned by lap distance
+use std::path::Path;
+
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::TelemetryError,
+    flashback::{FlashbackPoint, Rewind},
+    json::{load_json, save_json},
+    packet::Packet,
+    sampling::{DistanceSample, Framed, LapSampler},
+    telemetry::{CarTelemetryData, PacketLapData, MAX_CARS},
+};
+
+/// Minimum distance in metres between two samples of a lap.
+const SAMPLE_SPACING: f32 = 1.0;
+/// A lap must start within this many metres of the line to be recorded.
+const LINE_TOLERANCE: f32 = 50.0;
+
+/// Telemetry at a point of the lap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TelemetrySample {
+    pub lap_distance: f32,
+    pub time_in_ms: u32, // Time into the lap
+    pub speed: u16,      // Kilometres per hour
+    pub throttle: f32,
+    pub brake: f32,
+    pub steer: f32,
+    pub gear: i8,
+    pub engine_rpm: u16,
+}
+
+/// Telemetry channels resampled at a lap distance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Channels {
+    pub time_in_ms: f32,
+    pub speed: f32,
+    pub throttle: f32,
+    pub brake: f32,
+    pub steer: f32,
+    pub gear: i8, // Held from the last sample, gears aren't interpolated
+    pub engine_rpm: f32,
+}
+
+/// The telemetry of a lap indexed by lap distance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TelemetryLap {
+    pub session_uid: u64,
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub lap_time_in_ms: u32,
+    pub valid: bool,
+    pub session_time: f32, // Session time the lap was completed at
+    pub samples: Vec<TelemetrySample>,
+}
+
+/// Both laps at a lap distance.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ComparisonPoint {
+    pub lap_distance: f32,
+    pub reference: Channels,
+    pub lap: Channels,
+    pub delta: f32, // Seconds the lap is behind the reference so far, negative when ahead
+}
+
+/// Two laps resampled onto a common lap distance axis.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LapComparison {
+    pub spacing: f32, // Metres between points
+    pub points: Vec<ComparisonPoint>,
+    pub lap_time_delta: f32, // Seconds, negative when the lap was faster than the reference
+}
+
+impl TelemetryLap {
+    /// Channels at the lap distance, interpolated between samples. None outside the samples.
+    pub fn channels_at(&self, lap_distance: f32) -> Option<Channels> {
+        let next = self
+            .samples
+            .partition_point(|s| s.lap_distance < lap_distance);
+        let after = self.samples.get(next)?;
+        let before = match next.checked_sub(1) {
+            Some(prev) => &self.samples[prev],
+            None if lap_distance >= after.lap_distance => after,
+            None => return None,
+        };
+        let ratio = if after.lap_distance > before.lap_distance {
+            (lap_distance - before.lap_distance) / (after.lap_distance - before.lap_distance)
+        } else {
+            0.0
+        };
+        let lerp = |a: f32, b: f32| a + ratio * (b - a);
+        Some(Channels {
+            time_in_ms: lerp(before.time_in_ms as f32, after.time_in_ms as f32),
+            speed: lerp(before.speed as f32, after.speed as f32),
+            throttle: lerp(before.throttle, after.throttle),
+            brake: lerp(before.brake, after.brake),
+            steer: lerp(before.steer, after.steer),
+            gear: if ratio < 1.0 { before.gear } else { after.gear },
+            engine_rpm: lerp(before.engine_rpm as f32, after.engine_rpm as f32),
+        })
+    }
+
+    /// Resamples this lap and the reference every `spacing` metres over the distance both
+    /// cover, with the cumulative time delta of this lap to the reference.
+    pub fn compare(&self, reference: &TelemetryLap, spacing: f32) -> LapComparison {
+        let end = match (self.samples.last(), reference.samples.last()) {
+            (Some(a), Some(b)) => a.lap_distance.min(b.lap_distance),
+            _ => 0.0,
+        };
+        let start = match (self.samples.first(), reference.samples.first()) {
+            (Some(a), Some(b)) => a.lap_distance.max(b.lap_distance),
+            _ => 0.0,
+        };
+        let spacing = spacing.max(SAMPLE_SPACING);
+        let points = (0..)
+            .map(|idx| (start / spacing).ceil() * spacing + idx as f32 * spacing)
+            .take_while(|lap_distance| *lap_distance <= end)
+            .filter_map(|lap_distance| {
+                let lap = self.channels_at(lap_distance)?;
+                let reference = reference.channels_at(lap_distance)?;
+                Some(ComparisonPoint {
+                    lap_distance,
+                    reference,
+                    lap,
+                    delta: (lap.time_in_ms - reference.time_in_ms) / 1000.0,
+                })
+            })
+            .collect();
+        LapComparison {
+            spacing,
+            points,
+            lap_time_delta: (self.lap_time_in_ms as f32 - reference.lap_time_in_ms as f32) / 1000.0,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<TelemetryLap, TelemetryError> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+impl DistanceSample for TelemetrySample {
+    fn lap_distance(&self) -> f32 {
+        self.lap_distance
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarRecording {
+    lap: Option<Framed<(f32, u32)>>, // Lap distance and time into the lap
+    telemetry: Option<Framed<CarTelemetryData>>,
+    laps: LapSampler<TelemetrySample>,
+    bests: Vec<TelemetryLap>, // Each valid lap which improved on the previous best, fastest last
+}
+
+impl CarRecording {
+    /// Records a sample once the lap data and telemetry of the same frame have both arrived.
+    fn pair(&mut self) {
+        if let Some(sample) = self.sample() {
+            self.laps.push(sample, SAMPLE_SPACING);
+        }
+    }
+
+    fn sample(&self) -> Option<TelemetrySample> {
+        let lap = self.lap.as_ref()?;
+        let t = self.telemetry.as_ref()?.at(lap.frame)?;
+        let (lap_distance, time_in_ms) = lap.value;
+        Some(TelemetrySample {
+            lap_distance,
+            time_in_ms,
+            speed: t.speed,
+            throttle: t.throttle,
+            brake: t.brake,
+            steer: t.steer,
+            gear: t.gear,
+            engine_rpm: t.engine_rpm,
+        })
+    }
+}
+
+/// Records the telemetry of every lap of every car, pairing the lap data and car telemetry
+/// packets of the same frame. The fastest valid lap of each car is kept, other laps are only
+/// returned as they are completed.
+#[derive(Debug, Clone)]
+pub struct TelemetryLapRecorder {
+    session_uid: u64,
+    cars: Vec<CarRecording>,
+}
+
+impl Default for TelemetryLapRecorder {
+    fn default() -> Self {
+        TelemetryLapRecorder {
+            session_uid: 0,
+            cars: vec![CarRecording::default(); MAX_CARS],
+        }
+    }
+}
+
+impl TelemetryLapRecorder {
+    pub fn new() -> Self {
+        TelemetryLapRecorder::default()
+    }
+
+    /// Fastest valid lap of the car this session.
+    pub fn best_lap(&self, car_index: usize) -> Option<&TelemetryLap> {
+        self.cars.get(car_index)?.bests.last()
+    }
+
+    /// Updates the recorder with the packet, returning the laps completed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<TelemetryLap> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = TelemetryLapRecorder::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::LapData(pkt) => self.apply_lap_data(pkt),
+            Packet::CarTelemetry(pkt) => {
+                let frame = header.frame_identifier;
+                for (car, telemetry) in self.cars.iter_mut().zip(pkt.car_telemetry_data.iter()) {
+                    car.telemetry = Some(Framed::new(frame, telemetry.clone()));
+                    car.pair();
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<TelemetryLap> {
+        let frame = pkt.header.frame_identifier;
+        let mut completed = Vec::new();
+        for (car_index, (car, lap)) in self.cars.iter_mut().zip(pkt.lap_data.iter()).enumerate() {
+            car.lap = (lap.lap_distance >= 0.0).then_some(Framed::new(
+                frame,
+                (lap.lap_distance, lap.current_lap_time_in_ms),
+            ));
+            let finished = car.laps.update(lap, lap.current_lap_invalid == 0);
+            car.pair();
+            let finished = match finished {
+                Some(finished) if finished.starts_at_line(LINE_TOLERANCE) => TelemetryLap {
+                    session_uid: pkt.header.session_uid,
+                    car_index,
+                    lap_number: finished.lap_number,
+                    lap_time_in_ms: finished.lap_time_in_ms,
+                    valid: finished.valid,
+                    session_time: pkt.header.session_time,
+                    samples: finished.samples,
+                },
+                _ => continue,
+            };
+            let improved = match car.bests.last() {
+                Some(best) => finished.lap_time_in_ms < best.lap_time_in_ms,
+                None => true,
+            };
+            if finished.valid && finished.lap_time_in_ms > 0 && improved {
+                car.bests.push(finished.clone());
+            }
+            completed.push(finished);
+        }
+        completed
+    }
+}
+
+impl Rewind for TelemetryLapRecorder {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        for car in self.cars.iter_mut() {
+            car.bests
+                .retain(|best| !point.supersedes(best.session_uid, best.session_time));
+            // Restarts recording the lap from the next lap data
+            car.laps.restart();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketCarTelemetryData, testing};
+
+    /// Lap data and telemetry of the same frame for car 0.
+    fn frame(
+        recorder: &mut TelemetryLapRecorder,
+        session_uid: u64,
+        frame: u32,
+        lap: u8,
+        lap_distance: f32,
+        last_lap_time_in_ms: u32,
+    ) -> Vec<TelemetryLap> {
+        let session_time = frame as f32 / 10.0;
+        let mut lap_data: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, frame, session_time);
+        let data = &mut lap_data.lap_data[0];
+        data.current_lap_num = lap;
+        data.lap_distance = lap_distance;
+        data.current_lap_time_in_ms = (lap_distance * 20.0) as u32;
+        data.last_lap_time_in_ms = last_lap_time_in_ms;
+        let mut telemetry: PacketCarTelemetryData =
+            testing::packet(PacketId::CarTelemetry, session_uid, frame, session_time);
+        telemetry.car_telemetry_data[0].speed = 200;
+        let completed = recorder.apply(&Packet::LapData(Box::new(lap_data)));
+        recorder.apply(&Packet::CarTelemetry(Box::new(telemetry)));
+        completed
+    }
+
+    /// Drives car 0 from `start` metres to the end of the lap and over the line.
+    fn drive_lap(
+        recorder: &mut TelemetryLapRecorder,
+        session_uid: u64,
+        first_frame: u32,
+        lap: u8,
+        start: u32,
+        lap_time_in_ms: u32,
+    ) -> Vec<TelemetryLap> {
+        for (idx, metres) in (start..1000).step_by(10).enumerate() {
+            frame(
+                recorder,
+                session_uid,
+                first_frame + idx as u32,
+                lap,
+                metres as f32,
+                0,
+            );
+        }
+        let last_frame = first_frame + (1000 - start) / 10;
+        frame(
+            recorder,
+            session_uid,
+            last_frame,
+            lap + 1,
+            0.0,
+            lap_time_in_ms,
+        )
+    }
+
+    /// A lap sampled every 10 metres from `first` to `last` metres, `ms_per_metre` into the lap.
+    fn telemetry_lap(first: u32, last: u32, ms_per_metre: u32) -> TelemetryLap {
+        TelemetryLap {
+            session_uid: 1,
+            car_index: 0,
+            lap_number: 1,
+            lap_time_in_ms: 1000 * ms_per_metre,
+            valid: true,
+            session_time: 0.0,
+            samples: (first..=last)
+                .step_by(10)
+                .map(|metres| TelemetrySample {
+                    lap_distance: metres as f32,
+                    time_in_ms: metres * ms_per_metre,
+                    speed: 100 + metres as u16 / 10,
+                    throttle: metres as f32 / 1000.0,
+                    brake: 0.0,
+                    steer: 0.0,
+                    gear: 1 + (metres / 250) as i8,
+                    engine_rpm: 10_000,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn channels_are_interpolated_between_samples() {
+        let lap = telemetry_lap(0, 1000, 20);
+        let channels = lap.channels_at(15.0).unwrap();
+        assert_eq!(channels.time_in_ms, 300.0);
+        assert_eq!(channels.speed, 101.5);
+        assert!((channels.throttle - 0.015).abs() < 1e-6);
+        assert_eq!(channels.engine_rpm, 10_000.0);
+        // Gears are held until the sample they change at
+        assert_eq!(lap.channels_at(245.0).unwrap().gear, 1);
+        assert_eq!(lap.channels_at(250.0).unwrap().gear, 2);
+    }
+
+    #[test]
+    fn channels_outside_the_lap_are_none() {
+        let lap = telemetry_lap(100, 900, 20);
+        assert_eq!(lap.channels_at(50.0), None);
+        assert_eq!(lap.channels_at(950.0), None);
+        assert_eq!(lap.channels_at(100.0).unwrap().time_in_ms, 2000.0);
+        assert_eq!(lap.channels_at(900.0).unwrap().time_in_ms, 18_000.0);
+    }
+
+    #[test]
+    fn comparison_is_over_the_distance_both_laps_cover() {
+        let lap = telemetry_lap(0, 990, 22);
+        let reference = telemetry_lap(3, 993, 20);
+        let comparison = lap.compare(&reference, 25.0);
+        assert_eq!(comparison.spacing, 25.0);
+        let distances: Vec<f32> = comparison.points.iter().map(|p| p.lap_distance).collect();
+        let expected: Vec<f32> = (1..=39).map(|idx| idx as f32 * 25.0).collect();
+        assert_eq!(distances, expected);
+        // 2 ms lost per metre
+        for point in comparison.points.iter() {
+            let delta = point.lap_distance * 0.002;
+            assert!((point.delta - delta).abs() < 1e-4, "{:?}", point);
+        }
+        assert!((comparison.lap_time_delta - 2.0).abs() < 1e-6);
+        assert!((reference.compare(&lap, 25.0).lap_time_delta + 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn comparison_spacing_is_at_least_the_sample_spacing() {
+        let lap = telemetry_lap(0, 100, 20);
+        let comparison = lap.compare(&lap, 0.1);
+        assert_eq!(comparison.spacing, SAMPLE_SPACING);
+        assert_eq!(comparison.points.len(), 101);
+        assert!(comparison.points.iter().all(|p| p.delta == 0.0));
+    }
+
+    #[test]
+    fn lap_is_recorded_from_paired_frames() {
+        let mut recorder = TelemetryLapRecorder::new();
+        let completed = drive_lap(&mut recorder, 1, 0, 1, 0, 60_000);
+        let [lap] = &completed[..] else {
+            panic!("expected one lap, got {:?}", completed);
+        };
+        assert_eq!((lap.lap_number, lap.lap_time_in_ms), (1, 60_000));
+        assert_eq!(lap.samples.len(), 100);
+        assert!(lap.samples.iter().all(|s| s.speed == 200));
+        assert_eq!(recorder.best_lap(0), Some(lap));
+    }
+
+    #[test]
+    fn telemetry_of_another_frame_isnt_paired() {
+        let mut recorder = TelemetryLapRecorder::new();
+        frame(&mut recorder, 1, 0, 1, 0.0, 0);
+        let mut lap_data: PacketLapData = testing::packet(PacketId::LapData, 1, 1, 0.1);
+        lap_data.lap_data[0].current_lap_num = 1;
+        lap_data.lap_data[0].lap_distance = 10.0;
+        recorder.apply(&Packet::LapData(Box::new(lap_data)));
+        let completed = frame(&mut recorder, 1, 2, 2, 0.0, 60_000);
+        assert_eq!(completed[0].samples.len(), 1);
+    }
+
+    #[test]
+    fn lap_joined_part_way_round_isnt_returned() {
+        let mut recorder = TelemetryLapRecorder::new();
+        assert!(drive_lap(&mut recorder, 1, 0, 1, 500, 60_000).is_empty());
+        assert_eq!(drive_lap(&mut recorder, 1, 100, 2, 0, 61_000).len(), 1);
+    }
+
+    #[test]
+    fn only_improving_valid_laps_are_kept() {
+        let mut recorder = TelemetryLapRecorder::new();
+        drive_lap(&mut recorder, 1, 0, 1, 0, 60_000);
+        drive_lap(&mut recorder, 1, 200, 2, 0, 61_000);
+        assert_eq!(recorder.best_lap(0).unwrap().lap_number, 1);
+        drive_lap(&mut recorder, 1, 400, 3, 0, 59_000);
+        assert_eq!(recorder.best_lap(0).unwrap().lap_number, 3);
+    }
+
+    #[test]
+    fn flashback_drops_the_laps_after_the_point() {
+        let mut recorder = TelemetryLapRecorder::new();
+        drive_lap(&mut recorder, 1, 0, 1, 0, 60_000);
+        drive_lap(&mut recorder, 1, 200, 2, 0, 59_000);
+        recorder.apply(&testing::flashback(1, 301, 30.1, 150, 15.0));
+        assert_eq!(recorder.best_lap(0).unwrap().lap_number, 1);
+        // The lap in progress restarts, so the next crossing completes nothing
+        assert!(frame(&mut recorder, 1, 151, 3, 0.0, 58_000).is_empty());
+    }
+
+    #[test]
+    fn session_change_resets_the_laps() {
+        let mut recorder = TelemetryLapRecorder::new();
+        drive_lap(&mut recorder, 1, 0, 1, 0, 60_000);
+        frame(&mut recorder, 2, 0, 1, 0.0, 0);
+        assert!(recorder.best_lap(0).is_none());
+    }
+}
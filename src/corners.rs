@@ -0,0 +1,607 @@
+//! Corner detection and per corner performance
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    delta::{LapTrace, TracePoint},
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    sampling::{DistanceSample, Framed, LapSampler, SampledLap},
+    telemetry::{CarTelemetryData, PacketLapData, MAX_CARS},
+};
+
+/// Minimum distance in metres between two samples of a lap.
+const SAMPLE_SPACING: f32 = 1.0;
+/// Lateral g-force above which the car is cornering.
+const LATERAL_G_THRESHOLD: f32 = 1.0;
+/// Steering input above which the car is cornering.
+const STEER_THRESHOLD: f32 = 0.15;
+/// Cornering sections closer than this many metres are one corner, e.g. a chicane.
+const MERGE_DISTANCE: f32 = 30.0;
+/// Shorter cornering sections are corrections rather than corners.
+const MIN_CORNER_LENGTH: f32 = 20.0;
+/// Brake input above which the car is braking.
+const BRAKE_THRESHOLD: f32 = 0.1;
+/// Distance in metres before the apex searched for the braking point.
+const BRAKING_SEARCH: f32 = 400.0;
+/// A lap must start within this many metres of the line to be used.
+const LINE_TOLERANCE: f32 = 50.0;
+
+/// Car inputs and state at a point of the lap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LapSample {
+    pub lap_distance: f32,
+    pub time_in_ms: u32, // Time into the lap
+    pub speed: u16,      // Kilometres per hour
+    pub throttle: f32,
+    pub brake: f32,
+    pub steer: f32,
+    pub lateral_g: f32,
+}
+
+/// A corner of the track, the same on every lap.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Corner {
+    pub number: usize,       // Counted from the line, starting at 1
+    pub name: String,        // "T" and the number
+    pub entry_distance: f32, // Braking point, or where the car starts turning if it doesn't brake
+    pub apex_distance: f32,  // Slowest point
+    pub exit_distance: f32,  // Where the car stops turning
+}
+
+/// How a car took a corner on a lap.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CornerPerformance {
+    pub number: usize,
+    pub name: String,
+    pub braking_point: Option<f32>, // Lap distance the car started braking at, None if it didn't
+    pub entry_speed: u16,           // Kilometres per hour at the corner entry
+    pub apex_speed: u16,            // Lowest speed through the corner
+    pub apex_distance: f32,         // Lap distance of the lowest speed
+    pub exit_speed: u16,            // Kilometres per hour at the corner exit
+    pub time_in_ms: f32,            // Time from the corner entry to the exit
+    pub time_lost: Option<f32>,     // Seconds lost to the reference lap, negative when faster
+}
+
+/// Part of the lap between two corners.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Straight {
+    pub start_distance: f32,
+    pub end_distance: f32,
+    pub top_speed: u16,
+    pub time_in_ms: f32,
+    pub time_lost: Option<f32>, // Seconds lost to the reference lap, negative when faster
+}
+
+/// A completed lap broken down into corners and straights.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LapCorners {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub lap_time_in_ms: u32,
+    pub valid: bool,
+    pub corners: Vec<CornerPerformance>,
+    pub straights: Vec<Straight>,
+    pub session_time: f32, // Session time the lap was completed at
+}
+
+impl DistanceSample for LapSample {
+    fn lap_distance(&self) -> f32 {
+        self.lap_distance
+    }
+}
+
+/// A completed lap, with its trace for the time between two lap distances.
+#[derive(Debug, Clone)]
+struct RecordedLap {
+    valid: bool,
+    samples: Vec<LapSample>,
+    trace: LapTrace,
+}
+
+impl RecordedLap {
+    fn new(car_index: usize, lap: SampledLap<LapSample>, session_time: f32) -> Self {
+        let trace = LapTrace {
+            car_index,
+            lap_number: lap.lap_number,
+            lap_time_in_ms: lap.lap_time_in_ms,
+            session_time,
+            points: lap
+                .samples
+                .iter()
+                .map(|s| TracePoint {
+                    lap_distance: s.lap_distance,
+                    time_in_ms: s.time_in_ms,
+                })
+                .collect(),
+        };
+        RecordedLap {
+            valid: lap.valid,
+            samples: lap.samples,
+            trace,
+        }
+    }
+
+    fn speed_at(&self, lap_distance: f32) -> Option<u16> {
+        let next = self
+            .samples
+            .partition_point(|s| s.lap_distance < lap_distance);
+        self.samples
+            .get(next)
+            .or_else(|| self.samples.last())
+            .map(|s| s.speed)
+    }
+
+    fn between(&self, start: f32, end: f32) -> &[LapSample] {
+        let from = self.samples.partition_point(|s| s.lap_distance < start);
+        let to = self.samples.partition_point(|s| s.lap_distance <= end);
+        &self.samples[from..to.max(from)]
+    }
+
+    fn time_between(&self, start: f32, end: f32) -> Option<f32> {
+        Some(self.trace.time_at(end)? - self.trace.time_at(start)?)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarCorners {
+    lap: Option<Framed<(f32, u32)>>, // Lap distance and time into the lap
+    lateral_g: Option<Framed<f32>>,
+    telemetry: Option<Framed<CarTelemetryData>>,
+    recording: LapSampler<LapSample>,
+    bests: Vec<RecordedLap>, // Each valid lap which improved on the previous best, fastest last
+    laps: Vec<LapCorners>,
+}
+
+impl CarCorners {
+    /// Records a sample once the lap data, motion and telemetry of the same frame have arrived.
+    fn pair(&mut self) {
+        if let Some(sample) = self.sample() {
+            self.recording.push(sample, SAMPLE_SPACING);
+        }
+    }
+
+    fn sample(&self) -> Option<LapSample> {
+        let lap = self.lap.as_ref()?;
+        let lateral_g = *self.lateral_g.as_ref()?.at(lap.frame)?;
+        let t = self.telemetry.as_ref()?.at(lap.frame)?;
+        let (lap_distance, time_in_ms) = lap.value;
+        Some(LapSample {
+            lap_distance,
+            time_in_ms,
+            speed: t.speed,
+            throttle: t.throttle,
+            brake: t.brake,
+            steer: t.steer,
+            lateral_g,
+        })
+    }
+}
+
+/// Segments the laps of every car into corners and straights. The corners are detected from the
+/// steering, lateral g-force and speed of the first valid lap of the session, and every lap is
+/// then measured against the same corners so they are labelled consistently. Time lost is
+/// against the fastest valid lap of the car, or of the reference car if set.
+#[derive(Debug, Clone)]
+pub struct CornerTracker {
+    session_uid: u64,
+    reference_car: Option<usize>,
+    corners: Vec<Corner>,
+    corners_from: Option<f32>, // Session time the lap the corners were detected from completed at
+    cars: Vec<CarCorners>,
+}
+
+impl Default for CornerTracker {
+    fn default() -> Self {
+        CornerTracker {
+            session_uid: 0,
+            reference_car: None,
+            corners: Vec::new(),
+            corners_from: None,
+            cars: vec![CarCorners::default(); MAX_CARS],
+        }
+    }
+}
+
+impl CornerTracker {
+    pub fn new() -> Self {
+        CornerTracker::default()
+    }
+
+    /// Compares every car against the fastest lap of this car, e.g. a teammate.
+    pub fn reference_car(mut self, car_index: usize) -> Self {
+        self.reference_car = Some(car_index);
+        self
+    }
+
+    /// Corners detected this session, empty until a valid lap was completed.
+    pub fn corners(&self) -> &[Corner] {
+        &self.corners
+    }
+
+    /// Completed laps of the car broken down into corners, oldest first.
+    pub fn laps(&self, car_index: usize) -> &[LapCorners] {
+        self.cars.get(car_index).map_or(&[], |car| &car.laps[..])
+    }
+
+    /// Updates the tracker with the packet, returning the breakdown of the laps completed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<LapCorners> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = CornerTracker {
+                reference_car: self.reference_car,
+                ..CornerTracker::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        let frame = header.frame_identifier;
+        match packet {
+            Packet::LapData(pkt) => return self.apply_lap_data(pkt),
+            Packet::Motion(pkt) => {
+                for (car, motion) in self.cars.iter_mut().zip(pkt.car_motion_data.iter()) {
+                    car.lateral_g = Some(Framed::new(frame, motion.g_force_lateral));
+                    car.pair();
+                }
+            }
+            Packet::CarTelemetry(pkt) => {
+                for (car, telemetry) in self.cars.iter_mut().zip(pkt.car_telemetry_data.iter()) {
+                    car.telemetry = Some(Framed::new(frame, telemetry.clone()));
+                    car.pair();
+                }
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<LapCorners> {
+        let frame = pkt.header.frame_identifier;
+        let mut completed = Vec::new();
+        for (car_index, lap) in pkt.lap_data.iter().enumerate() {
+            let car = &mut self.cars[car_index];
+            car.lap = (lap.lap_distance >= 0.0).then_some(Framed::new(
+                frame,
+                (lap.lap_distance, lap.current_lap_time_in_ms),
+            ));
+            let finished = car.recording.update(lap, lap.current_lap_invalid == 0);
+            car.pair();
+            if let Some(finished) = finished.filter(|lap| lap.starts_at_line(LINE_TOLERANCE)) {
+                let lap = RecordedLap::new(car_index, finished, pkt.header.session_time);
+                completed.push(self.lap_completed(car_index, lap));
+            }
+        }
+        completed
+    }
+
+    fn lap_completed(&mut self, car_index: usize, lap: RecordedLap) -> LapCorners {
+        if self.corners.is_empty() && lap.valid {
+            self.corners = detect_corners(&lap.samples);
+            self.corners_from = Some(lap.trace.session_time);
+        }
+        let reference = self.cars[self.reference_car.unwrap_or(car_index)]
+            .bests
+            .last();
+        let time_lost = |start: f32, end: f32| {
+            let time = lap.time_between(start, end)?;
+            Some((time - reference?.time_between(start, end)?) / 1000.0)
+        };
+
+        let corners: Vec<CornerPerformance> = self
+            .corners
+            .iter()
+            .map(|corner| {
+                let through = lap.between(corner.entry_distance, corner.exit_distance);
+                let apex = through
+                    .iter()
+                    .min_by_key(|s| s.speed)
+                    .copied()
+                    .unwrap_or(LapSample {
+                        lap_distance: corner.apex_distance,
+                        speed: lap.speed_at(corner.apex_distance).unwrap_or(0),
+                        time_in_ms: 0,
+                        throttle: 0.0,
+                        brake: 0.0,
+                        steer: 0.0,
+                        lateral_g: 0.0,
+                    });
+                CornerPerformance {
+                    number: corner.number,
+                    name: corner.name.clone(),
+                    braking_point: braking_point(&lap.samples, apex.lap_distance)
+                        .map(|idx| lap.samples[idx].lap_distance),
+                    entry_speed: lap.speed_at(corner.entry_distance).unwrap_or(0),
+                    apex_speed: apex.speed,
+                    apex_distance: apex.lap_distance,
+                    exit_speed: lap.speed_at(corner.exit_distance).unwrap_or(0),
+                    time_in_ms: lap
+                        .time_between(corner.entry_distance, corner.exit_distance)
+                        .unwrap_or(0.0),
+                    time_lost: time_lost(corner.entry_distance, corner.exit_distance),
+                }
+            })
+            .collect();
+
+        let lap_end = lap.samples.last().map_or(0.0, |s| s.lap_distance);
+        let mut bounds = vec![0.0];
+        for corner in self.corners.iter() {
+            bounds.push(corner.entry_distance);
+            bounds.push(corner.exit_distance);
+        }
+        bounds.push(lap_end);
+        let straights: Vec<Straight> = bounds
+            .chunks(2)
+            .filter(|bound| bound.len() == 2 && bound[1] > bound[0])
+            .map(|bound| Straight {
+                start_distance: bound[0],
+                end_distance: bound[1],
+                top_speed: lap
+                    .between(bound[0], bound[1])
+                    .iter()
+                    .map(|s| s.speed)
+                    .max()
+                    .unwrap_or(0),
+                time_in_ms: lap.time_between(bound[0], bound[1]).unwrap_or(0.0),
+                time_lost: time_lost(bound[0], bound[1]),
+            })
+            .collect();
+
+        let lap_corners = LapCorners {
+            car_index,
+            lap_number: lap.trace.lap_number,
+            lap_time_in_ms: lap.trace.lap_time_in_ms,
+            valid: lap.valid,
+            corners,
+            straights,
+            session_time: lap.trace.session_time,
+        };
+        let car = &mut self.cars[car_index];
+        let improved = match car.bests.last() {
+            Some(best) => lap.trace.lap_time_in_ms < best.trace.lap_time_in_ms,
+            None => true,
+        };
+        if lap.valid && lap.trace.lap_time_in_ms > 0 && improved {
+            car.bests.push(lap);
+        }
+        car.laps.push(lap_corners.clone());
+        lap_corners
+    }
+}
+
+impl Rewind for CornerTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        if let Some(session_time) = self.corners_from {
+            if point.supersedes(point.session_uid, session_time) {
+                // Detected again from the next valid lap
+                self.corners.clear();
+                self.corners_from = None;
+            }
+        }
+        for car in self.cars.iter_mut() {
+            car.laps
+                .retain(|lap| !point.supersedes(point.session_uid, lap.session_time));
+            car.bests
+                .retain(|best| !point.supersedes(point.session_uid, best.trace.session_time));
+            // Restarts recording the lap from the next lap data
+            car.recording.restart();
+        }
+    }
+}
+
+/// Finds the corners of a lap, runs of samples where the car is steering or cornering hard.
+fn detect_corners(samples: &[LapSample]) -> Vec<Corner> {
+    let cornering = |s: &LapSample| {
+        s.lateral_g.abs() >= LATERAL_G_THRESHOLD || s.steer.abs() >= STEER_THRESHOLD
+    };
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (idx, sample) in samples.iter().enumerate() {
+        if !cornering(sample) {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((_, end))
+                if sample.lap_distance - samples[*end].lap_distance <= MERGE_DISTANCE =>
+            {
+                *end = idx
+            }
+            _ => runs.push((idx, idx)),
+        }
+    }
+    runs.into_iter()
+        .filter(|(start, end)| {
+            samples[*end].lap_distance - samples[*start].lap_distance >= MIN_CORNER_LENGTH
+        })
+        .enumerate()
+        .map(|(idx, (start, end))| {
+            let apex = (start..=end)
+                .min_by_key(|idx| samples[*idx].speed)
+                .unwrap_or(start);
+            let entry = braking_point(samples, samples[apex].lap_distance)
+                .map_or(start, |braking| braking.min(start));
+            Corner {
+                number: idx + 1,
+                name: format!("T{}", idx + 1),
+                entry_distance: samples[entry].lap_distance,
+                apex_distance: samples[apex].lap_distance,
+                exit_distance: samples[end].lap_distance,
+            }
+        })
+        .collect()
+}
+
+/// Index of the sample the car started braking at for the apex, searching back from the apex.
+fn braking_point(samples: &[LapSample], apex_distance: f32) -> Option<usize> {
+    let apex = samples.partition_point(|s| s.lap_distance < apex_distance);
+    let limit = apex_distance - BRAKING_SEARCH;
+    let mut found = None;
+    for idx in (0..apex.min(samples.len())).rev() {
+        if samples[idx].lap_distance < limit {
+            break;
+        }
+        if samples[idx].brake >= BRAKE_THRESHOLD {
+            found = Some(idx);
+        } else if found.is_some() {
+            break;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarTelemetryData, PacketMotionData},
+        testing,
+    };
+
+    /// Lap data, motion and telemetry of the same frame for car 0. The car brakes from 350
+    /// metres for a corner from 400 to 490 metres with the apex at 450 metres, `extra` ms are
+    /// lost through the corner.
+    fn frame(
+        tracker: &mut CornerTracker,
+        session_uid: u64,
+        frame: u32,
+        lap: u8,
+        lap_distance: f32,
+        extra: f32,
+    ) -> Vec<LapCorners> {
+        let session_time = frame as f32 / 10.0;
+        let lost = ((lap_distance - 400.0) / 100.0).clamp(0.0, 1.0) * extra;
+        let mut lap_data: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, frame, session_time);
+        let data = &mut lap_data.lap_data[0];
+        data.current_lap_num = lap;
+        data.lap_distance = lap_distance;
+        data.current_lap_time_in_ms = (lap_distance * 20.0 + lost) as u32;
+        data.last_lap_time_in_ms = if lap > 1 { 20_000 + extra as u32 } else { 0 };
+        let cornering = (400.0..500.0).contains(&lap_distance);
+        let mut motion: PacketMotionData =
+            testing::packet(PacketId::Motion, session_uid, frame, session_time);
+        motion.car_motion_data[0].g_force_lateral = if cornering { 2.0 } else { 0.0 };
+        let mut telemetry: PacketCarTelemetryData =
+            testing::packet(PacketId::CarTelemetry, session_uid, frame, session_time);
+        let t = &mut telemetry.car_telemetry_data[0];
+        t.speed = 300 - (150.0 - (lap_distance - 450.0).abs()).clamp(0.0, 150.0) as u16;
+        t.steer = if cornering { 0.5 } else { 0.0 };
+        t.brake = if (350.0..=420.0).contains(&lap_distance) {
+            0.5
+        } else {
+            0.0
+        };
+        let completed = tracker.apply(&Packet::LapData(Box::new(lap_data)));
+        tracker.apply(&Packet::Motion(Box::new(motion)));
+        tracker.apply(&Packet::CarTelemetry(Box::new(telemetry)));
+        completed
+    }
+
+    /// Drives car 0 round a 1000 metre lap and over the line.
+    fn drive_lap(
+        tracker: &mut CornerTracker,
+        session_uid: u64,
+        first_frame: u32,
+        lap: u8,
+        extra: f32,
+    ) -> Vec<LapCorners> {
+        for metres in (0..1000).step_by(10) {
+            let frame_identifier = first_frame + metres / 10;
+            frame(
+                tracker,
+                session_uid,
+                frame_identifier,
+                lap,
+                metres as f32,
+                extra,
+            );
+        }
+        frame(tracker, session_uid, first_frame + 100, lap + 1, 0.0, extra)
+    }
+
+    #[test]
+    fn corner_is_detected_from_the_first_valid_lap() {
+        let mut tracker = CornerTracker::new();
+        let completed = drive_lap(&mut tracker, 1, 0, 1, 0.0);
+        assert_eq!(
+            tracker.corners(),
+            &[Corner {
+                number: 1,
+                name: "T1".to_string(),
+                entry_distance: 350.0,
+                apex_distance: 450.0,
+                exit_distance: 490.0,
+            }]
+        );
+        let [lap] = &completed[..] else {
+            panic!("expected one lap, got {:?}", completed);
+        };
+        let corner = &lap.corners[0];
+        assert_eq!(corner.braking_point, Some(350.0));
+        assert_eq!((corner.apex_speed, corner.apex_distance), (150, 450.0));
+        assert_eq!(corner.time_in_ms, 2800.0);
+        assert_eq!(corner.time_lost, None); // No best lap to compare with yet
+        assert_eq!(lap.straights.len(), 2);
+        assert_eq!(tracker.laps(0), &completed[..]);
+    }
+
+    #[test]
+    fn time_lost_is_against_the_best_lap() {
+        let mut tracker = CornerTracker::new();
+        drive_lap(&mut tracker, 1, 0, 1, 0.0);
+        let completed = drive_lap(&mut tracker, 1, 200, 2, 1000.0);
+        let lap = &completed[0];
+        let lost = lap.corners[0].time_lost.unwrap();
+        assert!((lost - 0.9).abs() < 0.01, "{}", lost);
+        assert_eq!(lap.straights[0].time_lost, Some(0.0));
+    }
+
+    #[test]
+    fn flashback_drops_the_laps_after_the_point() {
+        let mut tracker = CornerTracker::new();
+        drive_lap(&mut tracker, 1, 0, 1, 0.0);
+        drive_lap(&mut tracker, 1, 200, 2, 0.0);
+        tracker.apply(&testing::flashback(1, 301, 30.1, 150, 15.0));
+        assert_eq!(tracker.laps(0).len(), 1);
+        // The lap in progress restarts, so the next crossing completes nothing
+        assert!(frame(&mut tracker, 1, 151, 3, 0.0, 0.0).is_empty());
+    }
+
+    #[test]
+    fn flashback_drops_the_best_lap_after_the_point() {
+        let mut tracker = CornerTracker::new();
+        drive_lap(&mut tracker, 1, 0, 1, 500.0);
+        // Lap 2 is the best lap until the flashback discards it
+        drive_lap(&mut tracker, 1, 200, 2, 0.0);
+        tracker.apply(&testing::flashback(1, 301, 30.1, 150, 15.0));
+        let completed = drive_lap(&mut tracker, 1, 151, 2, 1000.0);
+        // 0.45 seconds lost through the corner to lap 1, rather than 0.9 to the discarded lap
+        let lost = completed[0].corners[0].time_lost.unwrap();
+        assert!((lost - 0.45).abs() < 0.01, "{}", lost);
+    }
+
+    #[test]
+    fn flashback_before_the_first_lap_detects_the_corners_again() {
+        let mut tracker = CornerTracker::new();
+        drive_lap(&mut tracker, 1, 0, 1, 0.0);
+        assert_eq!(tracker.corners().len(), 1);
+        tracker.apply(&testing::flashback(1, 101, 10.1, 50, 5.0));
+        assert!(tracker.corners().is_empty());
+        drive_lap(&mut tracker, 1, 51, 1, 0.0);
+        drive_lap(&mut tracker, 1, 151, 2, 0.0);
+        assert_eq!(tracker.corners().len(), 1);
+    }
+
+    #[test]
+    fn session_change_resets_the_corners() {
+        let mut tracker = CornerTracker::new().reference_car(1);
+        drive_lap(&mut tracker, 1, 0, 1, 0.0);
+        frame(&mut tracker, 2, 0, 1, 0.0, 0.0);
+        assert!(tracker.corners().is_empty());
+        assert!(tracker.laps(0).is_empty());
+        assert_eq!(tracker.reference_car, Some(1));
+    }
+}
@@ -0,0 +1,313 @@
+//! Live delta to a reference lap, like the delta bar in the car
+use std::path::Path;
+
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::TelemetryError,
+    flashback::{FlashbackPoint, Rewind},
+    json::{load_json, save_json},
+    packet::Packet,
+    sampling::{DistanceSample, LapSampler},
+    telemetry::{PacketLapData, MAX_CARS},
+};
+
+/// Minimum distance in metres between two points of a trace.
+const POINT_SPACING: f32 = 2.0;
+/// A trace must start within this many metres of the line to count as a full lap.
+const START_TOLERANCE: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TracePoint {
+    pub lap_distance: f32, // Distance around the lap in metres
+    pub time_in_ms: u32,   // Time into the lap in milliseconds
+}
+
+/// Time into a lap indexed by lap distance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LapTrace {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub lap_time_in_ms: u32,
+    pub session_time: f32, // Session time the lap was completed at
+    pub points: Vec<TracePoint>,
+}
+
+impl LapTrace {
+    /// Time into the lap at the lap distance in milliseconds, interpolated between points.
+    pub fn time_at(&self, lap_distance: f32) -> Option<f32> {
+        let next = self
+            .points
+            .partition_point(|p| p.lap_distance < lap_distance);
+        let after = self.points.get(next)?;
+        let before = match next.checked_sub(1) {
+            Some(prev) => self.points[prev],
+            None => return Some(after.time_in_ms as f32),
+        };
+        if after.lap_distance <= before.lap_distance {
+            return Some(after.time_in_ms as f32);
+        }
+        let ratio =
+            (lap_distance - before.lap_distance) / (after.lap_distance - before.lap_distance);
+        Some(
+            before.time_in_ms as f32 + ratio * (after.time_in_ms as f32 - before.time_in_ms as f32),
+        )
+    }
+
+    /// Loads a trace saved with [`LapTrace::save`], e.g. a personal best from an earlier session.
+    pub fn load(path: impl AsRef<Path>) -> Result<LapTrace, TelemetryError> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+impl DistanceSample for TracePoint {
+    fn lap_distance(&self) -> f32 {
+        self.lap_distance
+    }
+}
+
+/// Lap the delta is calculated against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReferenceLap {
+    SessionBest,   // The fastest valid lap of any car this session
+    Car(usize),    // The fastest valid lap of another car this session, e.g. a teammate
+    Lap(LapTrace), // A fixed lap, e.g. a personal best loaded from file
+}
+
+/// Delta of the current lap to the reference lap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Delta {
+    pub lap_number: u8,
+    pub lap_distance: f32,
+    pub current_lap_time_in_ms: u32,
+    pub delta: f32,                    // Seconds, negative when ahead of the reference
+    pub predicted_lap_time_in_ms: u32, // Reference lap time plus the current delta
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarTraces {
+    laps: LapSampler<TracePoint>,
+    bests: Vec<LapTrace>, // Each valid lap which improved on the previous best, fastest last
+}
+
+/// Records a distance indexed trace of every lap and computes the delta of the tracked car, the
+/// player car unless set otherwise, to the reference lap.
+#[derive(Debug, Clone)]
+pub struct DeltaTracker {
+    session_uid: u64,
+    reference: ReferenceLap,
+    car_index: Option<usize>,
+    cars: Vec<CarTraces>,
+}
+
+impl DeltaTracker {
+    pub fn new(reference: ReferenceLap) -> Self {
+        DeltaTracker {
+            session_uid: 0,
+            reference,
+            car_index: None,
+            cars: vec![CarTraces::default(); MAX_CARS],
+        }
+    }
+
+    /// Tracks this car instead of the player car.
+    pub fn track_car(mut self, car_index: usize) -> Self {
+        self.car_index = Some(car_index);
+        self
+    }
+
+    pub fn set_reference(&mut self, reference: ReferenceLap) {
+        self.reference = reference;
+    }
+
+    /// Fastest valid lap of the car this session.
+    pub fn best_lap(&self, car_index: usize) -> Option<&LapTrace> {
+        self.cars.get(car_index)?.bests.last()
+    }
+
+    /// Fastest valid lap of any car this session.
+    pub fn session_best(&self) -> Option<&LapTrace> {
+        self.cars
+            .iter()
+            .filter_map(|car| car.bests.last())
+            .min_by_key(|best| best.lap_time_in_ms)
+    }
+
+    /// Updates the traces with the packet, returning the delta of the tracked car.
+    pub fn apply(&mut self, packet: &Packet) -> Option<Delta> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            let reference = self.reference.clone();
+            *self = DeltaTracker {
+                car_index: self.car_index,
+                ..DeltaTracker::new(reference)
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        let car_index = self.car_index.unwrap_or(header.player_car_index as usize);
+        match packet {
+            Packet::LapData(pkt) => {
+                self.record(pkt);
+                self.delta(pkt, car_index)
+            }
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, pkt: &PacketLapData) {
+        for (car_index, (car, lap)) in self.cars.iter_mut().zip(pkt.lap_data.iter()).enumerate() {
+            if lap.current_lap_num == 0 || lap.lap_distance < 0.0 {
+                continue;
+            }
+            if let Some(completed) = car.laps.update(lap, lap.current_lap_invalid == 0) {
+                let improved = match car.bests.last() {
+                    Some(best) => completed.lap_time_in_ms < best.lap_time_in_ms,
+                    None => true,
+                };
+                if completed.starts_at_line(START_TOLERANCE)
+                    && completed.valid
+                    && completed.lap_time_in_ms > 0
+                    && improved
+                {
+                    car.bests.push(LapTrace {
+                        car_index,
+                        lap_number: completed.lap_number,
+                        lap_time_in_ms: completed.lap_time_in_ms,
+                        session_time: pkt.header.session_time,
+                        points: completed.samples,
+                    });
+                }
+            }
+            // Going back around the lap means a flashback, forget what came after
+            car.laps.truncate(lap.lap_distance);
+            car.laps.push(
+                TracePoint {
+                    lap_distance: lap.lap_distance,
+                    time_in_ms: lap.current_lap_time_in_ms,
+                },
+                POINT_SPACING,
+            );
+        }
+    }
+
+    fn delta(&self, pkt: &PacketLapData, car_index: usize) -> Option<Delta> {
+        let lap = pkt.lap_data.get(car_index)?;
+        let reference = match &self.reference {
+            ReferenceLap::SessionBest => self.session_best()?,
+            ReferenceLap::Car(other) => self.best_lap(*other)?,
+            ReferenceLap::Lap(trace) => trace,
+        };
+        if lap.lap_distance < 0.0 {
+            return None;
+        }
+        let reference_time = reference.time_at(lap.lap_distance)?;
+        let delta = (lap.current_lap_time_in_ms as f32 - reference_time) / 1000.0;
+        let predicted = (reference.lap_time_in_ms as f32 + delta * 1000.0).max(0.0);
+        Some(Delta {
+            lap_number: lap.current_lap_num,
+            lap_distance: lap.lap_distance,
+            current_lap_time_in_ms: lap.current_lap_time_in_ms,
+            delta,
+            predicted_lap_time_in_ms: predicted as u32,
+        })
+    }
+}
+
+impl Rewind for DeltaTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        for car in self.cars.iter_mut() {
+            // The current lap is truncated by distance as the packets after the flashback arrive
+            car.bests
+                .retain(|best| !point.supersedes(point.session_uid, best.session_time));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    /// Lap data with car 0 and 1 at the same lap distance, car 1 a second slower into the lap.
+    fn lap_data(session_uid: u64, session_time: f32, lap: u8, lap_distance: f32) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        for (car_index, slower) in [0, 1000].into_iter().enumerate() {
+            let data = &mut pkt.lap_data[car_index];
+            data.current_lap_num = lap;
+            data.lap_distance = lap_distance;
+            data.current_lap_time_in_ms = (lap_distance * 20.0) as u32 + slower;
+            data.last_lap_time_in_ms = if lap > 1 { 90_000 + slower } else { 0 };
+        }
+        Packet::LapData(Box::new(pkt))
+    }
+
+    /// Drives both cars through lap 1 and into lap 2.
+    fn drive(tracker: &mut DeltaTracker, session_uid: u64) {
+        for metres in (0..4500).step_by(10) {
+            tracker.apply(&lap_data(
+                session_uid,
+                metres as f32 / 50.0,
+                1,
+                metres as f32,
+            ));
+        }
+        tracker.apply(&lap_data(session_uid, 90.0, 2, 0.0));
+    }
+
+    #[test]
+    fn first_lap_has_no_reference() {
+        let mut tracker = DeltaTracker::new(ReferenceLap::SessionBest);
+        assert_eq!(tracker.apply(&lap_data(1, 1.0, 1, 100.0)), None);
+        assert_eq!(tracker.session_best(), None);
+    }
+
+    #[test]
+    fn lap_rollover_records_the_best_lap() {
+        let mut tracker = DeltaTracker::new(ReferenceLap::SessionBest);
+        drive(&mut tracker, 1);
+        assert_eq!(tracker.best_lap(0).unwrap().lap_time_in_ms, 90_000);
+        assert_eq!(tracker.best_lap(1).unwrap().lap_time_in_ms, 91_000);
+        let delta = tracker.apply(&lap_data(1, 92.0, 2, 1000.0)).unwrap();
+        assert!(delta.delta.abs() < 0.01);
+    }
+
+    #[test]
+    fn session_best_is_the_fastest_car() {
+        let mut tracker = DeltaTracker::new(ReferenceLap::SessionBest).track_car(1);
+        drive(&mut tracker, 1);
+        assert_eq!(tracker.session_best().unwrap().car_index, 0);
+        let delta = tracker.apply(&lap_data(1, 92.0, 2, 1000.0)).unwrap();
+        assert!((delta.delta - 1.0).abs() < 0.01);
+        assert_eq!(delta.predicted_lap_time_in_ms, 91_000);
+    }
+
+    #[test]
+    fn flashback_drops_the_best_lap() {
+        let mut tracker = DeltaTracker::new(ReferenceLap::Car(0));
+        drive(&mut tracker, 1);
+        tracker.apply(&testing::flashback(1, 5000, 90.0, 4000, 80.0));
+        assert_eq!(tracker.best_lap(0), None);
+    }
+
+    #[test]
+    fn session_change_keeps_the_settings() {
+        let mut tracker = DeltaTracker::new(ReferenceLap::SessionBest).track_car(1);
+        drive(&mut tracker, 1);
+        drive(&mut tracker, 2);
+        assert_eq!(tracker.session_best().unwrap().lap_time_in_ms, 90_000);
+        let delta = tracker.apply(&lap_data(2, 92.0, 2, 1000.0)).unwrap();
+        assert!((delta.delta - 1.0).abs() < 0.01);
+    }
+}
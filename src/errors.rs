@@ -11,3 +11,17 @@ impl fmt::Display for TelemetryError {
 }
 
 impl Error for TelemetryError {}
+
+#[derive(Debug)]
+pub struct ParseNetworkError;
+
+impl fmt::Display for ParseNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid IP network, expected an address or address/prefix"
+        )
+    }
+}
+
+impl Error for ParseNetworkError {}
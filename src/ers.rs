@@ -0,0 +1,428 @@
+//! ERS deployment and harvesting analytics
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{CarStatusData, PacketLapData, MAX_CARS},
+};
+
+/// Energy the ERS may deploy per lap in Joules.
+pub const DEPLOY_LIMIT_PER_LAP: f32 = 4_000_000.0;
+/// Length in metres of a section of the deployment map.
+const SECTION_LENGTH: f32 = 50.0;
+/// Deploy allowance left at the end of a lap counted as unused, 10% of the limit.
+const DEFAULT_UNUSED_THRESHOLD: f32 = 400_000.0;
+
+/// Deployment and harvesting over a section of the lap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DeploymentSection {
+    pub lap_distance: f32,          // Start of the section in metres
+    pub deployed: f32,              // Joules
+    pub harvested: f32,             // Joules, MGU-K and MGU-H
+    pub average_speed: Option<f32>, // Kilometres per hour, None if no telemetry arrived in it
+    pub deploy_mode: u8, // Last mode used in the section, see `CarStatusData::ers_deploy_mode`
+    #[serde(skip)]
+    speed_sum: f32,
+    #[serde(skip)]
+    speed_samples: u32,
+}
+
+impl DeploymentSection {
+    fn add_speed(&mut self, speed: u16) {
+        self.speed_sum += speed as f32;
+        self.speed_samples += 1;
+        self.average_speed = Some(self.speed_sum / self.speed_samples as f32);
+    }
+}
+
+/// Energy use over a completed lap.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErsLap {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub store_at_start: Option<f32>, // Joules, None if the car wasn't seen crossing the line
+    pub store_at_end: f32,           // Joules
+    pub deployed: f32,               // Joules
+    pub harvested_mguk: f32,         // Joules
+    pub harvested_mguh: f32,         // Joules
+    pub unused: f32, // Deploy allowance left which the store could have supplied, Joules
+    pub sections: Vec<DeploymentSection>, // Deployment by lap distance
+    pub session_time: f32, // Session time the lap was completed at
+}
+
+/// Energy left to use on the current lap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ErsBudget {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub lap_distance: f32,
+    pub store_energy: f32,     // Joules
+    pub deployed: f32,         // Joules deployed so far this lap
+    pub harvested: f32,        // Joules harvested so far this lap, MGU-K and MGU-H
+    pub deploy_remaining: f32, // Joules which can still be deployed this lap
+    pub deploy_mode: u8,
+}
+
+/// Lap being recorded.
+#[derive(Debug, Clone)]
+struct CurrentLap {
+    lap_number: u8,
+    store_at_start: Option<f32>,
+    store_energy: f32,
+    deployed: f32,
+    harvested_mguk: f32,
+    harvested_mguh: f32,
+    sections: Vec<DeploymentSection>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarErs {
+    status: Option<CarStatusData>,
+    lap_distance: Option<f32>,
+    current: Option<CurrentLap>,
+    laps: Vec<ErsLap>,
+}
+
+impl CarErs {
+    fn section(&mut self) -> Option<&mut DeploymentSection> {
+        let lap_distance = self.lap_distance?;
+        Some(section_at(
+            &mut self.current.as_mut()?.sections,
+            lap_distance,
+        ))
+    }
+}
+
+/// Tracks the ERS of every car, recording per lap how much energy was deployed and harvested,
+/// where around the lap it was deployed, and how much of the deploy allowance went unused.
+#[derive(Debug, Clone)]
+pub struct ErsTracker {
+    session_uid: u64,
+    deploy_limit: f32,
+    unused_threshold: f32,
+    cars: Vec<CarErs>,
+}
+
+impl Default for ErsTracker {
+    fn default() -> Self {
+        ErsTracker {
+            session_uid: 0,
+            deploy_limit: DEPLOY_LIMIT_PER_LAP,
+            unused_threshold: DEFAULT_UNUSED_THRESHOLD,
+            cars: vec![CarErs::default(); MAX_CARS],
+        }
+    }
+}
+
+impl ErsTracker {
+    pub fn new() -> Self {
+        ErsTracker::default()
+    }
+
+    /// Energy the ERS may deploy per lap in Joules. Defaults to 4 MJ.
+    pub fn deploy_limit(mut self, joules: f32) -> Self {
+        self.deploy_limit = joules;
+        self
+    }
+
+    /// Unused energy in Joules above which a lap counts as wasting energy. Defaults to 400 kJ.
+    pub fn unused_threshold(mut self, joules: f32) -> Self {
+        self.unused_threshold = joules;
+        self
+    }
+
+    /// ERS use on each completed lap of the car, oldest first.
+    pub fn laps(&self, car_index: usize) -> &[ErsLap] {
+        self.cars.get(car_index).map_or(&[], |car| &car.laps[..])
+    }
+
+    /// Completed laps of the car which ended with more unused energy than the threshold.
+    pub fn unused_laps(&self, car_index: usize) -> impl Iterator<Item = &ErsLap> {
+        let threshold = self.unused_threshold;
+        self.laps(car_index)
+            .iter()
+            .filter(move |lap| lap.unused > threshold)
+    }
+
+    /// Energy the car has left to use on the current lap.
+    pub fn budget(&self, car_index: usize) -> Option<ErsBudget> {
+        let car = self.cars.get(car_index)?;
+        let status = car.status.as_ref()?;
+        let current = car.current.as_ref()?;
+        Some(ErsBudget {
+            car_index,
+            lap_number: current.lap_number,
+            lap_distance: car.lap_distance.unwrap_or(0.0),
+            store_energy: status.ers_store_energy,
+            deployed: status.ers_deployed_this_lap,
+            harvested: status.ers_harvested_this_lap_mguk + status.ers_harvested_this_lap_mguh,
+            deploy_remaining: (self.deploy_limit - status.ers_deployed_this_lap)
+                .clamp(0.0, status.ers_store_energy.max(0.0)),
+            deploy_mode: status.ers_deploy_mode,
+        })
+    }
+
+    /// Deployment by lap distance averaged over the completed laps of the car.
+    pub fn deployment_map(&self, car_index: usize) -> Vec<DeploymentSection> {
+        let laps = self.laps(car_index);
+        let mut map: Vec<DeploymentSection> = Vec::new();
+        let mut counts: Vec<u32> = Vec::new();
+        for section in laps.iter().flat_map(|lap| lap.sections.iter()) {
+            let averaged = section_at(&mut map, section.lap_distance);
+            let idx = (section.lap_distance / SECTION_LENGTH) as usize;
+            if counts.len() <= idx {
+                counts.resize(idx + 1, 0);
+            }
+            averaged.deployed += section.deployed;
+            averaged.harvested += section.harvested;
+            averaged.speed_sum += section.speed_sum;
+            averaged.speed_samples += section.speed_samples;
+            averaged.deploy_mode = section.deploy_mode;
+            counts[idx] += 1;
+        }
+        for (section, count) in map.iter_mut().zip(counts) {
+            if count > 0 {
+                section.deployed /= count as f32;
+                section.harvested /= count as f32;
+            }
+            if section.speed_samples > 0 {
+                section.average_speed = Some(section.speed_sum / section.speed_samples as f32);
+            }
+        }
+        map
+    }
+
+    /// Updates the tracker with the packet, returning the ERS use of the laps completed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<ErsLap> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = ErsTracker {
+                deploy_limit: self.deploy_limit,
+                unused_threshold: self.unused_threshold,
+                ..ErsTracker::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::LapData(pkt) => return self.apply_lap_data(pkt),
+            Packet::CarStatus(pkt) => {
+                for (car, status) in self.cars.iter_mut().zip(pkt.car_status_data.iter()) {
+                    // The counters reset at the line, a status packet can arrive with the reset
+                    // counters before the lap data packet of the new lap
+                    let (deployed, harvested) = match &car.status {
+                        Some(old) if status.ers_deployed_this_lap >= old.ers_deployed_this_lap => (
+                            status.ers_deployed_this_lap - old.ers_deployed_this_lap,
+                            (status.ers_harvested_this_lap_mguk
+                                + status.ers_harvested_this_lap_mguh
+                                - old.ers_harvested_this_lap_mguk
+                                - old.ers_harvested_this_lap_mguh)
+                                .max(0.0),
+                        ),
+                        Some(_) => {
+                            car.status = Some(status.clone());
+                            continue;
+                        }
+                        None => (0.0, 0.0),
+                    };
+                    if let Some(current) = car.current.as_mut() {
+                        current.store_energy = status.ers_store_energy;
+                        current.deployed = status.ers_deployed_this_lap;
+                        current.harvested_mguk = status.ers_harvested_this_lap_mguk;
+                        current.harvested_mguh = status.ers_harvested_this_lap_mguh;
+                    }
+                    if let Some(section) = car.section() {
+                        section.deployed += deployed;
+                        section.harvested += harvested;
+                        section.deploy_mode = status.ers_deploy_mode;
+                    }
+                    car.status = Some(status.clone());
+                }
+            }
+            Packet::CarTelemetry(pkt) => {
+                for (car, telemetry) in self.cars.iter_mut().zip(pkt.car_telemetry_data.iter()) {
+                    if let Some(section) = car.section() {
+                        section.add_speed(telemetry.speed);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<ErsLap> {
+        let mut completed = Vec::new();
+        for (car_index, (car, lap)) in self.cars.iter_mut().zip(pkt.lap_data.iter()).enumerate() {
+            car.lap_distance = (lap.lap_distance >= 0.0).then_some(lap.lap_distance);
+            if lap.current_lap_num == 0 {
+                continue;
+            }
+            let at_line = match &car.current {
+                Some(current) if current.lap_number == lap.current_lap_num => continue,
+                Some(current) => current.lap_number.checked_add(1) == Some(lap.current_lap_num),
+                None => false,
+            };
+            match car.current.take() {
+                Some(current) if at_line => {
+                    let ers_lap = ErsLap {
+                        car_index,
+                        lap_number: current.lap_number,
+                        store_at_start: current.store_at_start,
+                        store_at_end: current.store_energy,
+                        deployed: current.deployed,
+                        harvested_mguk: current.harvested_mguk,
+                        harvested_mguh: current.harvested_mguh,
+                        unused: (self.deploy_limit - current.deployed)
+                            .clamp(0.0, current.store_energy.max(0.0)),
+                        sections: current.sections,
+                        session_time: pkt.header.session_time,
+                    };
+                    car.laps.push(ers_lap.clone());
+                    completed.push(ers_lap);
+                }
+                _ => {}
+            }
+            let store_energy = car.status.as_ref().map_or(0.0, |s| s.ers_store_energy);
+            car.current = Some(CurrentLap {
+                lap_number: lap.current_lap_num,
+                store_at_start: (at_line && car.status.is_some()).then_some(store_energy),
+                store_energy,
+                deployed: 0.0,
+                harvested_mguk: 0.0,
+                harvested_mguh: 0.0,
+                sections: Vec::new(),
+            });
+        }
+        completed
+    }
+}
+
+impl Rewind for ErsTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        for car in self.cars.iter_mut() {
+            car.laps
+                .retain(|lap| !point.supersedes(point.session_uid, lap.session_time));
+            // Restarts recording the lap from the next lap data
+            car.status = None;
+            car.current = None;
+        }
+    }
+}
+
+/// Section of the lap the distance is in, adding the sections up to it.
+fn section_at(sections: &mut Vec<DeploymentSection>, lap_distance: f32) -> &mut DeploymentSection {
+    let idx = (lap_distance / SECTION_LENGTH) as usize;
+    if sections.len() <= idx {
+        let start = sections.len();
+        sections.extend((start..=idx).map(|idx| DeploymentSection {
+            lap_distance: idx as f32 * SECTION_LENGTH,
+            ..Default::default()
+        }));
+    }
+    &mut sections[idx]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketCarStatusData, testing};
+
+    fn car_status(session_uid: u64, session_time: f32, deployed: f32, store: f32) -> Packet {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        let status = &mut pkt.car_status_data[0];
+        status.ers_deployed_this_lap = deployed;
+        status.ers_harvested_this_lap_mguk = deployed / 2.0;
+        status.ers_store_energy = store;
+        status.ers_deploy_mode = 1;
+        Packet::CarStatus(Box::new(pkt))
+    }
+
+    fn lap_data(session_uid: u64, session_time: f32, lap: u8, lap_distance: f32) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        pkt.lap_data[0].current_lap_num = lap;
+        pkt.lap_data[0].lap_distance = lap_distance;
+        Packet::LapData(Box::new(pkt))
+    }
+
+    /// Drives lap 1 and 2 of a 1000 metre lap, deploying 3 MJ on each over the first half.
+    fn drive(tracker: &mut ErsTracker, session_uid: u64) -> Vec<ErsLap> {
+        let mut completed = Vec::new();
+        for lap in 1..=2u8 {
+            let start = (lap - 1) as f32 * 100.0;
+            completed.extend(tracker.apply(&lap_data(session_uid, start, lap, 0.0)));
+            tracker.apply(&car_status(session_uid, start, 0.0, 4_000_000.0));
+            for step in 1..=10 {
+                let session_time = start + step as f32 * 10.0;
+                let deployed = (step.min(5) as f32) * 600_000.0;
+                tracker.apply(&lap_data(
+                    session_uid,
+                    session_time,
+                    lap,
+                    step as f32 * 100.0 - 1.0,
+                ));
+                tracker.apply(&car_status(
+                    session_uid,
+                    session_time,
+                    deployed,
+                    4_000_000.0,
+                ));
+            }
+        }
+        completed.extend(tracker.apply(&lap_data(session_uid, 200.0, 3, 0.0)));
+        completed
+    }
+
+    #[test]
+    fn first_packets_complete_no_lap() {
+        let mut tracker = ErsTracker::new();
+        tracker.apply(&car_status(1, 0.0, 1_000_000.0, 3_000_000.0));
+        tracker.apply(&lap_data(1, 0.0, 4, 500.0));
+        assert!(tracker.apply(&lap_data(1, 1.0, 4, 510.0)).is_empty());
+        let budget = tracker.budget(0).unwrap();
+        assert_eq!(budget.deploy_remaining, 3_000_000.0);
+    }
+
+    #[test]
+    fn lap_rollover_completes_the_lap() {
+        let mut tracker = ErsTracker::new();
+        let laps = drive(&mut tracker, 1);
+        assert_eq!(laps.len(), 2);
+        let lap = &laps[1];
+        assert_eq!(lap.store_at_start, Some(4_000_000.0));
+        assert_eq!(lap.deployed, 3_000_000.0);
+        assert_eq!(lap.unused, 1_000_000.0);
+        // Deployed over the first half of the lap only
+        let deployed: f32 = lap.sections[..10].iter().map(|s| s.deployed).sum();
+        assert_eq!(deployed, 3_000_000.0);
+        assert_eq!(tracker.unused_laps(0).count(), 2);
+        assert_eq!(tracker.deployment_map(0).len(), lap.sections.len());
+    }
+
+    #[test]
+    fn flashback_drops_the_laps_after_the_point() {
+        let mut tracker = ErsTracker::new();
+        drive(&mut tracker, 1);
+        tracker.apply(&testing::flashback(1, 1000, 200.0, 500, 150.0));
+        assert_eq!(tracker.laps(0).len(), 1);
+        assert_eq!(tracker.budget(0), None);
+    }
+
+    #[test]
+    fn session_change_keeps_the_settings() {
+        let mut tracker = ErsTracker::new().unused_threshold(2_000_000.0);
+        drive(&mut tracker, 1);
+        assert_eq!(tracker.unused_laps(0).count(), 0);
+        tracker.apply(&lap_data(2, 0.0, 1, 0.0));
+        assert!(tracker.laps(0).is_empty());
+        assert_eq!(tracker.unused_threshold, 2_000_000.0);
+    }
+}
@@ -0,0 +1,180 @@
+//! Allowlist of the source addresses that packets are accepted from
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::errors::ParseNetworkError;
+
+/// An IP network, e.g. `192.168.1.0/24`. A single address is a network with the full prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Creates a network, returns None if the prefix length is too long for the address family.
+    /// An IPv4 mapped IPv6 network with a prefix of at least 96 bits is stored as the IPv4 network.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        if prefix_len > max_prefix_len(&addr) {
+            return None;
+        }
+        match canonical(addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix_len >= 96 => Some(IpNetwork {
+                addr: IpAddr::V4(v4),
+                prefix_len: prefix_len - 96,
+            }),
+            _ => Some(IpNetwork { addr, prefix_len }),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether the address lies within this network. IPv4 mapped IPv6 addresses are treated as IPv4.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), addr) => {
+                let addr = match addr {
+                    IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                    IpAddr::V6(v6) => v6,
+                };
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(addr: IpAddr) -> Self {
+        let addr = canonical(addr);
+        IpNetwork {
+            addr,
+            prefix_len: max_prefix_len(&addr),
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr = addr.parse().map_err(|_| ParseNetworkError)?;
+                let prefix_len = prefix_len.parse().map_err(|_| ParseNetworkError)?;
+                IpNetwork::new(addr, prefix_len).ok_or(ParseNetworkError)
+            }
+            None => Ok(IpNetwork::from(
+                s.parse::<IpAddr>().map_err(|_| ParseNetworkError)?,
+            )),
+        }
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        addr => addr,
+    }
+}
+
+/// Decides which datagrams are accepted based on their source address. An empty allowlist accepts everything.
+#[derive(Debug, Clone, Default)]
+pub struct SourceFilter {
+    allowlist: Vec<IpNetwork>,
+    log_rejected: bool,
+    rejected: Arc<AtomicU64>,
+}
+
+impl SourceFilter {
+    pub(crate) fn allow(&mut self, network: IpNetwork) {
+        self.allowlist.push(network);
+    }
+
+    pub(crate) fn log_rejected(&mut self) {
+        self.log_rejected = true;
+    }
+
+    /// Returns whether the datagram should be decoded, counting it if it is rejected.
+    pub(crate) fn accept(&self, source: SocketAddr) -> bool {
+        if self.allowlist.is_empty() || self.allowlist.iter().any(|n| n.contains(source.ip())) {
+            return true;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        if self.log_rejected {
+            log::warn!("Rejected datagram from {}", source);
+        }
+        false
+    }
+
+    /// Number of datagrams rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_network_matches_ipv4_addresses() {
+        let network: IpNetwork = "::ffff:192.168.1.0/120".parse().unwrap();
+        assert_eq!(network.addr(), "192.168.1.0".parse::<IpAddr>().unwrap());
+        assert_eq!(network.prefix_len(), 24);
+        assert!(network.contains("192.168.1.20".parse().unwrap()));
+        assert!(network.contains("::ffff:192.168.1.20".parse().unwrap()));
+        assert!(!network.contains("192.168.2.20".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_network_matches_mapped_addresses() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("::ffff:11.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn short_mapped_prefix_stays_ipv6() {
+        let network: IpNetwork = "::ffff:0.0.0.0/80".parse().unwrap();
+        assert!(network.addr().is_ipv6());
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn single_address() {
+        let network: IpNetwork = "::ffff:127.0.0.1".parse().unwrap();
+        assert_eq!(network.prefix_len(), 32);
+        assert!(network.contains("127.0.0.1".parse().unwrap()));
+        assert!(!network.contains("127.0.0.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,447 @@
+//! Marshal zone and car flag monitoring
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{LapData, PacketCarStatusData, PacketSessionData, MAX_CARS},
+    track::TrackMap,
+};
+
+/// Flag shown in a marshal zone or to a car.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum Flag {
+    #[default]
+    Unknown,
+    None,
+    Green,
+    Blue,
+    Yellow,
+    Red,
+}
+
+impl Flag {
+    /// Flag from `MarshalZone::zone_flag` or `CarStatusData::vehicle_fia_flags`.
+    pub fn from_i8(flag: i8) -> Flag {
+        match flag {
+            0 => Flag::None,
+            1 => Flag::Green,
+            2 => Flag::Blue,
+            3 => Flag::Yellow,
+            4 => Flag::Red,
+            _ => Flag::Unknown,
+        }
+    }
+}
+
+/// A marshal zone with the part of the lap it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MarshalZoneState {
+    pub zone: usize,
+    pub start: f32, // Lap distance in metres
+    pub end: f32,   // Lap distance in metres, the start of the next zone, wrapping for the last
+    pub flag: Flag,
+}
+
+impl MarshalZoneState {
+    /// Whether the zone runs over the line, its end is then before its start.
+    pub fn wraps(&self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn contains(&self, lap_distance: f32) -> bool {
+        if self.wraps() {
+            lap_distance >= self.start || lap_distance < self.end
+        } else {
+            lap_distance >= self.start && lap_distance < self.end
+        }
+    }
+
+    /// World positions (x, z) of the zone along the centerline of the map, to draw it.
+    pub fn on_map(&self, map: &TrackMap) -> Vec<(f32, f32)> {
+        let mut positions: Vec<(f32, f32)> = map.position_at(self.start).into_iter().collect();
+        let after_start = map.points.iter().filter(|p| p.lap_distance > self.start);
+        let before_end = map.points.iter().filter(|p| p.lap_distance < self.end);
+        if self.wraps() {
+            positions.extend(after_start.chain(before_end).map(|p| (p.x, p.z)));
+        } else {
+            positions.extend(
+                after_start
+                    .filter(|p| p.lap_distance < self.end)
+                    .map(|p| (p.x, p.z)),
+            );
+        }
+        positions.extend(map.position_at(self.end - f32::EPSILON));
+        positions
+    }
+}
+
+/// A blue flag shown to a car.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BlueFlag {
+    pub car_index: usize,
+    pub lap_number: u8,        // Lap of the car when the flag was shown
+    pub lap_distance: f32,     // Lap distance of the car when the flag was shown
+    pub start_time: f32,       // Session time
+    pub end_time: Option<f32>, // None while the flag is still shown
+}
+
+impl BlueFlag {
+    /// Seconds the flag was shown for, up to the session time if it is still shown.
+    pub fn duration(&self, session_time: f32) -> f32 {
+        self.end_time.unwrap_or(session_time) - self.start_time
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum FlagEvent {
+    ZoneFlagChanged {
+        zone: MarshalZoneState, // With the new flag
+        from: Flag,
+        session_time: f32,
+    },
+    CarFlagChanged {
+        car_index: usize,
+        from: Flag,
+        to: Flag,
+        session_time: f32,
+    },
+    BlueFlagEnded(BlueFlag),
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarFlags {
+    flag: Option<Flag>, // None until the first car status, and after a flashback
+    lap: Option<LapData>,
+    blue_flags: Vec<BlueFlag>, // Oldest first, the last one may still be shown
+}
+
+/// Follows the flags of the marshal zones from the session packet and of every car from the car
+/// status packets, emitting an event when one changes. The zones are mapped to lap distances with
+/// the track length, and onto a [`TrackMap`] with [`MarshalZoneState::on_map`]. Blue flags are
+/// kept per car with how long they were shown for. The first flag of a car, and its flag after a
+/// flashback, are taken as they are without an event.
+#[derive(Debug, Clone)]
+pub struct FlagMonitor {
+    session_uid: u64,
+    session_time: f32,
+    zones: Vec<MarshalZoneState>,
+    cars: Vec<CarFlags>,
+}
+
+impl Default for FlagMonitor {
+    fn default() -> Self {
+        FlagMonitor {
+            session_uid: 0,
+            session_time: 0.0,
+            zones: Vec::new(),
+            cars: vec![CarFlags::default(); MAX_CARS],
+        }
+    }
+}
+
+impl FlagMonitor {
+    pub fn new() -> Self {
+        FlagMonitor::default()
+    }
+
+    /// Marshal zones in the order of the lap, empty until the session packet arrived.
+    pub fn zones(&self) -> &[MarshalZoneState] {
+        &self.zones
+    }
+
+    /// Marshal zone at the lap distance.
+    pub fn zone_at(&self, lap_distance: f32) -> Option<&MarshalZoneState> {
+        self.zones.iter().find(|zone| zone.contains(lap_distance))
+    }
+
+    /// Zones showing a yellow or red flag.
+    pub fn hazards(&self) -> impl Iterator<Item = &MarshalZoneState> {
+        self.zones
+            .iter()
+            .filter(|zone| matches!(zone.flag, Flag::Yellow | Flag::Red))
+    }
+
+    /// Flag currently shown to the car.
+    pub fn car_flag(&self, car_index: usize) -> Flag {
+        self.cars
+            .get(car_index)
+            .and_then(|car| car.flag)
+            .unwrap_or_default()
+    }
+
+    /// Blue flags shown to the car this session, oldest first.
+    pub fn blue_flags(&self, car_index: usize) -> &[BlueFlag] {
+        self.cars
+            .get(car_index)
+            .map_or(&[], |car| &car.blue_flags[..])
+    }
+
+    /// Seconds the car was shown blue flags for this session.
+    pub fn blue_flag_time(&self, car_index: usize) -> f32 {
+        self.blue_flags(car_index)
+            .iter()
+            .map(|flag| flag.duration(self.session_time))
+            .sum()
+    }
+
+    /// Cars which were shown a blue flag this session.
+    pub fn blue_flagged_cars(&self) -> Vec<usize> {
+        (0..MAX_CARS)
+            .filter(|car_index| !self.cars[*car_index].blue_flags.is_empty())
+            .collect()
+    }
+
+    /// Updates the monitor with the packet, returning the flags which changed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<FlagEvent> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = FlagMonitor::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        self.session_time = header.session_time;
+        match packet {
+            Packet::Session(pkt) => self.apply_session(pkt),
+            Packet::CarStatus(pkt) => self.apply_car_status(pkt),
+            Packet::LapData(pkt) => {
+                for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+                    car.lap = Some(lap.clone());
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_session(&mut self, pkt: &PacketSessionData) -> Vec<FlagEvent> {
+        let track_length = pkt.track_length as f32;
+        let num_zones = (pkt.num_marshal_zones as usize).min(pkt.marshal_zones.len());
+        let zones = &pkt.marshal_zones[..num_zones];
+        let mut events = Vec::new();
+        let mut states = Vec::with_capacity(num_zones);
+        for (zone, marshal_zone) in zones.iter().enumerate() {
+            // Zones are in the order of the lap, the last one runs over the line
+            let end = zones.get(zone + 1).unwrap_or(&zones[0]).zone_start;
+            let state = MarshalZoneState {
+                zone,
+                start: marshal_zone.zone_start * track_length,
+                end: end * track_length,
+                flag: Flag::from_i8(marshal_zone.zone_flag),
+            };
+            let from = self.zones.get(zone).map_or(Flag::Unknown, |old| old.flag);
+            if from != state.flag {
+                events.push(FlagEvent::ZoneFlagChanged {
+                    zone: state,
+                    from,
+                    session_time: self.session_time,
+                });
+            }
+            states.push(state);
+        }
+        self.zones = states;
+        events
+    }
+
+    fn apply_car_status(&mut self, pkt: &PacketCarStatusData) -> Vec<FlagEvent> {
+        let session_time = self.session_time;
+        let mut events = Vec::new();
+        for (car_index, (car, status)) in self
+            .cars
+            .iter_mut()
+            .zip(pkt.car_status_data.iter())
+            .enumerate()
+        {
+            let flag = Flag::from_i8(status.vehicle_fia_flags);
+            if car.flag == Some(flag) {
+                continue;
+            }
+            // Without a flag to change from, the flag is only taken
+            if let Some(from) = car.flag {
+                events.push(FlagEvent::CarFlagChanged {
+                    car_index,
+                    from,
+                    to: flag,
+                    session_time,
+                });
+                if let Some(blue) = car.blue_flags.last_mut().filter(|b| b.end_time.is_none()) {
+                    blue.end_time = Some(session_time);
+                    events.push(FlagEvent::BlueFlagEnded(*blue));
+                }
+            }
+            if flag == Flag::Blue {
+                car.blue_flags.push(BlueFlag {
+                    car_index,
+                    lap_number: car.lap.as_ref().map_or(0, |lap| lap.current_lap_num),
+                    lap_distance: car.lap.as_ref().map_or(0.0, |lap| lap.lap_distance),
+                    start_time: session_time,
+                    end_time: None,
+                });
+            }
+            car.flag = Some(flag);
+        }
+        events
+    }
+}
+
+impl Rewind for FlagMonitor {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        for car in self.cars.iter_mut() {
+            car.blue_flags
+                .retain(|blue| !point.supersedes(point.session_uid, blue.start_time));
+            if let Some(blue) = car.blue_flags.last_mut() {
+                if blue
+                    .end_time
+                    .is_some_and(|end| point.supersedes(point.session_uid, end))
+                {
+                    // Shown at the flashback point, ends with the next car status
+                    blue.end_time = None;
+                }
+            }
+            // Taken again from the next car status, unless a blue flag is still shown
+            car.flag = match car.blue_flags.last() {
+                Some(blue) if blue.end_time.is_none() => Some(Flag::Blue),
+                _ => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketLapData, testing};
+
+    /// A 1000 metre track with zones from 100, 400 and 700 metres, the flags in that order.
+    fn session(monitor: &mut FlagMonitor, session_uid: u64, flags: [i8; 3]) -> Vec<FlagEvent> {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 1.0);
+        pkt.track_length = 1000;
+        pkt.num_marshal_zones = 3;
+        for (zone, (zone_start, flag)) in pkt
+            .marshal_zones
+            .iter_mut()
+            .zip([0.1, 0.4, 0.7].into_iter().zip(flags))
+        {
+            zone.zone_start = zone_start;
+            zone.zone_flag = flag;
+        }
+        monitor.apply(&Packet::Session(Box::new(pkt)))
+    }
+
+    /// Car status with the flag of car 0, every other car without a flag.
+    fn car_status(
+        monitor: &mut FlagMonitor,
+        session_uid: u64,
+        session_time: f32,
+        flag: i8,
+    ) -> Vec<FlagEvent> {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        pkt.car_status_data[0].vehicle_fia_flags = flag;
+        monitor.apply(&Packet::CarStatus(Box::new(pkt)))
+    }
+
+    #[test]
+    fn last_zone_runs_over_the_line() {
+        let mut monitor = FlagMonitor::new();
+        session(&mut monitor, 1, [1, 1, 3]);
+        let zone_at = |d: f32| monitor.zone_at(d).map(|zone| zone.zone);
+        assert_eq!(zone_at(100.0), Some(0));
+        assert_eq!(zone_at(699.0), Some(1));
+        assert_eq!(zone_at(950.0), Some(2));
+        assert_eq!(zone_at(50.0), Some(2));
+        assert!(monitor.zones()[2].wraps());
+        assert_eq!(monitor.hazards().count(), 1);
+    }
+
+    #[test]
+    fn zone_flag_changes_are_reported() {
+        let mut monitor = FlagMonitor::new();
+        session(&mut monitor, 1, [1, 1, 1]);
+        let events = session(&mut monitor, 1, [1, 3, 1]);
+        let [FlagEvent::ZoneFlagChanged { zone, from, .. }] = &events[..] else {
+            panic!("expected one zone change, got {:?}", events);
+        };
+        assert_eq!(
+            (zone.zone, zone.flag, *from),
+            (1, Flag::Yellow, Flag::Green)
+        );
+        assert_eq!((zone.start, zone.end), (400.0, 700.0));
+    }
+
+    #[test]
+    fn first_car_status_sets_the_flags_silently() {
+        let mut monitor = FlagMonitor::new();
+        assert_eq!(monitor.car_flag(0), Flag::Unknown);
+        assert!(car_status(&mut monitor, 1, 1.0, 1).is_empty());
+        assert_eq!(monitor.car_flag(0), Flag::Green);
+        assert_eq!(monitor.car_flag(5), Flag::None);
+        let events = car_status(&mut monitor, 1, 2.0, 0);
+        assert_eq!(
+            events,
+            vec![FlagEvent::CarFlagChanged {
+                car_index: 0,
+                from: Flag::Green,
+                to: Flag::None,
+                session_time: 2.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn blue_flags_are_timed() {
+        let mut monitor = FlagMonitor::new();
+        let mut lap_data: PacketLapData = testing::packet(PacketId::LapData, 1, 0, 1.0);
+        lap_data.lap_data[0].current_lap_num = 4;
+        monitor.apply(&Packet::LapData(Box::new(lap_data)));
+        car_status(&mut monitor, 1, 1.0, 0);
+        car_status(&mut monitor, 1, 10.0, 2);
+        assert_eq!(monitor.blue_flag_time(0), 0.0);
+        let events = car_status(&mut monitor, 1, 14.0, 0);
+        let FlagEvent::BlueFlagEnded(blue) = events[1] else {
+            panic!("expected the blue flag to end, got {:?}", events);
+        };
+        assert_eq!((blue.lap_number, blue.start_time), (4, 10.0));
+        assert_eq!(monitor.blue_flag_time(0), 4.0);
+        assert_eq!(monitor.blue_flagged_cars(), vec![0]);
+    }
+
+    #[test]
+    fn flashback_sets_the_flags_again_silently() {
+        let mut monitor = FlagMonitor::new();
+        car_status(&mut monitor, 1, 1.0, 0);
+        car_status(&mut monitor, 1, 10.0, 2);
+        car_status(&mut monitor, 1, 14.0, 1);
+        car_status(&mut monitor, 1, 20.0, 3);
+        monitor.apply(&testing::flashback(1, 0, 21.0, 0, 12.0));
+        // The blue flag was shown at the point, so it is open again
+        assert_eq!(monitor.car_flag(0), Flag::Blue);
+        assert_eq!(monitor.blue_flags(0)[0].end_time, None);
+        assert_eq!(monitor.car_flag(1), Flag::Unknown);
+        let events = car_status(&mut monitor, 1, 12.5, 2);
+        assert!(events.is_empty());
+        let events = car_status(&mut monitor, 1, 13.0, 0);
+        assert_eq!(events.len(), 2);
+        assert_eq!(monitor.blue_flag_time(0), 3.0);
+
+        monitor.apply(&testing::flashback(1, 0, 13.5, 0, 5.0));
+        assert!(monitor.blue_flags(0).is_empty());
+        assert!(car_status(&mut monitor, 1, 5.5, 2).is_empty());
+        assert_eq!(monitor.blue_flags(0).len(), 1);
+    }
+
+    #[test]
+    fn session_change_resets_the_flags() {
+        let mut monitor = FlagMonitor::new();
+        session(&mut monitor, 1, [1, 1, 1]);
+        car_status(&mut monitor, 1, 1.0, 2);
+        assert!(car_status(&mut monitor, 2, 1.0, 0).is_empty());
+        assert!(monitor.zones().is_empty());
+        assert!(monitor.blue_flagged_cars().is_empty());
+    }
+}
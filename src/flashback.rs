@@ -0,0 +1,36 @@
+//! Rolling back derived data when the player uses a flashback
+use crate::packet::{EventPacket, Packet};
+
+/// Point in the session the game flashed back to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashbackPoint {
+    pub session_uid: u64,
+    pub frame_identifier: u32, // Frame identifier flashed back to
+    pub session_time: f32,     // Session time flashed back to
+}
+
+impl FlashbackPoint {
+    /// Returns the flashback point if the packet is a flashback event.
+    pub fn from_packet(packet: &Packet) -> Option<FlashbackPoint> {
+        match packet {
+            Packet::Event(EventPacket::Flashback(pkt)) => Some(FlashbackPoint {
+                session_uid: pkt.header.session_uid,
+                frame_identifier: pkt.event.flashback_frame_identifier,
+                session_time: pkt.event.flashback_session_time,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether data recorded at this session time happened after the flashback point and no longer counts.
+    pub fn supersedes(&self, session_uid: u64, session_time: f32) -> bool {
+        self.session_uid == session_uid && session_time > self.session_time
+    }
+}
+
+/// Implemented by everything which keeps data over time, so that it can drop or mark as superseded
+/// the data after a flashback point. Implementors rewind themselves when they are given the
+/// flashback event, this is for applying a flashback seen elsewhere.
+pub trait Rewind {
+    fn rewind(&mut self, point: &FlashbackPoint);
+}
@@ -0,0 +1,298 @@
+//! Assembles the packets sharing a frame identifier into a single frame
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    packet::{EventPacket, Packet, PacketId},
+    telemetry::{
+        PacketCarDamageData, PacketCarSetupData, PacketCarStatusData, PacketCarTelemetryData,
+        PacketFinalClassificationData, PacketLapData, PacketLobbyInfoData, PacketMotionData,
+        PacketParticipantsData, PacketSessionData, PacketSessionHistoryData,
+    },
+};
+
+/// Number of emitted frames remembered, so that late packets don't start a new frame.
+const EMITTED_HISTORY: usize = 64;
+
+/// All packets received for one frame of one session.
+#[derive(Debug, Default, Serialize)]
+pub struct Frame {
+    pub session_uid: u64,
+    pub frame_identifier: u32,
+    pub session_time: f32,
+    pub complete: bool, // Whether every subscribed packet type was received
+    pub motion: Option<Box<PacketMotionData>>,
+    pub session: Option<Box<PacketSessionData>>,
+    pub lap_data: Option<Box<PacketLapData>>,
+    pub events: Vec<EventPacket>,
+    pub participants: Option<Box<PacketParticipantsData>>,
+    pub car_setups: Option<Box<PacketCarSetupData>>,
+    pub car_telemetry: Option<Box<PacketCarTelemetryData>>,
+    pub car_status: Option<Box<PacketCarStatusData>>,
+    pub final_classification: Option<Box<PacketFinalClassificationData>>,
+    pub lobby_info: Option<Box<PacketLobbyInfoData>>,
+    pub car_damage: Option<Box<PacketCarDamageData>>,
+    pub session_history: Option<Box<PacketSessionHistoryData>>,
+}
+
+impl Frame {
+    fn new(session_uid: u64, frame_identifier: u32, session_time: f32) -> Self {
+        Frame {
+            session_uid,
+            frame_identifier,
+            session_time,
+            ..Default::default()
+        }
+    }
+
+    fn insert(&mut self, packet: Packet) {
+        match packet {
+            Packet::Motion(pkt) => self.motion = Some(pkt),
+            Packet::Session(pkt) => self.session = Some(pkt),
+            Packet::LapData(pkt) => self.lap_data = Some(pkt),
+            Packet::Event(pkt) => self.events.push(pkt),
+            Packet::Participants(pkt) => self.participants = Some(pkt),
+            Packet::CarSetups(pkt) => self.car_setups = Some(pkt),
+            Packet::CarTelemetry(pkt) => self.car_telemetry = Some(pkt),
+            Packet::CarStatus(pkt) => self.car_status = Some(pkt),
+            Packet::FinalClassification(pkt) => self.final_classification = Some(pkt),
+            Packet::LobbyInfo(pkt) => self.lobby_info = Some(pkt),
+            Packet::CarDamage(pkt) => self.car_damage = Some(pkt),
+            Packet::SessionHistory(pkt) => self.session_history = Some(pkt),
+        }
+    }
+
+    fn contains(&self, id: PacketId) -> bool {
+        match id {
+            PacketId::Motion => self.motion.is_some(),
+            PacketId::Session => self.session.is_some(),
+            PacketId::LapData => self.lap_data.is_some(),
+            PacketId::Event => !self.events.is_empty(),
+            PacketId::Participants => self.participants.is_some(),
+            PacketId::CarSetups => self.car_setups.is_some(),
+            PacketId::CarTelemetry => self.car_telemetry.is_some(),
+            PacketId::CarStatus => self.car_status.is_some(),
+            PacketId::FinalClassification => self.final_classification.is_some(),
+            PacketId::LobbyInfo => self.lobby_info.is_some(),
+            PacketId::CarDamage => self.car_damage.is_some(),
+            PacketId::SessionHistory => self.session_history.is_some(),
+        }
+    }
+}
+
+/// Collects the subscribed packet types of each frame into a [`Frame`]. A frame is emitted as soon
+/// as every subscribed type has arrived, or incomplete once the session time has moved on by more
+/// than the timeout. Events are attached to their frame but are never required for it to be complete.
+/// No packets arrive while the game is paused, call [`FrameAssembler::poll`] periodically to emit
+/// the frames which have waited longer than the timeout in wall-clock time.
+pub struct FrameAssembler {
+    packet_ids: Vec<PacketId>,
+    timeout: Duration,
+    pending: BTreeMap<(u64, u32), (Instant, Frame)>, // With when the first packet arrived
+    emitted: VecDeque<(u64, u32)>,
+}
+
+impl FrameAssembler {
+    pub fn new(packet_ids: &[PacketId], timeout: Duration) -> Self {
+        FrameAssembler {
+            packet_ids: packet_ids.to_vec(),
+            timeout,
+            pending: BTreeMap::new(),
+            emitted: VecDeque::with_capacity(EMITTED_HISTORY),
+        }
+    }
+
+    /// Adds a packet, returning the frames which are complete or have timed out, oldest first.
+    pub fn push(&mut self, packet: Packet) -> Vec<Frame> {
+        let header = packet.header();
+        let key = (header.session_uid, header.frame_identifier);
+        let (session_uid, session_time) = (header.session_uid, header.session_time);
+
+        let mut frames = Vec::new();
+        if self
+            .emitted
+            .back()
+            .is_some_and(|last| last.0 != session_uid)
+        {
+            self.emitted.clear();
+        }
+        if matches!(&packet, Packet::Event(EventPacket::Flashback(_))) {
+            // The game replays the frames after the flashback point, their identifiers are reused
+            frames.extend(self.flush());
+            self.emitted.clear();
+        }
+        let id = packet.id();
+        if !self.packet_ids.contains(&id) {
+            return frames;
+        }
+
+        frames.extend(self.expire(session_uid, session_time));
+        if self.emitted.contains(&key) {
+            // Late packet for a frame which was already emitted
+            return frames;
+        }
+        let (_, frame) = self
+            .pending
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), Frame::new(key.0, key.1, session_time)));
+        frame.insert(packet);
+        if is_complete(&self.packet_ids, frame) {
+            frames.extend(self.emit(key));
+        }
+        frames
+    }
+
+    /// Emits the frames which have waited longer than the timeout since their first packet.
+    pub fn poll(&mut self) -> Vec<Frame> {
+        let expired: Vec<(u64, u32)> = self
+            .pending
+            .iter()
+            .filter(|(_, (received, _))| received.elapsed() > self.timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.emit(key))
+            .collect()
+    }
+
+    /// Emits every pending frame, e.g. when recording stops.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        let keys: Vec<(u64, u32)> = self.pending.keys().copied().collect();
+        keys.into_iter().filter_map(|key| self.emit(key)).collect()
+    }
+
+    /// Emits the frames of other sessions and those older than the timeout.
+    fn expire(&mut self, session_uid: u64, session_time: f32) -> Vec<Frame> {
+        let timeout = self.timeout.as_secs_f32();
+        let expired: Vec<(u64, u32)> = self
+            .pending
+            .iter()
+            .filter(|(_, (received, frame))| {
+                frame.session_uid != session_uid
+                    || session_time - frame.session_time > timeout
+                    || received.elapsed() > self.timeout
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.emit(key))
+            .collect()
+    }
+
+    fn emit(&mut self, key: (u64, u32)) -> Option<Frame> {
+        let (_, mut frame) = self.pending.remove(&key)?;
+        frame.complete = is_complete(&self.packet_ids, &frame);
+        self.mark_emitted(key);
+        Some(frame)
+    }
+
+    fn mark_emitted(&mut self, key: (u64, u32)) {
+        if self.emitted.len() == EMITTED_HISTORY {
+            self.emitted.pop_front();
+        }
+        self.emitted.push_back(key);
+    }
+}
+
+fn is_complete(packet_ids: &[PacketId], frame: &Frame) -> bool {
+    packet_ids
+        .iter()
+        .filter(|id| **id != PacketId::Event)
+        .all(|id| frame.contains(*id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testing;
+
+    const IDS: [PacketId; 2] = [PacketId::LapData, PacketId::CarTelemetry];
+
+    fn lap_data(session_uid: u64, frame_identifier: u32, session_time: f32) -> Packet {
+        Packet::LapData(Box::new(testing::packet(
+            PacketId::LapData,
+            session_uid,
+            frame_identifier,
+            session_time,
+        )))
+    }
+
+    fn car_telemetry(session_uid: u64, frame_identifier: u32, session_time: f32) -> Packet {
+        Packet::CarTelemetry(Box::new(testing::packet(
+            PacketId::CarTelemetry,
+            session_uid,
+            frame_identifier,
+            session_time,
+        )))
+    }
+
+    #[test]
+    fn emits_complete_frames() {
+        let mut assembler = FrameAssembler::new(&IDS, Duration::from_secs(1));
+        assert!(assembler.push(lap_data(1, 10, 1.0)).is_empty());
+        let frames = assembler.push(car_telemetry(1, 10, 1.0));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].complete);
+        assert!(frames[0].lap_data.is_some() && frames[0].car_telemetry.is_some());
+        // Late duplicate of the emitted frame
+        assert!(assembler.push(lap_data(1, 10, 1.0)).is_empty());
+        assert!(assembler.flush().is_empty());
+    }
+
+    #[test]
+    fn emits_incomplete_frames_after_the_session_time_timeout() {
+        let mut assembler = FrameAssembler::new(&IDS, Duration::from_millis(100));
+        assembler.push(lap_data(1, 10, 1.0));
+        let frames = assembler.push(lap_data(1, 20, 1.2));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].frame_identifier, 10);
+        assert!(!frames[0].complete);
+    }
+
+    #[test]
+    fn poll_emits_frames_while_paused() {
+        let mut assembler = FrameAssembler::new(&IDS, Duration::from_millis(10));
+        assembler.push(lap_data(1, 10, 1.0));
+        assert!(assembler.poll().is_empty());
+        thread::sleep(Duration::from_millis(20));
+        let frames = assembler.poll();
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].complete);
+    }
+
+    #[test]
+    fn replays_frames_after_a_flashback() {
+        let mut assembler = FrameAssembler::new(&IDS, Duration::from_secs(1));
+        assembler.push(lap_data(1, 10, 1.0));
+        assembler.push(car_telemetry(1, 10, 1.0));
+        assembler.push(lap_data(1, 11, 1.1));
+        // Not subscribed to events, the flashback still resets the frames
+        let frames = assembler.push(testing::flashback(1, 12, 1.2, 9, 0.9));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].frame_identifier, 11);
+        assembler.push(lap_data(1, 10, 1.0));
+        let frames = assembler.push(car_telemetry(1, 10, 1.0));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].complete);
+    }
+
+    #[test]
+    fn new_session_emits_pending_frames() {
+        let mut assembler = FrameAssembler::new(&IDS, Duration::from_secs(1));
+        assembler.push(lap_data(1, 10, 1.0));
+        assembler.push(car_telemetry(1, 10, 1.0));
+        let frames = assembler.push(lap_data(2, 10, 1.0));
+        assert!(frames.is_empty());
+        let frames = assembler.push(car_telemetry(2, 10, 1.0));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].session_uid, 2);
+    }
+}
@@ -0,0 +1,505 @@
+//! Fuel consumption tracking and fuel strategy
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    laps::{CompletedLap, LapEvent, LapTracker},
+    packet::Packet,
+    telemetry::{CarStatusData, LapData, MAX_CARS},
+};
+
+/// Number of fuel mixes, lean, standard, rich and max.
+pub const FUEL_MIXES: usize = 4;
+/// Larger jumps in total distance between two lap data packets aren't driving, e.g. a teleport.
+const MAX_DISTANCE_STEP: f32 = 200.0;
+/// A mix needs to have been used for this fraction of a lap before its consumption is trusted.
+const MIN_MIX_LAP_FRACTION: f32 = 0.25;
+
+/// Fuel used on a lap.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FuelLap {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub fuel_used: f32,                     // Fuel mass used on the lap
+    pub fuel_by_mix: [f32; FUEL_MIXES],     // Fuel mass used in each mix, lean, standard, rich, max
+    pub distance_by_mix: [f32; FUEL_MIXES], // Metres driven in each mix
+    pub safety_car: bool, // The safety car, virtual safety car or formation lap was out on the lap
+    pub pit_lap: bool,    // In or out lap
+    pub session_time: f32, // Session time the lap was completed at
+}
+
+impl FuelLap {
+    /// Whether the lap is representative of racing consumption.
+    pub fn green(&self) -> bool {
+        !self.safety_car && !self.pit_lap
+    }
+}
+
+/// Fuel consumption of a car, in fuel mass per lap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FuelConsumption {
+    pub per_lap: Option<f32>,               // Average over green flag laps
+    pub safety_car_per_lap: Option<f32>,    // Average over laps behind the safety car
+    pub per_mix: [Option<f32>; FUEL_MIXES], // Green flag consumption of each mix
+}
+
+/// What the driver should do to finish the race with the target fuel margin.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FuelAdvice {
+    OnTarget,                // Keep the current mix
+    Richer { fuel_mix: u8 }, // Enough spare fuel to run this richer mix to the finish
+    Leaner { fuel_mix: u8 }, // This leaner mix is needed to reach the finish
+    // No mix is lean enough, run this one and save this fuel mass per lap
+    LiftAndCoast { fuel_mix: u8, save_per_lap: f32 },
+}
+
+/// Fuel at the finish of the race at the current consumption.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FuelProjection {
+    pub car_index: usize,
+    pub fuel_in_tank: f32,
+    pub fuel_mix: u8,          // Current fuel mix
+    pub laps_remaining: f32,   // Laps to the finish, including the rest of the current lap
+    pub fuel_per_lap: f32,     // Green flag consumption of the current mix
+    pub fuel_at_finish: f32,   // Projected fuel mass at the finish, negative when short
+    pub margin_laps: f32,      // Fuel at the finish in laps
+    pub game_margin_laps: f32, // `CarStatusData::fuel_remaining_laps`, the value on the MFD
+    pub safety_car: bool,      // The safety car is out, consumption will be lower than projected
+    pub advice: FuelAdvice,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarFuel {
+    status: Option<CarStatusData>,
+    lap: Option<LapData>,
+    fuel_by_mix: [f32; FUEL_MIXES], // Current lap
+    distance_by_mix: [f32; FUEL_MIXES],
+    fuel_since_lap_data: [f32; FUEL_MIXES], // Burnt since the last lap data packet
+    carried: Option<([f32; FUEL_MIXES], [f32; FUEL_MIXES])>, // Fuel and distance past the line
+    safety_car: bool,
+    laps: Vec<FuelLap>,
+}
+
+impl CarFuel {
+    /// Adds the distance driven since the last lap data packet. When the car crossed the line the
+    /// distance and fuel past it are carried over to the next lap.
+    fn add_driven(&mut self, lap: &LapData) {
+        let fuel = std::mem::take(&mut self.fuel_since_lap_data);
+        let (old, status) = match (&self.lap, &self.status) {
+            (Some(old), Some(status)) => (old, status),
+            _ => return,
+        };
+        let driven = lap.total_distance - old.total_distance;
+        let mix = status.fuel_mix as usize;
+        if driven <= 0.0 || driven >= MAX_DISTANCE_STEP || mix >= FUEL_MIXES {
+            return;
+        }
+        if old.current_lap_num.checked_add(1) != Some(lap.current_lap_num) {
+            self.distance_by_mix[mix] += driven;
+            return;
+        }
+        let past_line = lap.lap_distance.clamp(0.0, driven);
+        let ratio = past_line / driven;
+        let mut carried_fuel = [0.0; FUEL_MIXES];
+        for (carried, fuel) in carried_fuel.iter_mut().zip(fuel.iter()) {
+            *carried = fuel * ratio;
+        }
+        let mut carried_distance = [0.0; FUEL_MIXES];
+        carried_distance[mix] = past_line;
+        self.distance_by_mix[mix] += driven - past_line;
+        for (total, carried) in self.fuel_by_mix.iter_mut().zip(carried_fuel.iter()) {
+            *total -= carried;
+        }
+        self.carried = Some((carried_fuel, carried_distance));
+    }
+
+    fn add(&mut self, fuel: &[f32; FUEL_MIXES], distance: &[f32; FUEL_MIXES]) {
+        for mix in 0..FUEL_MIXES {
+            self.fuel_by_mix[mix] += fuel[mix];
+            self.distance_by_mix[mix] += distance[mix];
+        }
+    }
+}
+
+/// Tracks the fuel used on every lap, per fuel mix and under the safety car, and projects the
+/// fuel left at the finish of the race.
+#[derive(Debug, Clone)]
+pub struct FuelTracker {
+    session_uid: u64,
+    laps: LapTracker,
+    total_laps: u8,
+    track_length: u16,
+    safety_car_status: u8,
+    target_margin: f32,
+    cars: Vec<CarFuel>,
+}
+
+impl Default for FuelTracker {
+    fn default() -> Self {
+        FuelTracker {
+            session_uid: 0,
+            laps: LapTracker::default(),
+            total_laps: 0,
+            track_length: 0,
+            safety_car_status: 0,
+            target_margin: 0.0,
+            cars: vec![CarFuel::default(); MAX_CARS],
+        }
+    }
+}
+
+impl FuelTracker {
+    pub fn new() -> Self {
+        FuelTracker::default()
+    }
+
+    /// Fuel mass to have left at the finish, the advice aims for it. Defaults to none.
+    pub fn target_margin(mut self, fuel: f32) -> Self {
+        self.target_margin = fuel;
+        self
+    }
+
+    /// Fuel used on each completed lap of the car, oldest first.
+    pub fn laps(&self, car_index: usize) -> &[FuelLap] {
+        self.cars.get(car_index).map_or(&[], |car| &car.laps[..])
+    }
+
+    /// Updates the tracker with the packet, returning the fuel used on the laps completed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<FuelLap> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = FuelTracker {
+                target_margin: self.target_margin,
+                ..FuelTracker::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind_fuel(&point);
+        }
+        if let Packet::LapData(pkt) = packet {
+            // Split what was used since the last lap data at the line before the lap is closed
+            for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+                car.add_driven(lap);
+            }
+        }
+        let completed: Vec<FuelLap> = self
+            .laps
+            .apply(packet)
+            .into_iter()
+            .filter_map(|event| match event {
+                LapEvent::Completed(lap) => Some(self.lap_completed(&lap)),
+                LapEvent::Corrected(_) => None,
+            })
+            .collect();
+        match packet {
+            Packet::Session(pkt) => {
+                self.total_laps = pkt.total_laps;
+                self.track_length = pkt.track_length;
+                self.safety_car_status = pkt.safety_car_status;
+            }
+            Packet::CarStatus(pkt) => {
+                for (car, status) in self.cars.iter_mut().zip(pkt.car_status_data.iter()) {
+                    if let Some(old) = &car.status {
+                        // Fuel burnt since the last packet counts towards the mix it was burnt in
+                        let used = old.fuel_in_tank - status.fuel_in_tank;
+                        let mix = old.fuel_mix as usize;
+                        if used > 0.0 && mix < FUEL_MIXES {
+                            car.fuel_by_mix[mix] += used;
+                            car.fuel_since_lap_data[mix] += used;
+                        }
+                    }
+                    car.status = Some(status.clone());
+                }
+            }
+            Packet::LapData(pkt) => {
+                let safety_car = self.safety_car_status != 0;
+                for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+                    if let Some((fuel, distance)) = car.carried.take() {
+                        // The line was crossed without a lap being completed, e.g. the first lap
+                        car.add(&fuel, &distance);
+                    }
+                    car.safety_car |= safety_car;
+                    car.lap = Some(lap.clone());
+                }
+            }
+            _ => {}
+        }
+        completed
+    }
+
+    fn lap_completed(&mut self, lap: &CompletedLap) -> FuelLap {
+        let car = &mut self.cars[lap.car_index];
+        let fuel_used = match (lap.fuel_at_start, lap.fuel_at_end) {
+            (Some(start), Some(end)) => (start - end).max(0.0),
+            _ => car.fuel_by_mix.iter().sum(),
+        };
+        let fuel_lap = FuelLap {
+            car_index: lap.car_index,
+            lap_number: lap.lap_number,
+            fuel_used,
+            fuel_by_mix: car.fuel_by_mix,
+            distance_by_mix: car.distance_by_mix,
+            safety_car: car.safety_car,
+            pit_lap: lap.pit_in || lap.pit_out,
+            session_time: lap.session_time,
+        };
+        car.fuel_by_mix = [0.0; FUEL_MIXES];
+        car.distance_by_mix = [0.0; FUEL_MIXES];
+        if let Some((fuel, distance)) = car.carried.take() {
+            car.add(&fuel, &distance);
+        }
+        car.safety_car = false;
+        car.laps.push(fuel_lap.clone());
+        fuel_lap
+    }
+
+    /// Fuel consumption of the car over the laps completed so far.
+    pub fn consumption(&self, car_index: usize) -> FuelConsumption {
+        let laps = self.laps(car_index);
+        let average = |green: bool| {
+            let used: Vec<f32> = laps
+                .iter()
+                .filter(|lap| lap.green() == green && !lap.pit_lap && lap.fuel_used > 0.0)
+                .map(|lap| lap.fuel_used)
+                .collect();
+            (!used.is_empty()).then(|| used.iter().sum::<f32>() / used.len() as f32)
+        };
+        let mut per_mix = [None; FUEL_MIXES];
+        if self.track_length > 0 {
+            let track_length = self.track_length as f32;
+            for (mix, rate) in per_mix.iter_mut().enumerate() {
+                let (fuel, distance) = laps.iter().filter(|lap| lap.green()).fold(
+                    (0.0, 0.0),
+                    |(fuel, distance), lap| {
+                        (
+                            fuel + lap.fuel_by_mix[mix],
+                            distance + lap.distance_by_mix[mix],
+                        )
+                    },
+                );
+                if distance >= MIN_MIX_LAP_FRACTION * track_length && fuel > 0.0 {
+                    *rate = Some(fuel / distance * track_length);
+                }
+            }
+        }
+        FuelConsumption {
+            per_lap: average(true),
+            safety_car_per_lap: average(false),
+            per_mix,
+        }
+    }
+
+    /// Projects the fuel the car will have left at the finish and advises on the fuel mix. None
+    /// outside of races or before the car's consumption is known.
+    pub fn projection(&self, car_index: usize) -> Option<FuelProjection> {
+        let car = self.cars.get(car_index)?;
+        let (status, lap) = (car.status.as_ref()?, car.lap.as_ref()?);
+        if self.total_laps == 0 || self.track_length == 0 {
+            return None;
+        }
+        let laps_done = lap.current_lap_num.saturating_sub(1) as f32
+            + lap.lap_distance.max(0.0) / self.track_length as f32;
+        let laps_remaining = (self.total_laps as f32 - laps_done).max(0.0);
+        let consumption = self.consumption(car_index);
+        let mix_rate = |mix: u8| consumption.per_mix.get(mix as usize).copied().flatten();
+        let fuel_per_lap = mix_rate(status.fuel_mix).or(consumption.per_lap)?;
+        let fuel_at_finish = status.fuel_in_tank - fuel_per_lap * laps_remaining;
+        let spare = |rate: f32| status.fuel_in_tank - rate * laps_remaining - self.target_margin;
+
+        let advice = if spare(fuel_per_lap) < 0.0 {
+            // The richest of the leaner mixes which still reaches the finish
+            let leaner = (0..status.fuel_mix)
+                .rev()
+                .filter_map(|mix| Some((mix, mix_rate(mix)?)))
+                .find(|(_, rate)| spare(*rate) >= 0.0);
+            match leaner {
+                Some((fuel_mix, _)) => FuelAdvice::Leaner { fuel_mix },
+                None => {
+                    let (fuel_mix, rate) = (0..status.fuel_mix)
+                        .filter_map(|mix| Some((mix, mix_rate(mix)?)))
+                        .next()
+                        .unwrap_or((status.fuel_mix, fuel_per_lap));
+                    FuelAdvice::LiftAndCoast {
+                        fuel_mix,
+                        save_per_lap: -spare(rate) / laps_remaining.max(1.0),
+                    }
+                }
+            }
+        } else {
+            let richer = (status.fuel_mix.saturating_add(1)..FUEL_MIXES as u8)
+                .rev()
+                .filter_map(|mix| Some((mix, mix_rate(mix)?)))
+                .find(|(_, rate)| spare(*rate) >= 0.0);
+            match richer {
+                Some((fuel_mix, _)) => FuelAdvice::Richer { fuel_mix },
+                None => FuelAdvice::OnTarget,
+            }
+        };
+
+        Some(FuelProjection {
+            car_index,
+            fuel_in_tank: status.fuel_in_tank,
+            fuel_mix: status.fuel_mix,
+            laps_remaining,
+            fuel_per_lap,
+            fuel_at_finish,
+            margin_laps: if fuel_per_lap > 0.0 {
+                fuel_at_finish / fuel_per_lap
+            } else {
+                0.0
+            },
+            game_margin_laps: status.fuel_remaining_laps,
+            safety_car: self.safety_car_status != 0,
+            advice,
+        })
+    }
+
+    fn rewind_fuel(&mut self, point: &FlashbackPoint) {
+        for car in self.cars.iter_mut() {
+            car.laps
+                .retain(|lap| !point.supersedes(point.session_uid, lap.session_time));
+            // The fuel comes back after a flashback, the current lap is only partly counted
+            car.status = None;
+            car.lap = None;
+            car.fuel_by_mix = [0.0; FUEL_MIXES];
+            car.distance_by_mix = [0.0; FUEL_MIXES];
+            car.fuel_since_lap_data = [0.0; FUEL_MIXES];
+            car.carried = None;
+        }
+    }
+}
+
+impl Rewind for FuelTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.laps.rewind(point);
+        self.rewind_fuel(point);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarStatusData, PacketLapData, PacketSessionData},
+        testing,
+    };
+
+    const TRACK_LENGTH: f32 = 5000.0;
+
+    fn session(session_uid: u64) -> Packet {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 0.0);
+        pkt.total_laps = 10;
+        pkt.track_length = TRACK_LENGTH as u16;
+        Packet::Session(Box::new(pkt))
+    }
+
+    fn car_status(session_uid: u64, session_time: f32, fuel: f32) -> Packet {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        pkt.car_status_data[0].fuel_in_tank = fuel;
+        pkt.car_status_data[0].fuel_mix = 1;
+        Packet::CarStatus(Box::new(pkt))
+    }
+
+    fn lap_data(session_uid: u64, session_time: f32, total_distance: f32) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        let lap = &mut pkt.lap_data[0];
+        lap.total_distance = total_distance;
+        lap.current_lap_num = (total_distance / TRACK_LENGTH) as u8 + 1;
+        lap.lap_distance = total_distance % TRACK_LENGTH;
+        Packet::LapData(Box::new(pkt))
+    }
+
+    /// Drives at 100 metres per packet, using 0.002 fuel per metre. The car status packets are
+    /// sent halfway between the lap data packets, so the line is crossed between the two.
+    fn drive(tracker: &mut FuelTracker, session_uid: u64, from: f32, to: f32) -> Vec<FuelLap> {
+        let mut completed = Vec::new();
+        let mut distance = from;
+        while distance <= to {
+            let session_time = distance / 50.0;
+            completed.extend(tracker.apply(&lap_data(session_uid, session_time, distance)));
+            let fuel = 100.0 - (distance + 50.0) * 0.002;
+            tracker.apply(&car_status(session_uid, session_time + 1.0, fuel));
+            distance += 100.0;
+        }
+        completed
+    }
+
+    #[test]
+    fn first_packets_complete_no_lap() {
+        let mut tracker = FuelTracker::new();
+        tracker.apply(&session(1));
+        assert!(drive(&mut tracker, 1, 2030.0, 4930.0).is_empty());
+        assert_eq!(tracker.projection(0).map(|p| p.fuel_per_lap), None);
+    }
+
+    #[test]
+    fn lap_rollover_splits_the_fuel_and_distance_at_the_line() {
+        let mut tracker = FuelTracker::new();
+        tracker.apply(&session(1));
+        let laps = drive(&mut tracker, 1, 30.0, 15_030.0);
+        // The first lap was joined after the line
+        assert_eq!(laps.len(), 3);
+        for lap in laps[1..].iter() {
+            assert!(
+                (lap.distance_by_mix[1] - TRACK_LENGTH).abs() < 0.1,
+                "{:?}",
+                lap
+            );
+        }
+        // The second lap was started on the line
+        assert!(
+            (laps[1].fuel_by_mix[1] - 10.0).abs() < 0.01,
+            "{:?}",
+            laps[1]
+        );
+        assert!((laps[1].fuel_used - 10.0).abs() < 0.01);
+        let consumption = tracker.consumption(0);
+        assert!((consumption.per_mix[1].unwrap() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn projects_the_fuel_at_the_finish() {
+        let mut tracker = FuelTracker::new().target_margin(1.0);
+        tracker.apply(&session(1));
+        drive(&mut tracker, 1, 30.0, 15_030.0);
+        let projection = tracker.projection(0).unwrap();
+        assert!((projection.laps_remaining - 6.994).abs() < 0.001);
+        assert!((projection.fuel_per_lap - 10.0).abs() < 0.1);
+        let fuel_at_finish = 69.84 - projection.fuel_per_lap * 6.994;
+        assert!((projection.fuel_at_finish - fuel_at_finish).abs() < 0.01);
+        // Short of the target margin with no leaner mix known
+        assert!(matches!(
+            projection.advice,
+            FuelAdvice::LiftAndCoast { fuel_mix: 1, save_per_lap } if save_per_lap > 0.0
+        ));
+    }
+
+    #[test]
+    fn flashback_drops_the_laps_after_the_point() {
+        let mut tracker = FuelTracker::new();
+        tracker.apply(&session(1));
+        drive(&mut tracker, 1, 30.0, 15_030.0);
+        tracker.apply(&testing::flashback(1, 1000, 300.6, 200, 120.0));
+        assert_eq!(tracker.laps(0).len(), 1);
+        // What was used after the point isn't counted towards the lap
+        let laps = drive(&mut tracker, 1, 6030.0, 10_030.0);
+        assert_eq!(laps.len(), 1);
+        assert!(laps[0].fuel_by_mix[1] < 10.0);
+    }
+
+    #[test]
+    fn session_change_keeps_the_target_margin() {
+        let mut tracker = FuelTracker::new().target_margin(2.0);
+        drive(&mut tracker, 1, 30.0, 10_030.0);
+        tracker.apply(&session(2));
+        assert!(tracker.laps(0).is_empty());
+        assert_eq!(tracker.target_margin, 2.0);
+    }
+}
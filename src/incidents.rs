@@ -0,0 +1,414 @@
+//! Incident and collision detection from g-forces, damage and proximity
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{CarDamageData, CarMotionData, LapData, MAX_CARS},
+};
+
+/// Change in horizontal g-force between two motion packets which counts as a spike.
+const G_SPIKE_THRESHOLD: f32 = 3.0;
+/// Increase in the damage of a part (percentage) which counts as new damage.
+const DAMAGE_THRESHOLD: u8 = 5;
+/// Cars closer than this many metres can have touched.
+const PROXIMITY: f32 = 6.0;
+/// Signals within this many seconds of each other belong to the same incident.
+const INCIDENT_WINDOW: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BodyPart {
+    FrontLeftWing,
+    FrontRightWing,
+    RearWing,
+    Floor,
+    Diffuser,
+    Sidepod,
+}
+
+impl BodyPart {
+    const ALL: [BodyPart; 6] = [
+        BodyPart::FrontLeftWing,
+        BodyPart::FrontRightWing,
+        BodyPart::RearWing,
+        BodyPart::Floor,
+        BodyPart::Diffuser,
+        BodyPart::Sidepod,
+    ];
+
+    fn damage(&self, damage: &CarDamageData) -> u8 {
+        match self {
+            BodyPart::FrontLeftWing => damage.front_left_wing_damage,
+            BodyPart::FrontRightWing => damage.front_right_wing_damage,
+            BodyPart::RearWing => damage.rear_wing_damage,
+            BodyPart::Floor => damage.floor_damage,
+            BodyPart::Diffuser => damage.diffuser_damage,
+            BodyPart::Sidepod => damage.sidepod_damage,
+        }
+    }
+}
+
+/// Something suggesting a car hit something.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum IncidentSignal {
+    GForceSpike {
+        car_index: usize,
+        change: f32, // Change in horizontal g-force since the previous motion packet
+    },
+    Damage {
+        car_index: usize,
+        part: BodyPart,
+        increase: u8, // Percentage points
+    },
+}
+
+impl IncidentSignal {
+    pub fn car_index(&self) -> usize {
+        match self {
+            IncidentSignal::GForceSpike { car_index, .. } => *car_index,
+            IncidentSignal::Damage { car_index, .. } => *car_index,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IncidentKind {
+    Contact, // Two or more cars were close together
+    Solo,    // No other car was close, e.g. hitting a wall
+}
+
+/// A likely contact or crash.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    pub cars: Vec<usize>, // Car indices involved, the car with the first signal first
+    pub lap_number: u8,   // Lap of the first car
+    pub lap_distance: f32, // Lap distance of the first car
+    pub session_time: f32, // Session time of the first signal
+    pub signals: Vec<IncidentSignal>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarIncidents {
+    motion: Option<CarMotionData>,
+    damage: Option<CarDamageData>,
+    lap: Option<LapData>,
+}
+
+/// Flags likely contacts between cars, and solo crashes, from sudden changes in the g-forces,
+/// new damage to the wings, floor, diffuser or sidepods, and the distance between the cars. An
+/// incident is reported once no signal was added to it for a second, so that the damage, which
+/// is sent less often than the motion, is part of the same incident.
+#[derive(Debug, Clone)]
+pub struct IncidentDetector {
+    session_uid: u64,
+    cars: Vec<CarIncidents>,
+    open: Vec<(f32, Incident)>, // Session time of the latest signal and the incident
+    incidents: Vec<Incident>,
+}
+
+impl Default for IncidentDetector {
+    fn default() -> Self {
+        IncidentDetector {
+            session_uid: 0,
+            cars: vec![CarIncidents::default(); MAX_CARS],
+            open: Vec::new(),
+            incidents: Vec::new(),
+        }
+    }
+}
+
+impl IncidentDetector {
+    pub fn new() -> Self {
+        IncidentDetector::default()
+    }
+
+    /// Incidents reported this session, oldest first.
+    pub fn incidents(&self) -> &[Incident] {
+        &self.incidents
+    }
+
+    /// Updates the detector with the packet, returning the incidents which are complete.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<Incident> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = IncidentDetector::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        let session_time = header.session_time;
+        let mut signals = Vec::new();
+        match packet {
+            Packet::Motion(pkt) => {
+                for (car_index, (car, motion)) in self
+                    .cars
+                    .iter_mut()
+                    .zip(pkt.car_motion_data.iter())
+                    .enumerate()
+                {
+                    if let Some(old) = &car.motion {
+                        let change = (motion.g_force_lateral - old.g_force_lateral)
+                            .hypot(motion.g_force_longitudinal - old.g_force_longitudinal);
+                        if change >= G_SPIKE_THRESHOLD {
+                            signals.push(IncidentSignal::GForceSpike { car_index, change });
+                        }
+                    }
+                    car.motion = Some(motion.clone());
+                }
+            }
+            Packet::CarDamage(pkt) => {
+                for (car_index, (car, damage)) in self
+                    .cars
+                    .iter_mut()
+                    .zip(pkt.car_damage_data.iter())
+                    .enumerate()
+                {
+                    if let Some(old) = &car.damage {
+                        for part in BodyPart::ALL {
+                            let increase = part.damage(damage).saturating_sub(part.damage(old));
+                            if increase >= DAMAGE_THRESHOLD {
+                                signals.push(IncidentSignal::Damage {
+                                    car_index,
+                                    part,
+                                    increase,
+                                });
+                            }
+                        }
+                    }
+                    car.damage = Some(damage.clone());
+                }
+            }
+            Packet::LapData(pkt) => {
+                for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+                    car.lap = Some(lap.clone());
+                }
+            }
+            _ => {}
+        }
+        for signal in signals {
+            self.add_signal(signal, session_time);
+        }
+
+        let (closed, open): (Vec<_>, Vec<_>) = self
+            .open
+            .drain(..)
+            .partition(|(latest, _)| session_time - latest > INCIDENT_WINDOW);
+        self.open = open;
+        let closed: Vec<Incident> = closed
+            .into_iter()
+            .map(|(_, incident)| incident)
+            // A lone g-force spike without another car close could be a kerb
+            .filter(|incident| {
+                incident.kind == IncidentKind::Contact
+                    || incident
+                        .signals
+                        .iter()
+                        .any(|signal| matches!(signal, IncidentSignal::Damage { .. }))
+            })
+            .collect();
+        self.incidents.extend(closed.iter().cloned());
+        closed
+    }
+
+    /// Cars within reach of the car, by their latest world positions.
+    fn nearby(&self, car_index: usize) -> Vec<usize> {
+        let position = match &self.cars[car_index].motion {
+            Some(motion) => motion,
+            None => return Vec::new(),
+        };
+        self.cars
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != car_index)
+            .filter(|(_, car)| car.lap.as_ref().is_some_and(|lap| lap.result_status == 2))
+            .filter_map(|(other, car)| Some((other, car.motion.as_ref()?)))
+            .filter(|(_, motion)| {
+                let dx = motion.world_position_x - position.world_position_x;
+                let dy = motion.world_position_y - position.world_position_y;
+                let dz = motion.world_position_z - position.world_position_z;
+                (dx * dx + dy * dy + dz * dz).sqrt() <= PROXIMITY
+            })
+            .map(|(other, _)| other)
+            .collect()
+    }
+
+    fn add_signal(&mut self, signal: IncidentSignal, session_time: f32) {
+        let car_index = signal.car_index();
+        let nearby = self.nearby(car_index);
+        let open = self.open.iter_mut().find(|(latest, incident)| {
+            session_time - latest <= INCIDENT_WINDOW && incident.cars.contains(&car_index)
+        });
+        match open {
+            Some((latest, incident)) => {
+                *latest = session_time;
+                for other in nearby {
+                    if !incident.cars.contains(&other) {
+                        incident.cars.push(other);
+                    }
+                }
+                if incident.cars.len() > 1 {
+                    incident.kind = IncidentKind::Contact;
+                }
+                incident.signals.push(signal);
+            }
+            None => {
+                let lap = self.cars[car_index].lap.as_ref();
+                let mut cars = vec![car_index];
+                cars.extend(nearby);
+                let incident = Incident {
+                    kind: if cars.len() > 1 {
+                        IncidentKind::Contact
+                    } else {
+                        IncidentKind::Solo
+                    },
+                    cars,
+                    lap_number: lap.map_or(0, |lap| lap.current_lap_num),
+                    lap_distance: lap.map_or(0.0, |lap| lap.lap_distance),
+                    session_time,
+                    signals: vec![signal],
+                };
+                self.open.push((session_time, incident));
+            }
+        }
+    }
+}
+
+impl Rewind for IncidentDetector {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.incidents
+            .retain(|incident| !point.supersedes(point.session_uid, incident.session_time));
+        self.open.clear();
+        // The g-forces and damage jump back, that isn't an incident
+        for car in self.cars.iter_mut() {
+            car.motion = None;
+            car.damage = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarDamageData, PacketLapData, PacketMotionData},
+        testing,
+    };
+
+    /// Cars 0 and 1 racing on lap 3.
+    fn lap_data(detector: &mut IncidentDetector, session_uid: u64) {
+        let mut pkt: PacketLapData = testing::packet(PacketId::LapData, session_uid, 0, 0.0);
+        for lap in pkt.lap_data.iter_mut().take(2) {
+            lap.current_lap_num = 3;
+            lap.lap_distance = 800.0;
+            lap.result_status = 2;
+        }
+        detector.apply(&Packet::LapData(Box::new(pkt)));
+    }
+
+    /// Car 0 with the lateral g-force at the origin, car 1 `gap` metres away.
+    fn motion(
+        detector: &mut IncidentDetector,
+        session_uid: u64,
+        session_time: f32,
+        g_force_lateral: f32,
+        gap: f32,
+    ) -> Vec<Incident> {
+        let mut pkt: PacketMotionData =
+            testing::packet(PacketId::Motion, session_uid, 0, session_time);
+        pkt.car_motion_data[0].g_force_lateral = g_force_lateral;
+        pkt.car_motion_data[1].world_position_x = gap;
+        detector.apply(&Packet::Motion(Box::new(pkt)))
+    }
+
+    fn damage(
+        detector: &mut IncidentDetector,
+        session_uid: u64,
+        session_time: f32,
+        front_left_wing_damage: u8,
+    ) -> Vec<Incident> {
+        let mut pkt: PacketCarDamageData =
+            testing::packet(PacketId::CarDamage, session_uid, 0, session_time);
+        pkt.car_damage_data[0].front_left_wing_damage = front_left_wing_damage;
+        detector.apply(&Packet::CarDamage(Box::new(pkt)))
+    }
+
+    #[test]
+    fn spike_next_to_another_car_is_a_contact() {
+        let mut detector = IncidentDetector::new();
+        lap_data(&mut detector, 1);
+        // The first motion packet has nothing to compare against
+        assert!(motion(&mut detector, 1, 1.0, 4.0, 2.0).is_empty());
+        assert!(motion(&mut detector, 1, 1.1, 0.0, 2.0).is_empty());
+        let incidents = motion(&mut detector, 1, 2.2, 0.0, 2.0);
+        let [incident] = &incidents[..] else {
+            panic!("expected one incident, got {:?}", incidents);
+        };
+        assert_eq!(incident.kind, IncidentKind::Contact);
+        assert_eq!(incident.cars, vec![0, 1]);
+        assert_eq!((incident.lap_number, incident.lap_distance), (3, 800.0));
+        assert_eq!(incident.session_time, 1.1);
+        assert_eq!(detector.incidents(), &incidents[..]);
+    }
+
+    #[test]
+    fn lone_spike_needs_damage_to_be_a_crash() {
+        let mut detector = IncidentDetector::new();
+        lap_data(&mut detector, 1);
+        motion(&mut detector, 1, 1.0, 0.0, 50.0);
+        motion(&mut detector, 1, 1.1, 4.0, 50.0);
+        // A kerb
+        assert!(motion(&mut detector, 1, 2.2, 4.0, 50.0).is_empty());
+
+        damage(&mut detector, 1, 2.2, 0);
+        motion(&mut detector, 1, 3.0, 0.0, 50.0);
+        damage(&mut detector, 1, 3.5, 20);
+        let incidents = motion(&mut detector, 1, 4.6, 0.0, 50.0);
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].kind, IncidentKind::Solo);
+        assert_eq!(
+            incidents[0].signals[1],
+            IncidentSignal::Damage {
+                car_index: 0,
+                part: BodyPart::FrontLeftWing,
+                increase: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn flashback_isnt_an_incident_and_drops_the_later_ones() {
+        let mut detector = IncidentDetector::new();
+        lap_data(&mut detector, 1);
+        motion(&mut detector, 1, 1.0, 0.0, 2.0);
+        motion(&mut detector, 1, 1.1, 4.0, 2.0);
+        motion(&mut detector, 1, 2.2, 4.0, 2.0);
+        assert_eq!(detector.incidents().len(), 1);
+        damage(&mut detector, 1, 2.3, 0);
+        detector.apply(&testing::flashback(1, 0, 2.4, 0, 1.0));
+        assert!(detector.incidents().is_empty());
+        // The g-forces and damage jump back to where the car was
+        motion(&mut detector, 1, 1.1, 0.0, 2.0);
+        damage(&mut detector, 1, 1.2, 30);
+        assert!(motion(&mut detector, 1, 2.5, 0.0, 2.0).is_empty());
+        assert!(detector.incidents().is_empty());
+    }
+
+    #[test]
+    fn session_change_resets_the_incidents() {
+        let mut detector = IncidentDetector::new();
+        lap_data(&mut detector, 1);
+        motion(&mut detector, 1, 1.0, 0.0, 2.0);
+        motion(&mut detector, 1, 1.1, 4.0, 2.0);
+        motion(&mut detector, 1, 2.2, 4.0, 2.0);
+        // No previous motion in the new session, so no spike
+        assert!(motion(&mut detector, 2, 0.1, 0.0, 2.0).is_empty());
+        assert!(motion(&mut detector, 2, 1.5, 0.0, 2.0).is_empty());
+        assert!(detector.incidents().is_empty());
+    }
+}
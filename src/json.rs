@@ -0,0 +1,50 @@
+//! Loading and saving the analysis results as JSON files
+use std::{fs, path::Path};
+
+use error_stack::{IntoReport, Result, ResultExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors::TelemetryError;
+
+pub(crate) fn load_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, TelemetryError> {
+    let data = fs::read_to_string(path)
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    serde_json::from_str(&data)
+        .report()
+        .change_context_lazy(|| TelemetryError)
+}
+
+pub(crate) fn save_json<T: Serialize>(
+    value: &T,
+    path: impl AsRef<Path>,
+) -> Result<(), TelemetryError> {
+    let data = serde_json::to_string(value)
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    fs::write(path, data)
+        .report()
+        .change_context_lazy(|| TelemetryError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn saved_value_loads_back() {
+        let path = env::temp_dir().join(format!("f1-telemetry-json-{}.json", std::process::id()));
+        save_json(&vec![(1u8, 2.5f32)], &path).unwrap();
+        let loaded: Vec<(u8, f32)> = load_json(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, vec![(1, 2.5)]);
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let path = env::temp_dir().join("f1-telemetry-json-missing.json");
+        assert!(load_json::<Vec<u8>>(path).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Lap tracker producing a record for every completed lap
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{CarStatusData, LapData, PacketLapData, PacketSessionHistoryData, MAX_CARS},
+};
+
+/// A lap completed by a car.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompletedLap {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub lap_time_in_ms: u32,
+    pub sector1_time_in_ms: u16,
+    pub sector2_time_in_ms: u16,
+    pub sector3_time_in_ms: u16,
+    pub valid: bool,
+    pub tyre_actual_compound: u8, // See `CarStatusData::actual_tyre_compound`, 0 if unknown
+    pub tyre_visual_compound: u8, // See `CarStatusData::visual_tyre_compound`, 0 if unknown
+    pub tyres_age_laps: u8,       // Age of the tyres at the end of the lap
+    pub fuel_at_start: Option<f32>, // Fuel mass when the lap started, None if it wasn't seen
+    pub fuel_at_end: Option<f32>,
+    pub position: u8,      // Race position at the end of the lap
+    pub pit_in: bool,      // Car entered the pit lane on this lap
+    pub pit_out: bool,     // Lap started in the pit lane
+    pub session_time: f32, // Session time the lap was completed at
+    pub reconciled: bool,  // Times were confirmed by the session history packet
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum LapEvent {
+    Completed(CompletedLap),
+    Corrected(CompletedLap), // The session history packet disagreed with the completed lap
+}
+
+/// Progress through the lap a car is currently on.
+#[derive(Debug, Clone)]
+struct LapProgress {
+    lap_number: u8,
+    sector1_time_in_ms: u16,
+    sector2_time_in_ms: u16,
+    invalid: bool,
+    pit_in: bool,
+    pit_out: bool,
+    fuel_at_start: Option<f32>,
+}
+
+impl LapProgress {
+    /// Starts tracking the lap. The fuel is only known if the car was seen crossing the line.
+    fn start(lap: &LapData, status: Option<&CarStatusData>, at_line: bool) -> Self {
+        LapProgress {
+            lap_number: lap.current_lap_num,
+            sector1_time_in_ms: lap.sector1_time_in_ms,
+            sector2_time_in_ms: lap.sector2_time_in_ms,
+            invalid: lap.current_lap_invalid == 1,
+            pit_in: false,
+            pit_out: lap.pit_status != 0,
+            fuel_at_start: status.filter(|_| at_line).map(|status| status.fuel_in_tank),
+        }
+    }
+
+    fn update(&mut self, lap: &LapData) {
+        self.sector1_time_in_ms = lap.sector1_time_in_ms;
+        self.sector2_time_in_ms = lap.sector2_time_in_ms;
+        self.invalid |= lap.current_lap_invalid == 1;
+        // Entering the pits late in the lap counts as an in lap, the pit exit is on the next lap
+        self.pit_in |= lap.pit_status != 0 && !self.pit_out;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarLaps {
+    progress: Option<LapProgress>,
+    status: Option<CarStatusData>,
+    completed: Vec<CompletedLap>,
+}
+
+/// Emits a [`CompletedLap`] for every car as it crosses the line, using the lap data and car
+/// status packets. The session history packets are used to confirm or correct the lap and sector
+/// times once the game has them.
+#[derive(Debug, Clone)]
+pub struct LapTracker {
+    session_uid: u64,
+    cars: Vec<CarLaps>,
+}
+
+impl Default for LapTracker {
+    fn default() -> Self {
+        LapTracker {
+            session_uid: 0,
+            cars: vec![CarLaps::default(); MAX_CARS],
+        }
+    }
+}
+
+impl LapTracker {
+    pub fn new() -> Self {
+        LapTracker::default()
+    }
+
+    /// Completed laps of the car, oldest first.
+    pub fn laps(&self, car_index: usize) -> &[CompletedLap] {
+        self.cars
+            .get(car_index)
+            .map_or(&[], |car| &car.completed[..])
+    }
+
+    /// Fastest valid lap of the car.
+    pub fn best_lap(&self, car_index: usize) -> Option<&CompletedLap> {
+        self.laps(car_index)
+            .iter()
+            .filter(|lap| lap.valid && lap.lap_time_in_ms > 0)
+            .min_by_key(|lap| lap.lap_time_in_ms)
+    }
+
+    /// Updates the tracker with the packet, returning the laps completed or corrected.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<LapEvent> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = LapTracker::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::LapData(pkt) => self.apply_lap_data(pkt),
+            Packet::CarStatus(pkt) => {
+                self.cars
+                    .iter_mut()
+                    .zip(pkt.car_status_data.iter())
+                    .for_each(|(car, status)| car.status = Some(status.clone()));
+                Vec::new()
+            }
+            Packet::SessionHistory(pkt) => self.apply_session_history(pkt),
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<LapEvent> {
+        let session_time = pkt.header.session_time;
+        let mut events = Vec::new();
+        for (car_index, (car, lap)) in self.cars.iter_mut().zip(pkt.lap_data.iter()).enumerate() {
+            if lap.current_lap_num == 0 {
+                continue;
+            }
+            let progress = match &mut car.progress {
+                Some(progress) if progress.lap_number == lap.current_lap_num => {
+                    progress.update(lap);
+                    continue;
+                }
+                Some(progress)
+                    if progress.lap_number.checked_add(1) == Some(lap.current_lap_num) =>
+                {
+                    progress.clone()
+                }
+                _ => {
+                    // First lap data seen for the car, or laps were skipped
+                    car.progress = Some(LapProgress::start(lap, car.status.as_ref(), false));
+                    continue;
+                }
+            };
+            let status = car.status.as_ref();
+            let sector3_time_in_ms = lap
+                .last_lap_time_in_ms
+                .saturating_sub(progress.sector1_time_in_ms as u32)
+                .saturating_sub(progress.sector2_time_in_ms as u32);
+            let completed = CompletedLap {
+                car_index,
+                lap_number: progress.lap_number,
+                lap_time_in_ms: lap.last_lap_time_in_ms,
+                sector1_time_in_ms: progress.sector1_time_in_ms,
+                sector2_time_in_ms: progress.sector2_time_in_ms,
+                sector3_time_in_ms: sector3_time_in_ms.min(u16::MAX as u32) as u16,
+                valid: !progress.invalid,
+                tyre_actual_compound: status.map_or(0, |status| status.actual_tyre_compound),
+                tyre_visual_compound: status.map_or(0, |status| status.visual_tyre_compound),
+                tyres_age_laps: status.map_or(0, |status| status.tyres_age_laps),
+                fuel_at_start: progress.fuel_at_start,
+                fuel_at_end: status.map(|status| status.fuel_in_tank),
+                position: lap.car_position,
+                pit_in: progress.pit_in,
+                pit_out: progress.pit_out,
+                session_time,
+                reconciled: false,
+            };
+            car.progress = Some(LapProgress::start(lap, status, true));
+            car.completed.push(completed.clone());
+            events.push(LapEvent::Completed(completed));
+        }
+        events
+    }
+
+    fn apply_session_history(&mut self, pkt: &PacketSessionHistoryData) -> Vec<LapEvent> {
+        let car = match self.cars.get_mut(pkt.car_idx as usize) {
+            Some(car) => car,
+            None => return Vec::new(),
+        };
+        let mut events = Vec::new();
+        for completed in car.completed.iter_mut() {
+            let history = match pkt.lap_history_data.get(completed.lap_number as usize - 1) {
+                Some(history) if history.lap_time_in_ms > 0 => history,
+                _ => continue,
+            };
+            let valid = history.lap_valid_bit_flags & 0x01 != 0;
+            let changed = completed.lap_time_in_ms != history.lap_time_in_ms
+                || completed.sector1_time_in_ms != history.sector1_time_in_ms
+                || completed.sector2_time_in_ms != history.sector2_time_in_ms
+                || completed.sector3_time_in_ms != history.sector3_time_in_ms
+                || completed.valid != valid;
+            completed.lap_time_in_ms = history.lap_time_in_ms;
+            completed.sector1_time_in_ms = history.sector1_time_in_ms;
+            completed.sector2_time_in_ms = history.sector2_time_in_ms;
+            completed.sector3_time_in_ms = history.sector3_time_in_ms;
+            completed.valid = valid;
+            completed.reconciled = true;
+            if changed {
+                events.push(LapEvent::Corrected(completed.clone()));
+            }
+        }
+        events
+    }
+}
+
+impl Rewind for LapTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        for car in self.cars.iter_mut() {
+            car.completed
+                .retain(|lap| !point.supersedes(point.session_uid, lap.session_time));
+            // Restarts tracking the lap from the next lap data
+            car.progress = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    fn lap_data(session_uid: u64, session_time: f32, lap: u8, last_lap_time_in_ms: u32) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        let data = &mut pkt.lap_data[0];
+        data.current_lap_num = lap;
+        data.last_lap_time_in_ms = last_lap_time_in_ms;
+        data.sector1_time_in_ms = 30_000;
+        data.sector2_time_in_ms = 31_000;
+        data.car_position = 1;
+        Packet::LapData(Box::new(pkt))
+    }
+
+    #[test]
+    fn first_packet_starts_tracking_mid_lap() {
+        let mut tracker = LapTracker::new();
+        assert!(tracker.apply(&lap_data(1, 10.0, 3, 0)).is_empty());
+        assert!(tracker.apply(&lap_data(1, 11.0, 3, 0)).is_empty());
+        assert!(tracker.laps(0).is_empty());
+    }
+
+    #[test]
+    fn lap_rollover_completes_the_lap() {
+        let mut tracker = LapTracker::new();
+        tracker.apply(&lap_data(1, 10.0, 1, 0));
+        let events = tracker.apply(&lap_data(1, 100.0, 2, 92_000));
+        let [LapEvent::Completed(lap)] = &events[..] else {
+            panic!("expected a completed lap, got {:?}", events);
+        };
+        assert_eq!(lap.lap_number, 1);
+        assert_eq!(lap.lap_time_in_ms, 92_000);
+        assert_eq!(lap.sector3_time_in_ms, 31_000);
+        assert!(lap.valid);
+        // The fuel is unknown for a lap whose start wasn't seen
+        assert_eq!(lap.fuel_at_start, None);
+        assert_eq!(tracker.best_lap(0).unwrap().lap_number, 1);
+    }
+
+    #[test]
+    fn flashback_drops_laps_after_the_point() {
+        let mut tracker = LapTracker::new();
+        tracker.apply(&lap_data(1, 10.0, 1, 0));
+        tracker.apply(&lap_data(1, 100.0, 2, 92_000));
+        tracker.apply(&testing::flashback(1, 1000, 100.0, 900, 90.0));
+        assert!(tracker.laps(0).is_empty());
+        // The lap is tracked again from the next lap data, without completing a lap
+        assert!(tracker.apply(&lap_data(1, 90.0, 1, 0)).is_empty());
+        assert_eq!(tracker.apply(&lap_data(1, 100.0, 2, 92_000)).len(), 1);
+    }
+
+    #[test]
+    fn session_change_resets_the_laps() {
+        let mut tracker = LapTracker::new();
+        tracker.apply(&lap_data(1, 10.0, 1, 0));
+        tracker.apply(&lap_data(1, 100.0, 2, 92_000));
+        assert!(tracker.apply(&lap_data(2, 100.0, 3, 92_000)).is_empty());
+        assert!(tracker.laps(0).is_empty());
+    }
+
+    #[test]
+    fn session_history_corrects_the_lap() {
+        let mut tracker = LapTracker::new();
+        tracker.apply(&lap_data(1, 10.0, 1, 0));
+        tracker.apply(&lap_data(1, 100.0, 2, 92_000));
+        let mut pkt: PacketSessionHistoryData =
+            testing::packet(PacketId::SessionHistory, 1, 0, 101.0);
+        let history = &mut pkt.lap_history_data[0];
+        history.lap_time_in_ms = 92_500;
+        history.sector1_time_in_ms = 30_000;
+        history.sector2_time_in_ms = 31_000;
+        history.sector3_time_in_ms = 31_500;
+        history.lap_valid_bit_flags = 0x0f;
+        let events = tracker.apply(&Packet::SessionHistory(Box::new(pkt)));
+        let [LapEvent::Corrected(lap)] = &events[..] else {
+            panic!("expected a corrected lap, got {:?}", events);
+        };
+        assert_eq!(lap.lap_time_in_ms, 92_500);
+        assert!(lap.reconciled);
+    }
+}
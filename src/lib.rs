@@ -1,153 +1,230 @@
 //! Functionality used to create connections + build the telemetry object
 //! which defines the data that you wish to record
-use binread::{self, io::Cursor, BinRead, BinReaderExt};
-use error_stack::{IntoReport, Report, Result, ResultExt};
+use binread::{self, io::Cursor, BinReaderExt};
+use packet::Packet;
 use serde::Serialize;
-use telemetry::{EventButtons, EventFastestLap, EventFlashback, PacketEventData};
-use tokio::{net::UdpSocket, sync::mpsc::UnboundedSender};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
-    errors::TelemetryError,
-    telemetry::{
-        EventDriveThroughPenaltyServed, EventPenalty, EventRaceWinner, EventRetirement,
-        EventSpeedTrap, EventStartLights, EventStopGoPenaltyServed, EventTeamMateInPits,
-        PacketCarDamageData, PacketCarSetupData, PacketCarStatusData, PacketCarTelemetryData,
-        PacketFinalClassificationData, PacketLapData, PacketLobbyInfoData, PacketMotionData,
-        PacketParticipantsData, PacketSessionData, PacketSessionHistoryData,
-    },
+    filter::{IpNetwork, SourceFilter},
+    sequence::{SequenceStats, SequenceTracker},
+    socket::{MulticastGroup, SocketOptions},
 };
 
-mod errors;
+pub mod compare;
+pub mod corners;
+pub mod delta;
+pub mod errors;
+pub mod ers;
+pub mod filter;
+pub mod flags;
+pub mod flashback;
+pub mod frame;
+pub mod fuel;
+pub mod incidents;
+mod json;
+pub mod laps;
+pub mod overtakes;
+pub mod packet;
+pub mod penalties;
+pub mod recording;
+pub mod resample;
+pub mod results;
+mod sampling;
+pub mod sequence;
+pub mod session;
+pub mod socket;
+pub mod state;
+mod stats;
+pub mod strategy;
 pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod timing;
+pub mod track;
+pub mod tyres;
+pub mod weather;
 
 const BUFFER_SIZE: usize = 10024;
-const UNSUPPORTED_EVENT: [&str; 6] = ["SSTA", "SEND", "DRSE", "DRSD", "CHQF", "LGOT"];
 
 /// Telemetry object. Used to record data from the F1 game and pass it through via channels.
 pub struct Telemetry {
-    endpoint: String,
+    endpoints: Vec<SocketAddr>,
+    socket_options: SocketOptions,
+    source_filter: SourceFilter,
+    sequence: Option<Arc<Mutex<SequenceTracker>>>,
     data: Vec<u8>,
 }
 
-/// Records the telemetry data.
-fn read_telemetry<T: BinRead + Serialize>(
-    buffer: [u8; BUFFER_SIZE],
-) -> error_stack::Result<String, TelemetryError> {
-    let mut reader = Cursor::new(buffer);
-    let tel: T = reader
-        .read_le::<T>()
-        .report()
-        .change_context_lazy(|| TelemetryError)?;
-    let data = serde_json::to_string(&tel)
-        .report()
-        .change_context_lazy(|| TelemetryError)?;
-    Ok(data)
+/// Identifies where a packet came from, so that data from several game instances can be told apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Origin {
+    pub endpoint: SocketAddr, // Local endpoint the packet was received on
+    pub source: SocketAddr,   // Address of the game instance that sent the packet
+    pub session_uid: u64,     // Session identifier taken from the packet header
 }
 
-/// Records the telemetry event data.
-fn read_event_telemetry(buffer: [u8; BUFFER_SIZE]) -> Result<Option<String>, TelemetryError> {
-    let mut reader = Cursor::new(buffer);
-    let pkt_hdr: PacketEventData<EventFlashback> = reader
-        .read_le()
-        .report()
-        .change_context_lazy(|| TelemetryError)?;
-    let event_type = chars_to_string(&pkt_hdr.event_string_code);
-    if UNSUPPORTED_EVENT.contains(&&event_type[..]) {
-        return Ok(None);
-    }
-
-    let tel: Option<String> = match &event_type[..] {
-        "SSTA" => None,
-        "SEND" => None,
-        "FTLP" => Some(read_telemetry::<PacketEventData<EventFastestLap>>(buffer)?),
-        "RTMT" => Some(read_telemetry::<PacketEventData<EventRetirement>>(buffer)?),
-        "DRSE" => None,
-        "DRSD" => None,
-        "TMPT" => Some(read_telemetry::<PacketEventData<EventTeamMateInPits>>(
-            buffer,
-        )?),
-        "CHQF" => None,
-        "RCWN" => Some(read_telemetry::<PacketEventData<EventRaceWinner>>(buffer)?),
-        "PENA" => Some(read_telemetry::<PacketEventData<EventPenalty>>(buffer)?),
-        "SPTP" => Some(read_telemetry::<PacketEventData<EventSpeedTrap>>(buffer)?),
-        "STLG" => Some(read_telemetry::<PacketEventData<EventStartLights>>(buffer)?),
-        "LGOT" => None,
-        "DTSV" => Some(read_telemetry::<
-            PacketEventData<EventDriveThroughPenaltyServed>,
-        >(buffer)?),
-        "SGSV" => Some(read_telemetry::<PacketEventData<EventStopGoPenaltyServed>>(
-            buffer,
-        )?),
-        "FLBK" => Some(read_telemetry::<PacketEventData<EventFlashback>>(buffer)?),
-        "BUTN" => Some(read_telemetry::<PacketEventData<EventButtons>>(buffer)?),
-        _ => None,
-    };
-
-    Ok(tel)
+/// Telemetry data tagged with the origin of the packet it was decoded from.
+#[derive(Debug, Serialize)]
+pub struct Tagged<T> {
+    pub origin: Origin,
+    pub data: T,
 }
 
 impl Telemetry {
-    /// Spawns an asynchronous task which is used to record the F1 game data. The data is then transmitted via channels.
+    /// Spawns an asynchronous task per endpoint which is used to record the F1 game data. The data is then transmitted via channels.
     pub async fn record(&mut self, tx: UnboundedSender<String>) {
-        tokio::spawn(Telemetry::transmitter(
-            tx,
-            self.endpoint.clone(),
-            self.data.clone(),
-        ));
+        self.spawn_transmitters(move |_, packet| {
+            if !is_recorded_as_json(&packet) {
+                return;
+            }
+            if let Ok(tel) = serde_json::to_string(&packet) {
+                let _ = tx.send(tel);
+            }
+        });
+    }
+
+    /// Same as [`Telemetry::record`], but every packet is tagged with its [`Origin`]. Use this when
+    /// recording from several game instances at once.
+    pub async fn record_tagged(&mut self, tx: UnboundedSender<Tagged<String>>) {
+        self.spawn_transmitters(move |origin, packet| {
+            if !is_recorded_as_json(&packet) {
+                return;
+            }
+            if let Ok(tel) = serde_json::to_string(&packet) {
+                let _ = tx.send(Tagged { origin, data: tel });
+            }
+        });
+    }
+
+    /// Same as [`Telemetry::record_tagged`], but the packets are passed through as typed [`Packet`]s
+    /// instead of JSON.
+    pub async fn record_packets(&mut self, tx: UnboundedSender<Tagged<Packet>>) {
+        self.spawn_transmitters(move |origin, packet| {
+            let _ = tx.send(Tagged {
+                origin,
+                data: packet,
+            });
+        });
     }
 
-    async fn transmitter(tx: UnboundedSender<String>, endpoint: String, data: Vec<u8>) {
-        let socket = UdpSocket::bind(&endpoint).await.unwrap();
+    /// Number of datagrams rejected because their source address isn't in the allowlist.
+    pub fn rejected_count(&self) -> u64 {
+        self.source_filter.rejected()
+    }
+
+    /// Packet loss and ordering statistics keyed by (session_uid, packet_id). Empty unless
+    /// sequence tracking was enabled on the builder.
+    pub fn sequence_stats(&self) -> HashMap<(u64, u8), SequenceStats> {
+        self.sequence
+            .as_ref()
+            .map(|sequence| sequence.lock().unwrap().all_stats())
+            .unwrap_or_default()
+    }
+
+    fn spawn_transmitters<F>(&self, sink: F)
+    where
+        F: Fn(Origin, Packet) + Clone + Send + 'static,
+    {
+        for endpoint in &self.endpoints {
+            tokio::spawn(Telemetry::transmitter(
+                sink.clone(),
+                *endpoint,
+                self.socket_options.clone(),
+                self.source_filter.clone(),
+                self.sequence.clone(),
+                self.data.clone(),
+            ));
+        }
+    }
+
+    async fn transmitter<F>(
+        sink: F,
+        endpoint: SocketAddr,
+        options: SocketOptions,
+        source_filter: SourceFilter,
+        sequence: Option<Arc<Mutex<SequenceTracker>>>,
+        data: Vec<u8>,
+    ) where
+        F: Fn(Origin, Packet),
+    {
+        let socket = match socket::bind(endpoint, &options) {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::error!("Failed to bind {}: {:?}", endpoint, err);
+                return;
+            }
+        };
         let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
         loop {
-            socket.recv(&mut buf).await.unwrap();
-            let mut reader = Cursor::new(buf);
-            let pkt_hdr: telemetry::PacketHeader = reader.read_le().unwrap();
+            let (len, source) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    // E.g. an ICMP port unreachable reported as connection refused
+                    log::warn!("Failed to receive on {}: {}", endpoint, err);
+                    continue;
+                }
+            };
+            if !source_filter.accept(source) {
+                continue;
+            }
+            let datagram = &buf[..len];
+            let mut reader = Cursor::new(datagram);
+            let pkt_hdr: telemetry::PacketHeader = match reader.read_le() {
+                Ok(pkt_hdr) => pkt_hdr,
+                Err(err) => {
+                    log::warn!("Invalid datagram of {} bytes from {}: {}", len, source, err);
+                    continue;
+                }
+            };
+            if let Some(sequence) = &sequence {
+                let mut sequence = sequence.lock().unwrap();
+                if pkt_hdr.packet_id == 3 {
+                    // Events aren't periodic, only flashbacks matter for ordering
+                    if let Some(flashback) = packet::read_flashback(datagram) {
+                        sequence.flashback(pkt_hdr.session_uid, flashback.flashback_session_time);
+                    }
+                } else if data.contains(&pkt_hdr.packet_id) && !sequence.accept(&pkt_hdr) {
+                    continue;
+                }
+            }
             if !data.contains(&pkt_hdr.packet_id) {
                 //Not interested in this packet_id
                 continue;
             }
-            let tel = match pkt_hdr.packet_id {
-                0 => read_telemetry::<PacketMotionData>(buf),
-                1 => read_telemetry::<PacketSessionData>(buf),
-                2 => read_telemetry::<PacketLapData>(buf),
-                3 => match read_event_telemetry(buf) {
-                    Ok(tel) => tel.ok_or_else(|| Report::new(TelemetryError)),
-                    Err(_) => continue,
-                },
-                4 => read_telemetry::<PacketParticipantsData>(buf),
-                5 => read_telemetry::<PacketCarSetupData>(buf),
-                6 => read_telemetry::<PacketCarTelemetryData>(buf),
-                7 => read_telemetry::<PacketCarStatusData>(buf),
-                8 => read_telemetry::<PacketFinalClassificationData>(buf),
-                9 => read_telemetry::<PacketLobbyInfoData>(buf),
-                10 => read_telemetry::<PacketCarDamageData>(buf),
-                11 => read_telemetry::<PacketSessionHistoryData>(buf),
+            let packet = match Packet::decode(datagram) {
+                Ok(Some(packet)) => packet,
                 _ => continue,
             };
-            let tel = match tel {
-                Ok(tel) => tel,
-                Err(_) => continue,
+            let origin = Origin {
+                endpoint,
+                source,
+                session_uid: pkt_hdr.session_uid,
             };
-            match tx.send(tel) {
-                Ok(_) => continue,
-                Err(_) => {
-                    continue;
-                }
-            }
+            sink(origin, packet);
         }
     }
 }
 
-fn chars_to_string(chars: &[char]) -> String {
-    let mut str = String::with_capacity(chars.len());
-    chars.iter().for_each(|c| str.push(*c));
-    str
+/// Whether the packet is passed on by the JSON recordings, see [`EventPacket::has_details`].
+fn is_recorded_as_json(packet: &Packet) -> bool {
+    match packet {
+        Packet::Event(event) => event.has_details(),
+        _ => true,
+    }
 }
 
 /// Telemetry object builder. Choose the data that you want to record.
 pub struct TelemetryBuilder {
-    endpoint: String,
+    endpoints: Vec<SocketAddr>,
+    socket_options: SocketOptions,
+    source_filter: SourceFilter,
+    sequence: Option<bool>,
     events_data: Option<u8>,
     car_status_data: Option<u8>,
     motion_data: Option<u8>,
@@ -162,9 +239,12 @@ pub struct TelemetryBuilder {
     session_history_data: Option<u8>,
 }
 impl TelemetryBuilder {
-    pub fn new(endpoint: String) -> Self {
+    pub fn new(endpoint: SocketAddr) -> Self {
         TelemetryBuilder {
-            endpoint,
+            endpoints: vec![endpoint],
+            socket_options: SocketOptions::default(),
+            source_filter: SourceFilter::default(),
+            sequence: None,
             events_data: None,
             car_status_data: None,
             motion_data: None,
@@ -180,6 +260,79 @@ impl TelemetryBuilder {
         }
     }
 
+    /// Listen on an additional endpoint, e.g. one per game instance when aggregating several rigs.
+    pub fn add_endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    /// Sets the socket receive buffer size (SO_RCVBUF) in bytes. The OS default can be too small
+    /// to keep up when all cars are sent at 60Hz.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Allows the address to be reused (SO_REUSEADDR).
+    pub fn reuse_address(mut self) -> Self {
+        self.socket_options.reuse_address = true;
+        self
+    }
+
+    /// Allows several sockets to bind to the same port (SO_REUSEPORT). Ignored where unsupported.
+    pub fn reuse_port(mut self) -> Self {
+        self.socket_options.reuse_port = true;
+        self
+    }
+
+    /// Receives packets sent by the game in broadcast mode (SO_BROADCAST).
+    pub fn receive_broadcast(mut self) -> Self {
+        self.socket_options.broadcast = true;
+        self
+    }
+
+    /// Joins an IPv4 multicast group on the given interface, 0.0.0.0 for any interface.
+    pub fn join_multicast_v4(mut self, group: Ipv4Addr, interface: Ipv4Addr) -> Self {
+        self.socket_options
+            .multicast_groups
+            .push(MulticastGroup::V4 { group, interface });
+        self
+    }
+
+    /// Joins an IPv6 multicast group on the interface with the given index, 0 for any interface.
+    pub fn join_multicast_v6(mut self, group: Ipv6Addr, interface: u32) -> Self {
+        self.socket_options
+            .multicast_groups
+            .push(MulticastGroup::V6 { group, interface });
+        self
+    }
+
+    /// Only accept datagrams from this address or subnet. Once any source is allowed, datagrams
+    /// from every other source are rejected.
+    pub fn allow_source(mut self, network: impl Into<IpNetwork>) -> Self {
+        self.source_filter.allow(network.into());
+        self
+    }
+
+    /// Log a warning for every rejected datagram.
+    pub fn log_rejected_sources(mut self) -> Self {
+        self.source_filter.log_rejected();
+        self
+    }
+
+    /// Track packet loss, duplicates and out of order packets using the frame identifier.
+    pub fn track_sequence(mut self) -> Self {
+        self.sequence = Some(self.sequence.unwrap_or(false));
+        self
+    }
+
+    /// Track the packet sequence and drop duplicate and out of order packets, so that time never
+    /// goes backwards except on a flashback.
+    pub fn drop_stale_packets(mut self) -> Self {
+        self.sequence = Some(true);
+        self
+    }
+
     pub fn add_events_data(mut self) -> Self {
         self.events_data = Some(3);
         self
@@ -241,7 +394,8 @@ impl TelemetryBuilder {
     }
 
     pub fn add_all_data(self) -> Self {
-        self.add_car_status_data()
+        self.add_events_data()
+            .add_car_status_data()
             .add_motion_data()
             .add_final_classification_data()
             .add_session_data()
@@ -257,7 +411,7 @@ impl TelemetryBuilder {
     fn as_array(&self) -> [Option<u8>; 12] {
         [
             self.events_data,
-            self.car_damage_data,
+            self.car_status_data,
             self.motion_data,
             self.final_classification_data,
             self.session_data,
@@ -274,8 +428,36 @@ impl TelemetryBuilder {
     pub fn build(self) -> Telemetry {
         let data = self.as_array().into_iter().flatten().collect();
         Telemetry {
-            endpoint: self.endpoint,
+            endpoints: self.endpoints,
+            socket_options: self.socket_options,
+            source_filter: self.source_filter,
+            sequence: self
+                .sequence
+                .map(|drop_stale| Arc::new(Mutex::new(SequenceTracker::new(drop_stale)))),
             data,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketId;
+
+    #[test]
+    fn all_data_subscribes_to_every_packet() {
+        let endpoint = "127.0.0.1:20777".parse().unwrap();
+        let mut data = TelemetryBuilder::new(endpoint).add_all_data().build().data;
+        data.sort_unstable();
+        assert_eq!(data, (0..12).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn car_status_data_subscribes_to_its_packet() {
+        let endpoint = "127.0.0.1:20777".parse().unwrap();
+        let telemetry = TelemetryBuilder::new(endpoint)
+            .add_car_status_data()
+            .build();
+        assert_eq!(telemetry.data, vec![PacketId::CarStatus as u8]);
+    }
+}
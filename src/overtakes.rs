@@ -0,0 +1,317 @@
+//! Overtakes and position changes derived from the race positions
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{LapData, PacketLapData, MAX_CARS},
+};
+
+/// Why a car got ahead of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PassKind {
+    OnTrack,    // Both cars were racing on track
+    PitStop,    // One of the cars was in the pit lane
+    Retirement, // The car which dropped behind retired, was disqualified or not classified
+}
+
+/// A car getting ahead of another car.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Pass {
+    pub kind: PassKind,
+    pub car_index: usize,        // Car which got ahead
+    pub passed_car_index: usize, // Car which dropped behind
+    pub position: u8,            // Position of the car which got ahead after the pass
+    pub lap_number: u8,          // Lap of the car which got ahead
+    pub lap_distance: f32,       // Lap distance of the car which got ahead
+    pub drs: bool,               // DRS of the car which got ahead was open
+    pub session_time: f32,
+}
+
+/// Race position of a car changing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PositionChange {
+    pub car_index: usize,
+    pub from: u8,
+    pub to: u8,
+    pub lap_number: u8,
+    pub session_time: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PositionEvent {
+    Changed(PositionChange),
+    Pass(Pass),
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarPosition {
+    lap: Option<LapData>,
+    drs: bool,
+}
+
+impl CarPosition {
+    fn position(&self) -> Option<u8> {
+        self.lap
+            .as_ref()
+            .map(|lap| lap.car_position)
+            .filter(|position| *position > 0)
+    }
+
+    fn in_pits(&self) -> bool {
+        self.lap.as_ref().is_some_and(|lap| lap.pit_status != 0)
+    }
+}
+
+/// Derives overtakes from the race positions in the lap data, which the game doesn't send events
+/// for. Each pair of cars swapping order is reported as a [`Pass`], told apart from positions
+/// changing because a car was in the pit lane or retired, with where on the lap it happened and
+/// whether the DRS of the car which got ahead was open. The DRS is only known with the car
+/// telemetry packets. Meant for races, positions in other sessions follow the lap times.
+#[derive(Debug, Clone)]
+pub struct OvertakeDetector {
+    session_uid: u64,
+    cars: Vec<CarPosition>,
+    passes: Vec<Pass>,
+}
+
+impl Default for OvertakeDetector {
+    fn default() -> Self {
+        OvertakeDetector {
+            session_uid: 0,
+            cars: vec![CarPosition::default(); MAX_CARS],
+            passes: Vec::new(),
+        }
+    }
+}
+
+impl OvertakeDetector {
+    pub fn new() -> Self {
+        OvertakeDetector::default()
+    }
+
+    /// Passes this session, oldest first.
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// On track passes the car made this session.
+    pub fn overtakes(&self, car_index: usize) -> impl Iterator<Item = &Pass> {
+        self.passes
+            .iter()
+            .filter(move |pass| pass.kind == PassKind::OnTrack && pass.car_index == car_index)
+    }
+
+    /// Updates the detector with the packet, returning the position changes and passes.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<PositionEvent> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = OvertakeDetector::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::LapData(pkt) => self.apply_lap_data(pkt),
+            Packet::CarTelemetry(pkt) => {
+                self.cars
+                    .iter_mut()
+                    .zip(pkt.car_telemetry_data.iter())
+                    .for_each(|(car, telemetry)| car.drs = telemetry.drs == 1);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<PositionEvent> {
+        let session_time = pkt.header.session_time;
+        let old: Vec<CarPosition> = self.cars.clone();
+        for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+            car.lap = Some(lap.clone());
+        }
+
+        let mut events = Vec::new();
+        for (car_index, (old, new)) in old.iter().zip(self.cars.iter()).enumerate() {
+            let (from, to) = match (old.position(), new.position()) {
+                (Some(from), Some(to)) if from != to => (from, to),
+                _ => continue,
+            };
+            let lap = &pkt.lap_data[car_index];
+            events.push(PositionEvent::Changed(PositionChange {
+                car_index,
+                from,
+                to,
+                lap_number: lap.current_lap_num,
+                session_time,
+            }));
+        }
+
+        // A pass is a car which was behind another now being ahead of it
+        for (car_index, (old_car, car)) in old.iter().zip(self.cars.iter()).enumerate() {
+            let (from, to) = match (old_car.position(), car.position()) {
+                (Some(from), Some(to)) if to < from => (from, to),
+                _ => continue,
+            };
+            let passed = old.iter().zip(self.cars.iter()).enumerate().filter(
+                |(_, (old_other, other))| match (old_other.position(), other.position()) {
+                    (Some(other_from), Some(other_to)) => other_from < from && other_to > to,
+                    _ => false,
+                },
+            );
+            for (passed_car_index, (old_other, other)) in passed {
+                let lap = &pkt.lap_data[car_index];
+                let kind = if other.lap.as_ref().is_some_and(|lap| lap.result_status >= 4) {
+                    PassKind::Retirement
+                } else if old_car.in_pits()
+                    || car.in_pits()
+                    || old_other.in_pits()
+                    || other.in_pits()
+                {
+                    PassKind::PitStop
+                } else {
+                    PassKind::OnTrack
+                };
+                let pass = Pass {
+                    kind,
+                    car_index,
+                    passed_car_index,
+                    position: to,
+                    lap_number: lap.current_lap_num,
+                    lap_distance: lap.lap_distance,
+                    drs: car.drs,
+                    session_time,
+                };
+                self.passes.push(pass.clone());
+                events.push(PositionEvent::Pass(pass));
+            }
+        }
+        events
+    }
+}
+
+impl Rewind for OvertakeDetector {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.passes
+            .retain(|pass| !point.supersedes(point.session_uid, pass.session_time));
+        // Positions jump back, that isn't a pass
+        for car in self.cars.iter_mut() {
+            car.lap = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketCarTelemetryData, testing};
+
+    /// Lap data with the positions of cars 0, 1 and 2, car 1 with the pit status.
+    fn positions(
+        detector: &mut OvertakeDetector,
+        session_uid: u64,
+        session_time: f32,
+        positions: [u8; 3],
+        pit_status: u8,
+    ) -> Vec<PositionEvent> {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        for (lap, position) in pkt.lap_data.iter_mut().zip(positions) {
+            lap.car_position = position;
+            lap.current_lap_num = 5;
+            lap.lap_distance = 1200.0;
+            lap.result_status = 2;
+        }
+        pkt.lap_data[1].pit_status = pit_status;
+        detector.apply(&Packet::LapData(Box::new(pkt)))
+    }
+
+    fn passes(events: &[PositionEvent]) -> Vec<&Pass> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                PositionEvent::Pass(pass) => Some(pass),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_lap_data_has_no_changes() {
+        let mut detector = OvertakeDetector::new();
+        assert!(positions(&mut detector, 1, 1.0, [1, 2, 3], 0).is_empty());
+        assert!(positions(&mut detector, 1, 1.1, [1, 2, 3], 0).is_empty());
+    }
+
+    #[test]
+    fn swap_on_track_is_an_overtake() {
+        let mut detector = OvertakeDetector::new();
+        positions(&mut detector, 1, 1.0, [1, 2, 3], 0);
+        let mut telemetry: PacketCarTelemetryData =
+            testing::packet(PacketId::CarTelemetry, 1, 0, 1.05);
+        telemetry.car_telemetry_data[2].drs = 1;
+        detector.apply(&Packet::CarTelemetry(Box::new(telemetry)));
+        let events = positions(&mut detector, 1, 1.1, [1, 3, 2], 0);
+        assert_eq!(
+            events[..2],
+            [
+                PositionEvent::Changed(PositionChange {
+                    car_index: 1,
+                    from: 2,
+                    to: 3,
+                    lap_number: 5,
+                    session_time: 1.1,
+                }),
+                PositionEvent::Changed(PositionChange {
+                    car_index: 2,
+                    from: 3,
+                    to: 2,
+                    lap_number: 5,
+                    session_time: 1.1,
+                }),
+            ]
+        );
+        let passes = passes(&events);
+        assert_eq!(passes.len(), 1);
+        assert_eq!(passes[0].kind, PassKind::OnTrack);
+        assert_eq!((passes[0].car_index, passes[0].passed_car_index), (2, 1));
+        assert_eq!((passes[0].position, passes[0].lap_distance), (2, 1200.0));
+        assert!(passes[0].drs);
+        assert_eq!(detector.overtakes(2).count(), 1);
+    }
+
+    #[test]
+    fn pit_stop_loses_places_without_overtakes() {
+        let mut detector = OvertakeDetector::new();
+        positions(&mut detector, 1, 1.0, [1, 2, 3], 1);
+        let events = positions(&mut detector, 1, 1.1, [1, 3, 2], 1);
+        let passes = passes(&events);
+        assert_eq!(passes[0].kind, PassKind::PitStop);
+        assert_eq!(detector.overtakes(2).count(), 0);
+    }
+
+    #[test]
+    fn flashback_drops_the_later_passes_and_isnt_a_pass() {
+        let mut detector = OvertakeDetector::new();
+        positions(&mut detector, 1, 1.0, [1, 2, 3], 0);
+        positions(&mut detector, 1, 2.0, [1, 3, 2], 0);
+        assert_eq!(detector.passes().len(), 1);
+        detector.apply(&testing::flashback(1, 0, 2.1, 0, 1.5));
+        assert!(detector.passes().is_empty());
+        // Positions jump back to before the pass
+        assert!(positions(&mut detector, 1, 1.6, [1, 2, 3], 0).is_empty());
+    }
+
+    #[test]
+    fn session_change_resets_the_positions() {
+        let mut detector = OvertakeDetector::new();
+        positions(&mut detector, 1, 1.0, [1, 2, 3], 0);
+        positions(&mut detector, 1, 2.0, [1, 3, 2], 0);
+        assert!(positions(&mut detector, 2, 0.5, [3, 2, 1], 0).is_empty());
+        assert!(detector.passes().is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+//! Typed representation of the packets sent by the game
+use binread::{self, io::Cursor, BinRead, BinReaderExt};
+use error_stack::{IntoReport, Result, ResultExt};
+use serde::Serialize;
+
+use crate::{
+    errors::TelemetryError,
+    telemetry::{
+        EventButtons, EventDriveThroughPenaltyServed, EventFastestLap, EventFlashback,
+        EventNoDetails, EventPenalty, EventRaceWinner, EventRetirement, EventSpeedTrap,
+        EventStartLights, EventStopGoPenaltyServed, EventTeamMateInPits, PacketCarDamageData,
+        PacketCarSetupData, PacketCarStatusData, PacketCarTelemetryData, PacketEventData,
+        PacketFinalClassificationData, PacketHeader, PacketLapData, PacketLobbyInfoData,
+        PacketMotionData, PacketParticipantsData, PacketSessionData, PacketSessionHistoryData,
+    },
+};
+
+/// Packet ids as found in `PacketHeader::packet_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[repr(u8)]
+pub enum PacketId {
+    Motion = 0,
+    Session = 1,
+    LapData = 2,
+    Event = 3,
+    Participants = 4,
+    CarSetups = 5,
+    CarTelemetry = 6,
+    CarStatus = 7,
+    FinalClassification = 8,
+    LobbyInfo = 9,
+    CarDamage = 10,
+    SessionHistory = 11,
+}
+
+impl PacketId {
+    pub fn from_u8(id: u8) -> Option<PacketId> {
+        let id = match id {
+            0 => PacketId::Motion,
+            1 => PacketId::Session,
+            2 => PacketId::LapData,
+            3 => PacketId::Event,
+            4 => PacketId::Participants,
+            5 => PacketId::CarSetups,
+            6 => PacketId::CarTelemetry,
+            7 => PacketId::CarStatus,
+            8 => PacketId::FinalClassification,
+            9 => PacketId::LobbyInfo,
+            10 => PacketId::CarDamage,
+            11 => PacketId::SessionHistory,
+            _ => return None,
+        };
+        Some(id)
+    }
+}
+
+/// A decoded event packet. Serializes to the same JSON as the wrapped packet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum EventPacket {
+    SessionStarted(PacketEventData<EventNoDetails>),
+    SessionEnded(PacketEventData<EventNoDetails>),
+    FastestLap(PacketEventData<EventFastestLap>),
+    Retirement(PacketEventData<EventRetirement>),
+    DrsEnabled(PacketEventData<EventNoDetails>),
+    DrsDisabled(PacketEventData<EventNoDetails>),
+    TeamMateInPits(PacketEventData<EventTeamMateInPits>),
+    ChequeredFlag(PacketEventData<EventNoDetails>),
+    RaceWinner(PacketEventData<EventRaceWinner>),
+    Penalty(PacketEventData<EventPenalty>),
+    SpeedTrap(PacketEventData<EventSpeedTrap>),
+    StartLights(PacketEventData<EventStartLights>),
+    LightsOut(PacketEventData<EventNoDetails>),
+    DriveThroughPenaltyServed(PacketEventData<EventDriveThroughPenaltyServed>),
+    StopGoPenaltyServed(PacketEventData<EventStopGoPenaltyServed>),
+    Flashback(PacketEventData<EventFlashback>),
+    Buttons(PacketEventData<EventButtons>),
+}
+
+impl EventPacket {
+    /// Whether the event carries details. The JSON recording has never included the events
+    /// without them (SSTA, SEND, DRSE, DRSD, CHQF and LGOT), they are only passed as typed packets.
+    pub(crate) fn has_details(&self) -> bool {
+        !matches!(
+            self,
+            EventPacket::SessionStarted(_)
+                | EventPacket::SessionEnded(_)
+                | EventPacket::DrsEnabled(_)
+                | EventPacket::DrsDisabled(_)
+                | EventPacket::ChequeredFlag(_)
+                | EventPacket::LightsOut(_)
+        )
+    }
+
+    pub fn header(&self) -> &PacketHeader {
+        match self {
+            EventPacket::SessionStarted(pkt) => &pkt.header,
+            EventPacket::SessionEnded(pkt) => &pkt.header,
+            EventPacket::FastestLap(pkt) => &pkt.header,
+            EventPacket::Retirement(pkt) => &pkt.header,
+            EventPacket::DrsEnabled(pkt) => &pkt.header,
+            EventPacket::DrsDisabled(pkt) => &pkt.header,
+            EventPacket::TeamMateInPits(pkt) => &pkt.header,
+            EventPacket::ChequeredFlag(pkt) => &pkt.header,
+            EventPacket::RaceWinner(pkt) => &pkt.header,
+            EventPacket::Penalty(pkt) => &pkt.header,
+            EventPacket::SpeedTrap(pkt) => &pkt.header,
+            EventPacket::StartLights(pkt) => &pkt.header,
+            EventPacket::LightsOut(pkt) => &pkt.header,
+            EventPacket::DriveThroughPenaltyServed(pkt) => &pkt.header,
+            EventPacket::StopGoPenaltyServed(pkt) => &pkt.header,
+            EventPacket::Flashback(pkt) => &pkt.header,
+            EventPacket::Buttons(pkt) => &pkt.header,
+        }
+    }
+}
+
+/// A decoded packet. Serializes to the same JSON as the wrapped packet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Packet {
+    Motion(Box<PacketMotionData>),
+    Session(Box<PacketSessionData>),
+    LapData(Box<PacketLapData>),
+    Event(EventPacket),
+    Participants(Box<PacketParticipantsData>),
+    CarSetups(Box<PacketCarSetupData>),
+    CarTelemetry(Box<PacketCarTelemetryData>),
+    CarStatus(Box<PacketCarStatusData>),
+    FinalClassification(Box<PacketFinalClassificationData>),
+    LobbyInfo(Box<PacketLobbyInfoData>),
+    CarDamage(Box<PacketCarDamageData>),
+    SessionHistory(Box<PacketSessionHistoryData>),
+}
+
+impl Packet {
+    /// Decodes a datagram. Returns None for packet ids and events which aren't supported.
+    pub fn decode(buffer: &[u8]) -> Result<Option<Packet>, TelemetryError> {
+        let header: PacketHeader = read(buffer)?;
+        let packet = match header.packet_id {
+            0 => Packet::Motion(Box::new(read(buffer)?)),
+            1 => Packet::Session(Box::new(read(buffer)?)),
+            2 => Packet::LapData(Box::new(read(buffer)?)),
+            3 => match read_event(buffer)? {
+                Some(event) => Packet::Event(event),
+                None => return Ok(None),
+            },
+            4 => Packet::Participants(Box::new(read(buffer)?)),
+            5 => Packet::CarSetups(Box::new(read(buffer)?)),
+            6 => Packet::CarTelemetry(Box::new(read(buffer)?)),
+            7 => Packet::CarStatus(Box::new(read(buffer)?)),
+            8 => Packet::FinalClassification(Box::new(read(buffer)?)),
+            9 => Packet::LobbyInfo(Box::new(read(buffer)?)),
+            10 => Packet::CarDamage(Box::new(read(buffer)?)),
+            11 => Packet::SessionHistory(Box::new(read(buffer)?)),
+            _ => return Ok(None),
+        };
+        Ok(Some(packet))
+    }
+
+    pub fn header(&self) -> &PacketHeader {
+        match self {
+            Packet::Motion(pkt) => &pkt.header,
+            Packet::Session(pkt) => &pkt.header,
+            Packet::LapData(pkt) => &pkt.header,
+            Packet::Event(pkt) => pkt.header(),
+            Packet::Participants(pkt) => &pkt.header,
+            Packet::CarSetups(pkt) => &pkt.header,
+            Packet::CarTelemetry(pkt) => &pkt.header,
+            Packet::CarStatus(pkt) => &pkt.header,
+            Packet::FinalClassification(pkt) => &pkt.header,
+            Packet::LobbyInfo(pkt) => &pkt.header,
+            Packet::CarDamage(pkt) => &pkt.header,
+            Packet::SessionHistory(pkt) => &pkt.header,
+        }
+    }
+
+    pub fn id(&self) -> PacketId {
+        match self {
+            Packet::Motion(_) => PacketId::Motion,
+            Packet::Session(_) => PacketId::Session,
+            Packet::LapData(_) => PacketId::LapData,
+            Packet::Event(_) => PacketId::Event,
+            Packet::Participants(_) => PacketId::Participants,
+            Packet::CarSetups(_) => PacketId::CarSetups,
+            Packet::CarTelemetry(_) => PacketId::CarTelemetry,
+            Packet::CarStatus(_) => PacketId::CarStatus,
+            Packet::FinalClassification(_) => PacketId::FinalClassification,
+            Packet::LobbyInfo(_) => PacketId::LobbyInfo,
+            Packet::CarDamage(_) => PacketId::CarDamage,
+            Packet::SessionHistory(_) => PacketId::SessionHistory,
+        }
+    }
+}
+
+/// Reads a packet from the datagram.
+fn read<T: BinRead<Args = ()>>(buffer: &[u8]) -> Result<T, TelemetryError> {
+    let mut reader = Cursor::new(buffer);
+    reader
+        .read_le::<T>()
+        .report()
+        .change_context_lazy(|| TelemetryError)
+}
+
+/// Reads the event packet, the event string code decides which event it holds.
+fn read_event(buffer: &[u8]) -> Result<Option<EventPacket>, TelemetryError> {
+    let pkt: PacketEventData<EventFlashback> = read(buffer)?;
+    let event = match &chars_to_string(&pkt.event_string_code)[..] {
+        "SSTA" => EventPacket::SessionStarted(read(buffer)?),
+        "SEND" => EventPacket::SessionEnded(read(buffer)?),
+        "FTLP" => EventPacket::FastestLap(read(buffer)?),
+        "RTMT" => EventPacket::Retirement(read(buffer)?),
+        "DRSE" => EventPacket::DrsEnabled(read(buffer)?),
+        "DRSD" => EventPacket::DrsDisabled(read(buffer)?),
+        "TMPT" => EventPacket::TeamMateInPits(read(buffer)?),
+        "CHQF" => EventPacket::ChequeredFlag(read(buffer)?),
+        "RCWN" => EventPacket::RaceWinner(read(buffer)?),
+        "PENA" => EventPacket::Penalty(read(buffer)?),
+        "SPTP" => EventPacket::SpeedTrap(read(buffer)?),
+        "STLG" => EventPacket::StartLights(read(buffer)?),
+        "LGOT" => EventPacket::LightsOut(read(buffer)?),
+        "DTSV" => EventPacket::DriveThroughPenaltyServed(read(buffer)?),
+        "SGSV" => EventPacket::StopGoPenaltyServed(read(buffer)?),
+        "FLBK" => EventPacket::Flashback(pkt),
+        "BUTN" => EventPacket::Buttons(read(buffer)?),
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
+
+/// Returns the flashback event if the datagram is one.
+pub(crate) fn read_flashback(buffer: &[u8]) -> Option<EventFlashback> {
+    let pkt: PacketEventData<EventFlashback> = read(buffer).ok()?;
+    if pkt.header.packet_id != PacketId::Event as u8
+        || chars_to_string(&pkt.event_string_code) != "FLBK"
+    {
+        return None;
+    }
+    Some(pkt.event)
+}
+
+pub(crate) fn chars_to_string(chars: &[char]) -> String {
+    let mut str = String::with_capacity(chars.len());
+    chars.iter().for_each(|c| str.push(*c));
+    str
+}
+
+/// Name from a null terminated name field, e.g. `ParticipantData::name`.
+pub(crate) fn name_to_string(chars: &[char]) -> String {
+    chars.iter().take_while(|c| **c != '\0').collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn events_without_details_are_decoded() {
+        for code in ["SSTA", "SEND", "DRSE", "DRSD", "CHQF", "LGOT"] {
+            assert!(!testing::event(code, &[], 1, 10, 1.0).has_details());
+        }
+        assert!(testing::event("FTLP", &[], 1, 10, 1.0).has_details());
+    }
+
+    #[test]
+    fn reads_the_flashback() {
+        let Packet::Event(event) = testing::flashback(1, 100, 10.0, 40, 4.0) else {
+            panic!("not an event");
+        };
+        assert!(matches!(event, EventPacket::Flashback(_)));
+        let mut buffer = vec![0u8; 36];
+        buffer[5] = PacketId::Event as u8;
+        buffer[24..28].copy_from_slice(b"FLBK");
+        buffer[28..32].copy_from_slice(&40u32.to_le_bytes());
+        let flashback = read_flashback(&buffer).unwrap();
+        assert_eq!(flashback.flashback_frame_identifier, 40);
+        assert!(read_flashback(&buffer[..30]).is_none());
+    }
+}
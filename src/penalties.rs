@@ -0,0 +1,534 @@
+//! Ledger of the warnings and penalties of every driver
+use std::{fmt::Write as _, path::Path};
+
+use error_stack::Result;
+use serde::Serialize;
+
+use crate::{
+    errors::TelemetryError,
+    flashback::{FlashbackPoint, Rewind},
+    json::save_json,
+    packet::{name_to_string, EventPacket, Packet},
+    telemetry::{EventPenalty, LapData, MAX_CARS},
+};
+
+/// Penalty types as found in `EventPenalty::penalty_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PenaltyType {
+    DriveThrough = 0,
+    StopGo = 1,
+    GridPenalty = 2,
+    PenaltyReminder = 3,
+    TimePenalty = 4,
+    Warning = 5,
+    Disqualified = 6,
+    RemovedFromFormationLap = 7,
+    ParkedTooLongTimer = 8,
+    TyreRegulations = 9,
+    ThisLapInvalidated = 10,
+    ThisAndNextLapInvalidated = 11,
+    ThisLapInvalidatedWithoutReason = 12,
+    ThisAndNextLapInvalidatedWithoutReason = 13,
+    ThisAndPreviousLapInvalidated = 14,
+    ThisAndPreviousLapInvalidatedWithoutReason = 15,
+    Retired = 16,
+    BlackFlagTimer = 17,
+}
+
+impl PenaltyType {
+    pub fn from_u8(penalty_type: u8) -> Option<PenaltyType> {
+        let penalty_type = match penalty_type {
+            0 => PenaltyType::DriveThrough,
+            1 => PenaltyType::StopGo,
+            2 => PenaltyType::GridPenalty,
+            3 => PenaltyType::PenaltyReminder,
+            4 => PenaltyType::TimePenalty,
+            5 => PenaltyType::Warning,
+            6 => PenaltyType::Disqualified,
+            7 => PenaltyType::RemovedFromFormationLap,
+            8 => PenaltyType::ParkedTooLongTimer,
+            9 => PenaltyType::TyreRegulations,
+            10 => PenaltyType::ThisLapInvalidated,
+            11 => PenaltyType::ThisAndNextLapInvalidated,
+            12 => PenaltyType::ThisLapInvalidatedWithoutReason,
+            13 => PenaltyType::ThisAndNextLapInvalidatedWithoutReason,
+            14 => PenaltyType::ThisAndPreviousLapInvalidated,
+            15 => PenaltyType::ThisAndPreviousLapInvalidatedWithoutReason,
+            16 => PenaltyType::Retired,
+            17 => PenaltyType::BlackFlagTimer,
+            _ => return None,
+        };
+        Some(penalty_type)
+    }
+}
+
+/// Infringement types as found in `EventPenalty::infringement_type`, by their number.
+const INFRINGEMENTS: [&str; 55] = [
+    "Blocking by slow driving",
+    "Blocking by wrong way driving",
+    "Reversing off the start line",
+    "Big collision",
+    "Small collision",
+    "Collision failed to hand back position single",
+    "Collision failed to hand back position multiple",
+    "Corner cutting gained time",
+    "Corner cutting overtake single",
+    "Corner cutting overtake multiple",
+    "Crossed pit exit lane",
+    "Ignoring blue flags",
+    "Ignoring yellow flags",
+    "Ignoring drive through",
+    "Too many drive throughs",
+    "Drive through reminder serve within n laps",
+    "Drive through reminder serve this lap",
+    "Pit lane speeding",
+    "Parked for too long",
+    "Ignoring tyre regulations",
+    "Too many penalties",
+    "Multiple warnings",
+    "Approaching disqualification",
+    "Tyre regulations select single",
+    "Tyre regulations select multiple",
+    "Lap invalidated corner cutting",
+    "Lap invalidated running wide",
+    "Corner cutting ran wide gained time minor",
+    "Corner cutting ran wide gained time significant",
+    "Corner cutting ran wide gained time extreme",
+    "Lap invalidated wall riding",
+    "Lap invalidated flashback used",
+    "Lap invalidated reset to track",
+    "Blocking the pitlane",
+    "Jump start",
+    "Safety car to car collision",
+    "Safety car illegal overtake",
+    "Safety car exceeding allowed pace",
+    "Virtual safety car exceeding allowed pace",
+    "Formation lap below allowed speed",
+    "Formation lap parking",
+    "Retired mechanical failure",
+    "Retired terminally damaged",
+    "Safety car falling too far back",
+    "Black flag timer",
+    "Unserved stop go penalty",
+    "Unserved drive through penalty",
+    "Engine component change",
+    "Gearbox change",
+    "Parc fermé change",
+    "League grid penalty",
+    "Retry penalty",
+    "Illegal time gain",
+    "Mandatory pitstop",
+    "Attribute assigned",
+];
+
+/// Infringement types which are corner cutting.
+const CORNER_CUTTING: [u8; 7] = [7, 8, 9, 25, 27, 28, 29];
+
+/// Name of the infringement type, e.g. "Pit lane speeding".
+pub fn infringement_name(infringement_type: u8) -> &'static str {
+    INFRINGEMENTS
+        .get(infringement_type as usize)
+        .copied()
+        .unwrap_or("Unknown infringement")
+}
+
+/// A penalty given by the stewards.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PenaltyRecord {
+    pub penalty_type: u8,               // See `PenaltyType`
+    pub infringement_type: u8,          // See `infringement_name`
+    pub other_car_index: Option<usize>, // The other car involved, if any
+    pub time: u8,                       // Time gained, or time spent doing action in seconds
+    pub lap_number: u8,                 // Lap the penalty occurred on
+    pub places_gained: u8,              // Number of places gained by this
+}
+
+impl PenaltyRecord {
+    fn from_event(event: &EventPenalty) -> Self {
+        PenaltyRecord {
+            penalty_type: event.penalty_type,
+            infringement_type: event.infringement_type,
+            other_car_index: Some(event.other_vehicle_idx as usize)
+                .filter(|idx| *idx < MAX_CARS && *idx != event.vehicle_idx as usize),
+            time: event.time,
+            lap_number: event.lap_num,
+            places_gained: event.places_gained,
+        }
+    }
+
+    pub fn penalty(&self) -> Option<PenaltyType> {
+        PenaltyType::from_u8(self.penalty_type)
+    }
+
+    pub fn is_corner_cutting(&self) -> bool {
+        CORNER_CUTTING.contains(&self.infringement_type)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum LedgerEntryKind {
+    Penalty(PenaltyRecord),
+    DriveThroughServed,
+    StopGoServed,
+}
+
+/// Something the stewards did to a car.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+    pub car_index: usize,
+    pub session_time: f32,
+    pub kind: LedgerEntryKind,
+}
+
+/// Warnings and penalties of a driver in the session.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DriverPenalties {
+    pub car_index: usize,
+    pub name: String,        // Empty until the participants packet arrived
+    pub warnings: u8,        // Warnings as counted by the game
+    pub time_penalties: u8,  // Seconds of time penalties to be added
+    pub corner_cutting: u32, // Corner cutting infringements, warned, penalised or invalidating the lap
+    pub drive_throughs: u32, // Drive through penalties given
+    pub drive_throughs_served: u32,
+    pub drive_throughs_unserved: u8,
+    pub stop_gos: u32, // Stop go penalties given
+    pub stop_gos_served: u32,
+    pub stop_gos_unserved: u8,
+    pub grid_penalties: u32,
+    pub disqualified: bool,
+    pub entries: Vec<LedgerEntry>, // Oldest first
+}
+
+/// Warnings and penalties of every driver with at least one, by car index.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StewardingReport {
+    pub session_uid: u64,
+    pub drivers: Vec<DriverPenalties>,
+}
+
+impl StewardingReport {
+    /// The report as text, a section per driver listing the totals and every decision.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Stewarding report for session {}\n", self.session_uid);
+        for driver in self.drivers.iter() {
+            let name = if driver.name.is_empty() {
+                format!("Car {}", driver.car_index)
+            } else {
+                driver.name.clone()
+            };
+            let _ = writeln!(text, "\n{}", name);
+            let _ = writeln!(
+                text,
+                "  Warnings: {}, corner cutting: {}, time penalties: {}s",
+                driver.warnings, driver.corner_cutting, driver.time_penalties
+            );
+            let _ = writeln!(
+                text,
+                "  Drive throughs: {} ({} served, {} unserved), stop go: {} ({} served, {} unserved)",
+                driver.drive_throughs,
+                driver.drive_throughs_served,
+                driver.drive_throughs_unserved,
+                driver.stop_gos,
+                driver.stop_gos_served,
+                driver.stop_gos_unserved
+            );
+            if driver.disqualified {
+                let _ = writeln!(text, "  Disqualified");
+            }
+            for entry in driver.entries.iter() {
+                let decision = match &entry.kind {
+                    LedgerEntryKind::Penalty(penalty) => match penalty.penalty() {
+                        Some(penalty_type) => format!(
+                            "Lap {}: {:?} for {}",
+                            penalty.lap_number,
+                            penalty_type,
+                            infringement_name(penalty.infringement_type)
+                        ),
+                        None => format!(
+                            "Lap {}: Penalty type {} for {}",
+                            penalty.lap_number,
+                            penalty.penalty_type,
+                            infringement_name(penalty.infringement_type)
+                        ),
+                    },
+                    LedgerEntryKind::DriveThroughServed => String::from("Drive through served"),
+                    LedgerEntryKind::StopGoServed => String::from("Stop go served"),
+                };
+                let _ = writeln!(text, "  {:>9.3}s {}", entry.session_time, decision);
+            }
+        }
+        text
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PenaltyEvent {
+    Recorded(LedgerEntry),
+    SessionEnded(StewardingReport), // Final report, on the session ended event
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarPenalties {
+    name: String,
+    lap: Option<LapData>,
+}
+
+/// Keeps a ledger of the penalty events and penalty served events of every driver, combined with
+/// the warnings, time penalties and unserved penalties the lap data counts. The game counts
+/// warnings and time penalties itself, those are taken from the lap data rather than the events.
+#[derive(Debug, Clone)]
+pub struct PenaltyTracker {
+    session_uid: u64,
+    cars: Vec<CarPenalties>,
+    entries: Vec<LedgerEntry>,
+}
+
+impl Default for PenaltyTracker {
+    fn default() -> Self {
+        PenaltyTracker {
+            session_uid: 0,
+            cars: vec![CarPenalties::default(); MAX_CARS],
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl PenaltyTracker {
+    pub fn new() -> Self {
+        PenaltyTracker::default()
+    }
+
+    /// Every entry of the ledger, oldest first.
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// Warnings and penalties of the driver so far.
+    pub fn driver(&self, car_index: usize) -> Option<DriverPenalties> {
+        let car = self.cars.get(car_index)?;
+        let mut driver = DriverPenalties {
+            car_index,
+            name: car.name.clone(),
+            ..Default::default()
+        };
+        if let Some(lap) = &car.lap {
+            driver.warnings = lap.warnings;
+            driver.time_penalties = lap.penalties;
+            driver.drive_throughs_unserved = lap.num_unserved_drive_through_pens;
+            driver.stop_gos_unserved = lap.num_unserved_stop_go_pens;
+            driver.disqualified = lap.result_status == 5;
+        }
+        for entry in self.entries.iter().filter(|e| e.car_index == car_index) {
+            match &entry.kind {
+                LedgerEntryKind::Penalty(penalty) => {
+                    if penalty.is_corner_cutting() {
+                        driver.corner_cutting += 1;
+                    }
+                    match penalty.penalty() {
+                        Some(PenaltyType::DriveThrough) => driver.drive_throughs += 1,
+                        Some(PenaltyType::StopGo) => driver.stop_gos += 1,
+                        Some(PenaltyType::GridPenalty) => driver.grid_penalties += 1,
+                        Some(PenaltyType::Disqualified) => driver.disqualified = true,
+                        _ => {}
+                    }
+                }
+                LedgerEntryKind::DriveThroughServed => driver.drive_throughs_served += 1,
+                LedgerEntryKind::StopGoServed => driver.stop_gos_served += 1,
+            }
+            driver.entries.push(entry.clone());
+        }
+        Some(driver)
+    }
+
+    /// Report of every driver with a warning or penalty so far.
+    pub fn report(&self) -> StewardingReport {
+        StewardingReport {
+            session_uid: self.session_uid,
+            drivers: (0..MAX_CARS)
+                .filter_map(|car_index| self.driver(car_index))
+                .filter(|driver| {
+                    !driver.entries.is_empty()
+                        || driver.warnings > 0
+                        || driver.time_penalties > 0
+                        || driver.drive_throughs_unserved > 0
+                        || driver.stop_gos_unserved > 0
+                })
+                .collect(),
+        }
+    }
+
+    /// Updates the ledger with the packet, returning the new entries, and the report when the
+    /// session ended.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<PenaltyEvent> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = PenaltyTracker::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        let session_time = header.session_time;
+        let (car_index, kind) = match packet {
+            Packet::LapData(pkt) => {
+                for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+                    car.lap = Some(lap.clone());
+                }
+                return Vec::new();
+            }
+            Packet::Participants(pkt) => {
+                for (car, participant) in self.cars.iter_mut().zip(pkt.participants.iter()) {
+                    car.name = name_to_string(&participant.name);
+                }
+                return Vec::new();
+            }
+            Packet::Event(EventPacket::Penalty(pkt)) => (
+                pkt.event.vehicle_idx as usize,
+                LedgerEntryKind::Penalty(PenaltyRecord::from_event(&pkt.event)),
+            ),
+            Packet::Event(EventPacket::DriveThroughPenaltyServed(pkt)) => (
+                pkt.event.vehicle_idx as usize,
+                LedgerEntryKind::DriveThroughServed,
+            ),
+            Packet::Event(EventPacket::StopGoPenaltyServed(pkt)) => (
+                pkt.event.vehicle_idx as usize,
+                LedgerEntryKind::StopGoServed,
+            ),
+            Packet::Event(EventPacket::SessionEnded(_)) => {
+                return vec![PenaltyEvent::SessionEnded(self.report())];
+            }
+            _ => return Vec::new(),
+        };
+        if car_index >= MAX_CARS {
+            return Vec::new();
+        }
+        let entry = LedgerEntry {
+            car_index,
+            session_time,
+            kind,
+        };
+        self.entries.push(entry.clone());
+        vec![PenaltyEvent::Recorded(entry)]
+    }
+}
+
+impl Rewind for PenaltyTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.entries
+            .retain(|entry| !point.supersedes(point.session_uid, entry.session_time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketLapData, PacketParticipantsData},
+        testing,
+    };
+
+    /// A penalty of the type for the infringement to car 0, with car 1 involved.
+    fn penalty(
+        tracker: &mut PenaltyTracker,
+        session_time: f32,
+        penalty_type: PenaltyType,
+        infringement_type: u8,
+    ) -> Vec<PenaltyEvent> {
+        let details = [penalty_type as u8, infringement_type, 0, 1, 5, 3, 0];
+        let event = testing::event("PENA", &details, 1, 0, session_time);
+        tracker.apply(&Packet::Event(event))
+    }
+
+    fn event(tracker: &mut PenaltyTracker, code: &str, session_time: f32) -> Vec<PenaltyEvent> {
+        let event = testing::event(code, &[0], 1, 0, session_time);
+        tracker.apply(&Packet::Event(event))
+    }
+
+    #[test]
+    fn penalties_are_counted_per_driver() {
+        let mut tracker = PenaltyTracker::new();
+        let events = penalty(&mut tracker, 10.0, PenaltyType::Warning, 7);
+        assert_eq!(
+            events,
+            vec![PenaltyEvent::Recorded(LedgerEntry {
+                car_index: 0,
+                session_time: 10.0,
+                kind: LedgerEntryKind::Penalty(PenaltyRecord {
+                    penalty_type: 5,
+                    infringement_type: 7,
+                    other_car_index: Some(1),
+                    time: 5,
+                    lap_number: 3,
+                    places_gained: 0,
+                }),
+            })]
+        );
+        penalty(&mut tracker, 20.0, PenaltyType::DriveThrough, 17);
+        event(&mut tracker, "DTSV", 30.0);
+        penalty(&mut tracker, 40.0, PenaltyType::StopGo, 3);
+
+        let mut lap_data: PacketLapData = testing::packet(PacketId::LapData, 1, 0, 41.0);
+        lap_data.lap_data[0].warnings = 1;
+        lap_data.lap_data[0].num_unserved_stop_go_pens = 1;
+        tracker.apply(&Packet::LapData(Box::new(lap_data)));
+        let mut participants: PacketParticipantsData =
+            testing::packet(PacketId::Participants, 1, 0, 41.0);
+        participants.participants[0].name[..3].copy_from_slice(&['A', 'L', 'O']);
+        tracker.apply(&Packet::Participants(Box::new(participants)));
+
+        let driver = tracker.driver(0).unwrap();
+        assert_eq!(driver.name, "ALO");
+        assert_eq!((driver.warnings, driver.corner_cutting), (1, 1));
+        assert_eq!(
+            (driver.drive_throughs, driver.drive_throughs_served),
+            (1, 1)
+        );
+        assert_eq!((driver.stop_gos, driver.stop_gos_unserved), (1, 1));
+        assert_eq!(driver.entries.len(), 4);
+        let report = tracker.report();
+        assert_eq!(report.drivers.len(), 1);
+        assert!(report
+            .to_text()
+            .contains("  20.000s Lap 3: DriveThrough for Pit lane speeding"));
+    }
+
+    #[test]
+    fn session_ended_sends_the_report() {
+        let mut tracker = PenaltyTracker::new();
+        penalty(&mut tracker, 10.0, PenaltyType::TimePenalty, 4);
+        let events = event(&mut tracker, "SEND", 20.0);
+        let [PenaltyEvent::SessionEnded(report)] = &events[..] else {
+            panic!("expected the report, got {:?}", events);
+        };
+        assert_eq!(report.session_uid, 1);
+        assert_eq!(report.drivers[0].entries.len(), 1);
+    }
+
+    #[test]
+    fn flashback_drops_the_later_entries() {
+        let mut tracker = PenaltyTracker::new();
+        penalty(&mut tracker, 10.0, PenaltyType::Warning, 7);
+        penalty(&mut tracker, 20.0, PenaltyType::Warning, 8);
+        tracker.apply(&testing::flashback(1, 0, 21.0, 0, 15.0));
+        assert_eq!(tracker.entries().len(), 1);
+        assert_eq!(tracker.entries()[0].session_time, 10.0);
+    }
+
+    #[test]
+    fn session_change_resets_the_ledger() {
+        let mut tracker = PenaltyTracker::new();
+        penalty(&mut tracker, 10.0, PenaltyType::Warning, 7);
+        let lap_data: PacketLapData = testing::packet(PacketId::LapData, 2, 0, 1.0);
+        tracker.apply(&Packet::LapData(Box::new(lap_data)));
+        assert!(tracker.entries().is_empty());
+        assert!(tracker.report().drivers.is_empty());
+    }
+}
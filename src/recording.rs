@@ -0,0 +1,126 @@
+//! In memory recording of the received packets
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+};
+
+/// What happens to the packets after the point the game flashed back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashbackPolicy {
+    Truncate,       // Drop them
+    MarkSuperseded, // Keep them, marked as superseded
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedPacket {
+    pub packet: Packet,
+    pub superseded: bool, // Recorded after a point the game flashed back to
+}
+
+/// Records packets in arrival order. Flashbacks truncate or mark as superseded the packets which
+/// no longer count, depending on the policy.
+#[derive(Debug, Clone, Serialize)]
+pub struct Recording {
+    #[serde(skip)]
+    policy: FlashbackPolicy,
+    packets: Vec<RecordedPacket>,
+}
+
+impl Recording {
+    pub fn new(policy: FlashbackPolicy) -> Self {
+        Recording {
+            policy,
+            packets: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, packet: Packet) {
+        if let Some(point) = FlashbackPoint::from_packet(&packet) {
+            self.rewind(&point);
+        }
+        self.packets.push(RecordedPacket {
+            packet,
+            superseded: false,
+        });
+    }
+
+    /// Every recorded packet, including superseded ones.
+    pub fn packets(&self) -> &[RecordedPacket] {
+        &self.packets
+    }
+
+    /// The packets which still count.
+    pub fn valid_packets(&self) -> impl Iterator<Item = &Packet> {
+        self.packets
+            .iter()
+            .filter(|recorded| !recorded.superseded)
+            .map(|recorded| &recorded.packet)
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+impl Rewind for Recording {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        let supersedes = |recorded: &RecordedPacket| {
+            let header = recorded.packet.header();
+            point.supersedes(header.session_uid, header.session_time)
+        };
+        match self.policy {
+            FlashbackPolicy::Truncate => self.packets.retain(|recorded| !supersedes(recorded)),
+            FlashbackPolicy::MarkSuperseded => self
+                .packets
+                .iter_mut()
+                .filter(|recorded| supersedes(recorded))
+                .for_each(|recorded| recorded.superseded = true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketLapData, testing};
+
+    fn lap_data(session_uid: u64, session_time: f32) -> Packet {
+        let pkt: PacketLapData = testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        Packet::LapData(Box::new(pkt))
+    }
+
+    fn record(policy: FlashbackPolicy) -> Recording {
+        let mut recording = Recording::new(policy);
+        recording.push(lap_data(1, 1.0));
+        recording.push(lap_data(1, 2.0));
+        recording.push(lap_data(1, 3.0));
+        recording.push(testing::flashback(1, 300, 3.0, 150, 1.5));
+        recording
+    }
+
+    #[test]
+    fn flashback_truncates() {
+        let recording = record(FlashbackPolicy::Truncate);
+        // The first packet and the flashback event itself
+        assert_eq!(recording.packets().len(), 2);
+        assert_eq!(recording.valid_packets().count(), 2);
+    }
+
+    #[test]
+    fn flashback_marks_superseded() {
+        let recording = record(FlashbackPolicy::MarkSuperseded);
+        assert_eq!(recording.packets().len(), 4);
+        assert_eq!(recording.valid_packets().count(), 2);
+        assert!(recording.packets()[1].superseded && recording.packets()[2].superseded);
+    }
+
+    #[test]
+    fn flashback_of_another_session_is_ignored() {
+        let mut recording = Recording::new(FlashbackPolicy::Truncate);
+        recording.push(lap_data(1, 3.0));
+        recording.push(testing::flashback(2, 300, 3.0, 150, 1.5));
+        assert_eq!(recording.valid_packets().count(), 2);
+    }
+}
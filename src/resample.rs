@@ -0,0 +1,489 @@
+//! Resampling of telemetry channels to a fixed rate
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{CarMotionData, CarStatusData, CarTelemetryData},
+};
+
+/// How a channel is filled in between packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Interpolation {
+    Linear, // Continuous values, e.g. speed
+    Hold,   // Discrete values keep the last value, e.g. gear or DRS
+}
+
+/// A value of a car read from the packets, e.g. the speed from the car telemetry packet.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub name: String,
+    pub interpolation: Interpolation,
+    extract: fn(&Packet, usize) -> Option<f32>, // None for packets without the channel
+}
+
+fn telemetry(
+    packet: &Packet,
+    car_index: usize,
+    value: fn(&CarTelemetryData) -> f32,
+) -> Option<f32> {
+    match packet {
+        Packet::CarTelemetry(pkt) => pkt.car_telemetry_data.get(car_index).map(value),
+        _ => None,
+    }
+}
+
+fn motion(packet: &Packet, car_index: usize, value: fn(&CarMotionData) -> f32) -> Option<f32> {
+    match packet {
+        Packet::Motion(pkt) => pkt.car_motion_data.get(car_index).map(value),
+        _ => None,
+    }
+}
+
+fn status(packet: &Packet, car_index: usize, value: fn(&CarStatusData) -> f32) -> Option<f32> {
+    match packet {
+        Packet::CarStatus(pkt) => pkt.car_status_data.get(car_index).map(value),
+        _ => None,
+    }
+}
+
+impl Channel {
+    /// A custom channel, `extract` returns the value of the car in the packet, None for packets
+    /// which don't carry the channel.
+    pub fn new(
+        name: &str,
+        interpolation: Interpolation,
+        extract: fn(&Packet, usize) -> Option<f32>,
+    ) -> Self {
+        Channel {
+            name: name.to_string(),
+            interpolation,
+            extract,
+        }
+    }
+
+    pub fn speed() -> Self {
+        Channel::new("speed", Interpolation::Linear, |p, car| {
+            telemetry(p, car, |t| t.speed as f32)
+        })
+    }
+
+    pub fn throttle() -> Self {
+        Channel::new("throttle", Interpolation::Linear, |p, car| {
+            telemetry(p, car, |t| t.throttle)
+        })
+    }
+
+    pub fn brake() -> Self {
+        Channel::new("brake", Interpolation::Linear, |p, car| {
+            telemetry(p, car, |t| t.brake)
+        })
+    }
+
+    pub fn steer() -> Self {
+        Channel::new("steer", Interpolation::Linear, |p, car| {
+            telemetry(p, car, |t| t.steer)
+        })
+    }
+
+    pub fn gear() -> Self {
+        Channel::new("gear", Interpolation::Hold, |p, car| {
+            telemetry(p, car, |t| t.gear as f32)
+        })
+    }
+
+    pub fn engine_rpm() -> Self {
+        Channel::new("engine_rpm", Interpolation::Linear, |p, car| {
+            telemetry(p, car, |t| t.engine_rpm as f32)
+        })
+    }
+
+    pub fn drs() -> Self {
+        Channel::new("drs", Interpolation::Hold, |p, car| {
+            telemetry(p, car, |t| t.drs as f32)
+        })
+    }
+
+    pub fn lap_distance() -> Self {
+        Channel::new("lap_distance", Interpolation::Linear, |p, car| match p {
+            Packet::LapData(pkt) => pkt.lap_data.get(car).map(|lap| lap.lap_distance),
+            _ => None,
+        })
+    }
+
+    pub fn world_position_x() -> Self {
+        Channel::new("world_position_x", Interpolation::Linear, |p, car| {
+            motion(p, car, |m| m.world_position_x)
+        })
+    }
+
+    pub fn world_position_y() -> Self {
+        Channel::new("world_position_y", Interpolation::Linear, |p, car| {
+            motion(p, car, |m| m.world_position_y)
+        })
+    }
+
+    pub fn world_position_z() -> Self {
+        Channel::new("world_position_z", Interpolation::Linear, |p, car| {
+            motion(p, car, |m| m.world_position_z)
+        })
+    }
+
+    pub fn g_force_lateral() -> Self {
+        Channel::new("g_force_lateral", Interpolation::Linear, |p, car| {
+            motion(p, car, |m| m.g_force_lateral)
+        })
+    }
+
+    pub fn g_force_longitudinal() -> Self {
+        Channel::new("g_force_longitudinal", Interpolation::Linear, |p, car| {
+            motion(p, car, |m| m.g_force_longitudinal)
+        })
+    }
+
+    pub fn fuel_in_tank() -> Self {
+        Channel::new("fuel_in_tank", Interpolation::Linear, |p, car| {
+            status(p, car, |s| s.fuel_in_tank)
+        })
+    }
+
+    pub fn ers_store_energy() -> Self {
+        Channel::new("ers_store_energy", Interpolation::Linear, |p, car| {
+            status(p, car, |s| s.ers_store_energy)
+        })
+    }
+
+    pub fn ers_deploy_mode() -> Self {
+        Channel::new("ers_deploy_mode", Interpolation::Hold, |p, car| {
+            status(p, car, |s| s.ers_deploy_mode as f32)
+        })
+    }
+}
+
+/// The channels at one instant of the fixed rate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResampledRow {
+    pub session_time: f64,
+    pub values: Vec<Option<f32>>, // In the order of the channels, None before the first value
+}
+
+impl ResampledRow {
+    /// The row as comma separated values, empty for missing values.
+    pub fn to_csv(&self) -> String {
+        let mut row = format!("{:.6}", self.session_time);
+        for value in self.values.iter() {
+            row.push(',');
+            if let Some(value) = value {
+                row.push_str(&value.to_string());
+            }
+        }
+        row
+    }
+}
+
+/// Values of a channel received but not yet resampled.
+#[derive(Debug, Clone, Default)]
+struct ChannelSamples {
+    samples: VecDeque<(f64, f32)>, // Session time and value
+}
+
+impl ChannelSamples {
+    fn push(&mut self, session_time: f64, value: f32) {
+        match self.samples.back_mut() {
+            // Out of order, the later packet already arrived
+            Some((last, _)) if session_time < *last => {}
+            Some((last, last_value)) if session_time == *last => *last_value = value,
+            _ => self.samples.push_back((session_time, value)),
+        }
+    }
+
+    fn value_at(&self, session_time: f64, interpolation: Interpolation) -> Option<f32> {
+        let next = self
+            .samples
+            .partition_point(|(time, _)| *time < session_time);
+        let (after_time, after) = match self.samples.get(next) {
+            Some(after) => *after,
+            // Past the last value, it is held
+            None => return self.samples.back().map(|(_, value)| *value),
+        };
+        let (before_time, before) = match next.checked_sub(1) {
+            Some(prev) => self.samples[prev],
+            None if after_time == session_time => return Some(after),
+            None => return None,
+        };
+        match interpolation {
+            Interpolation::Hold if after_time == session_time => Some(after),
+            Interpolation::Hold => Some(before),
+            Interpolation::Linear => {
+                let ratio = (session_time - before_time) / (after_time - before_time);
+                Some(before + (ratio as f32) * (after - before))
+            }
+        }
+    }
+
+    /// Drops the values after the session time.
+    fn truncate(&mut self, session_time: f64) {
+        let keep = self
+            .samples
+            .partition_point(|(time, _)| *time <= session_time);
+        self.samples.truncate(keep);
+    }
+
+    /// Drops the values no longer needed to resample at the session time.
+    fn prune(&mut self, session_time: f64) {
+        let keep = self
+            .samples
+            .partition_point(|(time, _)| *time <= session_time);
+        self.samples.drain(..keep.saturating_sub(1));
+    }
+}
+
+/// Resamples channels of one car, the player car unless set otherwise, to a fixed rate against
+/// the session time. A row is produced once every channel has a value at or after its time, so
+/// continuous channels are interpolated rather than extrapolated, or once it is older than the
+/// maximum delay, so a channel which stopped arriving holds its last value rather than stalling
+/// the output. After a flashback the values received after the flashback point are dropped,
+/// rows already produced after it are not retracted and start again from the next packet.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    rate: f64,
+    car_index: Option<usize>,
+    max_delay: f64,
+    channels: Vec<Channel>,
+    samples: Vec<ChannelSamples>,
+    session_uid: u64,
+    next_row: Option<u64>, // Index of the next row, its session time is the index over the rate
+}
+
+impl Resampler {
+    /// Resampler producing `rate` rows a second, e.g. 20, 60 or 100.
+    pub fn new(rate: u32) -> Self {
+        Resampler {
+            rate: rate.max(1) as f64,
+            car_index: None,
+            max_delay: 1.0,
+            channels: Vec::new(),
+            samples: Vec::new(),
+            session_uid: 0,
+            next_row: None,
+        }
+    }
+
+    /// Resamples this car instead of the player car.
+    pub fn track_car(mut self, car_index: usize) -> Self {
+        self.car_index = Some(car_index);
+        self
+    }
+
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channels.push(channel);
+        self.samples.push(ChannelSamples::default());
+        self
+    }
+
+    /// Session time after which a row is produced even if a channel has no value after it yet.
+    /// Defaults to 1 second.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay.as_secs_f64();
+        self
+    }
+
+    /// Channel names as a comma separated header for [`ResampledRow::to_csv`].
+    pub fn csv_header(&self) -> String {
+        let mut header = String::from("session_time");
+        for channel in self.channels.iter() {
+            header.push(',');
+            header.push_str(&channel.name);
+        }
+        header
+    }
+
+    fn restart(&mut self) {
+        self.samples.iter_mut().for_each(|s| s.samples.clear());
+        self.next_row = None;
+    }
+
+    /// Adds the values of the packet, returning the rows which can now be produced.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<ResampledRow> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            self.restart();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            // The event carries no values, and its session time is from before the flashback
+            self.rewind(&point);
+            return Vec::new();
+        }
+        let session_time = header.session_time as f64;
+        let car_index = self.car_index.unwrap_or(header.player_car_index as usize);
+        for (channel, samples) in self.channels.iter().zip(self.samples.iter_mut()) {
+            if let Some(value) = (channel.extract)(packet, car_index) {
+                samples.push(session_time, value);
+            }
+        }
+
+        let next_row = *self
+            .next_row
+            .get_or_insert((session_time * self.rate).ceil() as u64);
+        // Rows up to the oldest latest value of the channels which have values
+        let ready = self
+            .samples
+            .iter()
+            .filter_map(|s| s.samples.back().map(|(time, _)| *time))
+            .fold(f64::MAX, f64::min)
+            .max(session_time - self.max_delay);
+        if ready == f64::MAX {
+            return Vec::new();
+        }
+        let mut rows = Vec::new();
+        let mut row = next_row;
+        loop {
+            let time = row as f64 / self.rate;
+            if time > ready {
+                break;
+            }
+            rows.push(ResampledRow {
+                session_time: time,
+                values: self
+                    .channels
+                    .iter()
+                    .zip(self.samples.iter())
+                    .map(|(channel, samples)| samples.value_at(time, channel.interpolation))
+                    .collect(),
+            });
+            row += 1;
+        }
+        self.next_row = Some(row);
+        let time = row as f64 / self.rate;
+        self.samples.iter_mut().for_each(|s| s.prune(time));
+        rows
+    }
+}
+
+impl Rewind for Resampler {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        let session_time = point.session_time as f64;
+        self.samples
+            .iter_mut()
+            .for_each(|s| s.truncate(session_time));
+        // Rows pending before the point are still produced, once rows after the point have been
+        // produced they start again from the next packet
+        let row = (session_time * self.rate).ceil() as u64;
+        if self.next_row.is_some_and(|next_row| next_row > row) {
+            self.next_row = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarStatusData, PacketCarTelemetryData},
+        testing,
+    };
+
+    fn telemetry(session_uid: u64, session_time: f32, speed: u16, gear: i8) -> Packet {
+        let mut pkt: PacketCarTelemetryData =
+            testing::packet(PacketId::CarTelemetry, session_uid, 0, session_time);
+        pkt.car_telemetry_data[0].speed = speed;
+        pkt.car_telemetry_data[0].gear = gear;
+        Packet::CarTelemetry(Box::new(pkt))
+    }
+
+    fn resampler() -> Resampler {
+        Resampler::new(10)
+            .channel(Channel::speed())
+            .channel(Channel::gear())
+    }
+
+    #[test]
+    fn rows_interpolate_linear_and_hold_discrete_channels() {
+        let mut resampler = resampler();
+        assert_eq!(resampler.csv_header(), "session_time,speed,gear");
+        let rows = resampler.apply(&telemetry(1, 1.0, 100, 3));
+        assert_eq!(rows.len(), 1);
+        let rows = resampler.apply(&telemetry(1, 1.25, 200, 4));
+        assert_eq!(
+            rows.iter().map(|r| r.session_time).collect::<Vec<_>>(),
+            vec![1.1, 1.2]
+        );
+        assert_eq!(rows[0].values, vec![Some(140.0), Some(3.0)]);
+        assert_eq!(rows[1].to_csv(), "1.200000,180,3");
+    }
+
+    fn status(session_uid: u64, session_time: f32, fuel_in_tank: f32) -> Packet {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        pkt.car_status_data[0].fuel_in_tank = fuel_in_tank;
+        Packet::CarStatus(Box::new(pkt))
+    }
+
+    #[test]
+    fn rows_are_produced_after_the_max_delay() {
+        let mut resampler = Resampler::new(10)
+            .channel(Channel::speed())
+            .channel(Channel::fuel_in_tank())
+            .max_delay(Duration::from_millis(200));
+        resampler.apply(&status(1, 1.0, 10.0));
+        resampler.apply(&telemetry(1, 1.0, 100, 3));
+        // The fuel stops arriving, rows wait for it until they are 200 ms old
+        assert!(resampler.apply(&telemetry(1, 1.1, 110, 3)).is_empty());
+        let rows = resampler.apply(&telemetry(1, 1.35, 135, 3));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].session_time, 1.1);
+        assert_eq!(rows[0].values, vec![Some(110.0), Some(10.0)]);
+    }
+
+    #[test]
+    fn flashback_drops_the_values_after_the_point() {
+        let mut resampler = Resampler::new(10)
+            .channel(Channel::speed())
+            .channel(Channel::fuel_in_tank());
+        resampler.apply(&status(1, 1.0, 10.0));
+        resampler.apply(&telemetry(1, 1.0, 100, 3));
+        resampler.apply(&telemetry(1, 1.2, 120, 3));
+        resampler.apply(&telemetry(1, 1.4, 140, 3));
+        resampler.apply(&testing::flashback(1, 0, 1.4, 0, 1.25));
+        let mut rows = resampler.apply(&status(1, 1.31, 9.0));
+        rows.extend(resampler.apply(&telemetry(1, 1.31, 200, 3)));
+        assert_eq!(
+            rows.iter().map(|r| r.session_time).collect::<Vec<_>>(),
+            vec![1.1, 1.2, 1.3]
+        );
+        // Speed at 1.3 is from the value after the flashback, not interpolated towards 140
+        assert!((rows[2].values[0].unwrap() - 192.7).abs() < 0.1);
+    }
+
+    #[test]
+    fn flashback_before_produced_rows_starts_again() {
+        let mut resampler = resampler();
+        resampler.apply(&telemetry(1, 1.0, 100, 3));
+        resampler.apply(&telemetry(1, 2.0, 200, 4));
+        resampler.apply(&testing::flashback(1, 0, 2.0, 0, 1.5));
+        assert!(resampler.apply(&telemetry(1, 1.61, 150, 3)).is_empty());
+        let rows = resampler.apply(&telemetry(1, 1.81, 170, 3));
+        assert_eq!(
+            rows.iter().map(|r| r.session_time).collect::<Vec<_>>(),
+            vec![1.7, 1.8]
+        );
+        assert!((rows[0].values[0].unwrap() - 159.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn session_change_restarts_the_rows() {
+        let mut resampler = resampler();
+        resampler.apply(&telemetry(1, 5.0, 100, 3));
+        let rows = resampler.apply(&telemetry(2, 0.5, 50, 1));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].session_time, 0.5);
+        assert_eq!(rows[0].values, vec![Some(50.0), Some(1.0)]);
+    }
+}
@@ -0,0 +1,577 @@
+//! Race results from the final classification and championship standings across rounds
+use std::{cmp::Ordering, collections::HashMap, fmt::Write as _, path::Path};
+
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::TelemetryError,
+    json::{load_json, save_json},
+    packet::{name_to_string, Packet},
+    telemetry::{PacketFinalClassificationData, PacketParticipantsData, MAX_CARS},
+};
+
+/// A stint of tyres in the result.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ResultStint {
+    pub actual_compound: u8, // See `CarStatusData::actual_tyre_compound`
+    pub visual_compound: u8, // See `CarStatusData::visual_tyre_compound`
+    pub end_lap: u8,
+}
+
+/// Gap of a car to the winner.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Gap {
+    Leader,
+    Time(f64), // Seconds, including time penalties
+    Laps(u8),  // Laps down on the winner
+    NotClassified,
+}
+
+/// A car in the result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResultEntry {
+    pub position: u8,
+    pub car_index: usize,
+    pub name: String, // "Car" and the index if the participants weren't seen
+    pub team_id: u8,
+    pub race_number: u8,
+    pub grid_position: u8,
+    pub num_laps: u8,
+    pub result_status: u8, // See `FinalClassificationData::result_status`
+    pub game_points: u8,   // Points the game awarded
+    pub num_pit_stops: u8,
+    pub best_lap_time_in_ms: u32,
+    pub fastest_lap: bool, // Fastest lap of the session
+    pub race_time: f64,    // Seconds, including time penalties
+    pub penalties_time: u8,
+    pub num_penalties: u8,
+    pub gap: Gap,
+    pub stints: Vec<ResultStint>,
+}
+
+impl ResultEntry {
+    /// Finished, or not running at the end but counted as finished, e.g. lapped cars.
+    pub fn classified(&self) -> bool {
+        !matches!(self.result_status, 0 | 4..=7)
+    }
+}
+
+/// The classification of a session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RaceResult {
+    pub session_uid: u64,
+    pub track_id: i8,              // -1 if the session packet wasn't seen
+    pub session_type: u8,          // See `PacketSessionData::session_type`, 0 if unknown
+    pub entries: Vec<ResultEntry>, // By position
+}
+
+/// Race time as minutes, seconds and milliseconds, e.g. 1:23:45.678 or 1:23.456.
+fn format_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    let (hours, minutes) = (millis / 3_600_000, millis / 60_000 % 60);
+    let (secs, millis) = (millis / 1000 % 60, millis % 1000);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}.{:03}", hours, minutes, secs, millis)
+    } else {
+        format!("{}:{:02}.{:03}", minutes, secs, millis)
+    }
+}
+
+fn result_status_name(result_status: u8) -> &'static str {
+    match result_status {
+        4 => "DNF",
+        5 => "DSQ",
+        6 => "NC",
+        7 => "Retired",
+        _ => "",
+    }
+}
+
+impl RaceResult {
+    /// Builds the result of the final classification, with the names and teams of the
+    /// participants if given.
+    pub fn new(
+        classification: &PacketFinalClassificationData,
+        participants: Option<&PacketParticipantsData>,
+    ) -> Self {
+        let num_cars = (classification.num_cars as usize).min(MAX_CARS);
+        let mut entries: Vec<ResultEntry> = classification.classification_data[..num_cars]
+            .iter()
+            .enumerate()
+            .filter(|(_, car)| car.position > 0)
+            .map(|(car_index, car)| {
+                let participant = participants.map(|pkt| &pkt.participants[car_index]);
+                let num_stints = (car.num_tyre_stints as usize).min(car.tyre_stints_actual.len());
+                ResultEntry {
+                    position: car.position,
+                    car_index,
+                    name: participant
+                        .map(|p| name_to_string(&p.name))
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or_else(|| format!("Car {}", car_index)),
+                    team_id: participant.map_or(255, |p| p.team_id),
+                    race_number: participant.map_or(0, |p| p.race_number),
+                    grid_position: car.grid_position,
+                    num_laps: car.num_laps,
+                    result_status: car.result_status,
+                    game_points: car.points,
+                    num_pit_stops: car.num_pit_stops,
+                    best_lap_time_in_ms: car.best_lap_time_in_ms,
+                    fastest_lap: false,
+                    race_time: car.total_race_time + car.penalties_time as f64,
+                    penalties_time: car.penalties_time,
+                    num_penalties: car.num_penalties,
+                    gap: Gap::NotClassified,
+                    stints: (0..num_stints)
+                        .map(|stint| ResultStint {
+                            actual_compound: car.tyre_stints_actual[stint],
+                            visual_compound: car.tyre_stints_visual[stint],
+                            end_lap: car.tyre_stints_end_laps[stint],
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.position);
+
+        if let Some(fastest) = entries
+            .iter_mut()
+            .filter(|entry| entry.best_lap_time_in_ms > 0)
+            .min_by_key(|entry| entry.best_lap_time_in_ms)
+        {
+            fastest.fastest_lap = true;
+        }
+        let winner = entries.first().map(|e| (e.num_laps, e.race_time));
+        for entry in entries.iter_mut() {
+            entry.gap = match winner {
+                _ if !entry.classified() => Gap::NotClassified,
+                _ if entry.position == 1 => Gap::Leader,
+                Some((laps, _)) if entry.num_laps < laps => Gap::Laps(laps - entry.num_laps),
+                Some((_, time)) => Gap::Time(entry.race_time - time),
+                None => Gap::NotClassified,
+            };
+        }
+        RaceResult {
+            session_uid: classification.header.session_uid,
+            track_id: -1,
+            session_type: 0,
+            entries,
+        }
+    }
+
+    /// Entry which set the fastest lap.
+    pub fn fastest_lap(&self) -> Option<&ResultEntry> {
+        self.entries.iter().find(|entry| entry.fastest_lap)
+    }
+
+    /// The result sheet as text, a line per car with the gap to the winner.
+    pub fn to_text(&self) -> String {
+        let mut text =
+            String::from("Pos  No  Driver                Laps  Time/Gap      Grid  Pits  Pen\n");
+        for entry in self.entries.iter() {
+            let time = match entry.gap {
+                Gap::Leader => format_time(entry.race_time),
+                Gap::Time(gap) => format!("+{:.3}", gap),
+                Gap::Laps(1) => String::from("+1 lap"),
+                Gap::Laps(laps) => format!("+{} laps", laps),
+                Gap::NotClassified => result_status_name(entry.result_status).to_string(),
+            };
+            let _ = writeln!(
+                text,
+                "{:>3}  {:>2}  {:<20}  {:>4}  {:<12}  {:>4}  {:>4}  {:>3}{}",
+                entry.position,
+                entry.race_number,
+                entry.name,
+                entry.num_laps,
+                time,
+                entry.grid_position,
+                entry.num_pit_stops,
+                entry.penalties_time,
+                if entry.fastest_lap {
+                    "  Fastest lap"
+                } else {
+                    ""
+                }
+            );
+        }
+        text
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<RaceResult, TelemetryError> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+/// Builds the [`RaceResult`] of every session from its final classification packet, using the
+/// latest participants and session packets for the names, teams and track.
+#[derive(Debug, Clone)]
+pub struct ResultRecorder {
+    session_uid: u64,
+    participants: Option<PacketParticipantsData>,
+    track_id: i8,
+    session_type: u8,
+}
+
+impl Default for ResultRecorder {
+    fn default() -> Self {
+        ResultRecorder {
+            session_uid: 0,
+            participants: None,
+            track_id: -1,
+            session_type: 0,
+        }
+    }
+}
+
+impl ResultRecorder {
+    pub fn new() -> Self {
+        ResultRecorder::default()
+    }
+
+    /// Updates the recorder with the packet, returning the result on the final classification.
+    pub fn apply(&mut self, packet: &Packet) -> Option<RaceResult> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = ResultRecorder::default();
+            self.session_uid = header.session_uid;
+        }
+        match packet {
+            Packet::Participants(pkt) => self.participants = Some((**pkt).clone()),
+            Packet::Session(pkt) => {
+                self.track_id = pkt.track_id;
+                self.session_type = pkt.session_type;
+            }
+            Packet::FinalClassification(pkt) => {
+                let mut result = RaceResult::new(pkt, self.participants.as_ref());
+                result.track_id = self.track_id;
+                result.session_type = self.session_type;
+                return Some(result);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Points awarded by finishing position, with an optional point for the fastest lap.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointsSystem {
+    pub positions: Vec<u32>,    // Points of the winner first
+    pub fastest_lap: u32,       // Points for the fastest lap
+    pub fastest_lap_within: u8, // Fastest lap only scores finishing in this position or higher
+}
+
+impl Default for PointsSystem {
+    /// The current Formula 1 points, 25 to 1 for the top ten and a point for the fastest lap
+    /// finishing in the top ten.
+    fn default() -> Self {
+        PointsSystem {
+            positions: vec![25, 18, 15, 12, 10, 8, 6, 4, 2, 1],
+            fastest_lap: 1,
+            fastest_lap_within: 10,
+        }
+    }
+}
+
+impl PointsSystem {
+    /// Points by finishing position, without points for the fastest lap.
+    pub fn new(positions: Vec<u32>) -> Self {
+        PointsSystem {
+            positions,
+            fastest_lap: 0,
+            fastest_lap_within: 0,
+        }
+    }
+
+    pub fn fastest_lap(mut self, points: u32, within: u8) -> Self {
+        self.fastest_lap = points;
+        self.fastest_lap_within = within;
+        self
+    }
+
+    /// Points scored by the entry.
+    pub fn points(&self, entry: &ResultEntry) -> u32 {
+        if !entry.classified() {
+            return 0;
+        }
+        let position = self
+            .positions
+            .get(entry.position as usize - 1)
+            .copied()
+            .unwrap_or(0);
+        if entry.fastest_lap && entry.position <= self.fastest_lap_within {
+            position + self.fastest_lap
+        } else {
+            position
+        }
+    }
+}
+
+/// A driver's finish in a round of the championship.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundEntry {
+    pub name: String,
+    pub team_id: u8,
+    pub position: u8,
+    pub classified: bool,
+    pub fastest_lap: bool,
+    pub points: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Round {
+    pub session_uid: u64,
+    pub track_id: i8,
+    pub entries: Vec<RoundEntry>, // By position
+}
+
+/// A driver's or team's place in the championship.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Standing {
+    pub position: usize,
+    pub name: String, // Driver name, or the team id for teams
+    pub points: u32,
+    pub wins: u32,
+    pub podiums: u32,
+    pub finishes: Vec<u8>, // Classified finishing positions, best first, for countback
+}
+
+/// Championship standings accumulated over rounds, drivers are told apart by their name. Saved
+/// and loaded as JSON to carry a season over.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Standings {
+    pub points_system: PointsSystem,
+    pub rounds: Vec<Round>,
+}
+
+impl Standings {
+    pub fn new() -> Self {
+        Standings::default()
+    }
+
+    pub fn points_system(mut self, points_system: PointsSystem) -> Self {
+        self.points_system = points_system;
+        self
+    }
+
+    /// Adds the result as a round, replacing the round of the same session if it was added before.
+    pub fn add_round(&mut self, result: &RaceResult) {
+        let round = Round {
+            session_uid: result.session_uid,
+            track_id: result.track_id,
+            entries: result
+                .entries
+                .iter()
+                .map(|entry| RoundEntry {
+                    name: entry.name.clone(),
+                    team_id: entry.team_id,
+                    position: entry.position,
+                    classified: entry.classified(),
+                    fastest_lap: entry.fastest_lap,
+                    points: self.points_system.points(entry),
+                })
+                .collect(),
+        };
+        match self
+            .rounds
+            .iter_mut()
+            .find(|r| r.session_uid == result.session_uid)
+        {
+            Some(existing) => *existing = round,
+            None => self.rounds.push(round),
+        }
+    }
+
+    /// Drivers' championship, ties are broken by the better finishes.
+    pub fn drivers(&self) -> Vec<Standing> {
+        self.table(|entry| entry.name.clone())
+    }
+
+    /// Teams' championship, named by the team id.
+    pub fn teams(&self) -> Vec<Standing> {
+        self.table(|entry| entry.team_id.to_string())
+    }
+
+    fn table(&self, key: impl Fn(&RoundEntry) -> String) -> Vec<Standing> {
+        let mut standings: Vec<Standing> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for entry in self.rounds.iter().flat_map(|round| round.entries.iter()) {
+            let name = key(entry);
+            let idx = *index.entry(name.clone()).or_insert_with(|| {
+                standings.push(Standing {
+                    position: 0,
+                    name,
+                    points: 0,
+                    wins: 0,
+                    podiums: 0,
+                    finishes: Vec::new(),
+                });
+                standings.len() - 1
+            });
+            let standing = &mut standings[idx];
+            standing.points += entry.points;
+            if entry.classified {
+                standing.wins += (entry.position == 1) as u32;
+                standing.podiums += (entry.position <= 3) as u32;
+                standing.finishes.push(entry.position);
+            }
+        }
+        for standing in standings.iter_mut() {
+            standing.finishes.sort_unstable();
+        }
+        standings.sort_by(|a, b| match b.points.cmp(&a.points) {
+            // Countback, the better finish first
+            Ordering::Equal => countback(&a.finishes, &b.finishes),
+            ordering => ordering,
+        });
+        for (position, standing) in standings.iter_mut().enumerate() {
+            standing.position = position + 1;
+        }
+        standings
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Standings, TelemetryError> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+/// Orders by the best finish, then the number of such finishes, then the next best finish.
+fn countback(a: &[u8], b: &[u8]) -> Ordering {
+    for (a, b) in a.iter().zip(b.iter()) {
+        match a.cmp(b) {
+            Ordering::Equal => continue,
+            ordering => return ordering,
+        }
+    }
+    b.len().cmp(&a.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, telemetry::PacketSessionData, testing};
+
+    /// Car 0 wins, car 1 is 5.5 seconds behind with the fastest lap, car 2 is a lap down and
+    /// car 3 retired.
+    fn classification(session_uid: u64) -> PacketFinalClassificationData {
+        let mut pkt: PacketFinalClassificationData =
+            testing::packet(PacketId::FinalClassification, session_uid, 0, 0.0);
+        pkt.num_cars = 4;
+        let cars = [
+            (1, 10, 3, 5_000.0, 80_000),
+            (2, 10, 3, 5_005.5, 79_000),
+            (3, 9, 3, 5_010.0, 81_000),
+            (4, 4, 7, 2_000.0, 78_500),
+        ];
+        for (car, (position, num_laps, result_status, total_race_time, best_lap)) in
+            pkt.classification_data.iter_mut().zip(cars)
+        {
+            car.position = position;
+            car.num_laps = num_laps;
+            car.result_status = result_status;
+            car.total_race_time = total_race_time;
+            car.best_lap_time_in_ms = best_lap;
+        }
+        pkt
+    }
+
+    #[test]
+    fn gaps_and_fastest_lap_are_from_the_classification() {
+        let result = RaceResult::new(&classification(1), None);
+        let gaps: Vec<Gap> = result.entries.iter().map(|e| e.gap).collect();
+        assert_eq!(
+            gaps,
+            vec![
+                Gap::Leader,
+                Gap::Time(5.5),
+                Gap::Laps(1),
+                Gap::NotClassified
+            ]
+        );
+        // The fastest lap of the session counts whether or not the car was classified
+        assert_eq!(result.fastest_lap().unwrap().car_index, 3);
+        assert_eq!(result.entries[0].name, "Car 0");
+        let text = result.to_text();
+        assert!(text.contains("1:23:20.000"));
+        assert!(text.contains("+1 lap"));
+        assert!(text.contains("Retired"));
+    }
+
+    #[test]
+    fn recorder_adds_the_track_and_names() {
+        let mut recorder = ResultRecorder::new();
+        let mut session: PacketSessionData = testing::packet(PacketId::Session, 1, 0, 0.0);
+        session.track_id = 7;
+        session.session_type = 10;
+        recorder.apply(&Packet::Session(Box::new(session)));
+        let mut participants: PacketParticipantsData =
+            testing::packet(PacketId::Participants, 1, 0, 0.0);
+        participants.participants[0].name[..3].copy_from_slice(&['V', 'E', 'R']);
+        participants.participants[0].team_id = 2;
+        recorder.apply(&Packet::Participants(Box::new(participants)));
+        let result = recorder
+            .apply(&Packet::FinalClassification(Box::new(classification(1))))
+            .unwrap();
+        assert_eq!((result.track_id, result.session_type), (7, 10));
+        assert_eq!(
+            (result.entries[0].name.as_str(), result.entries[0].team_id),
+            ("VER", 2)
+        );
+
+        // A new session forgets the track and names
+        let result = recorder
+            .apply(&Packet::FinalClassification(Box::new(classification(2))))
+            .unwrap();
+        assert_eq!(result.track_id, -1);
+        assert_eq!(result.entries[0].name, "Car 0");
+    }
+
+    #[test]
+    fn standings_break_ties_on_countback() {
+        let mut standings = Standings::new().points_system(PointsSystem::new(vec![10, 5, 5]));
+        let mut result = RaceResult::new(&classification(1), None);
+        standings.add_round(&result);
+        // Same session again replaces the round
+        standings.add_round(&result);
+        result.session_uid = 2;
+        for (entry, position) in result.entries.iter_mut().zip([3, 1, 2]) {
+            entry.position = position;
+        }
+        standings.add_round(&result);
+        let drivers = standings.drivers();
+        assert_eq!(standings.rounds.len(), 2);
+        // Cars 0 and 1 are level on points, car 1 was second rather than third
+        assert_eq!(
+            drivers
+                .iter()
+                .map(|d| (d.name.as_str(), d.points, d.wins, d.podiums))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Car 1", 15, 1, 2),
+                ("Car 0", 15, 1, 2),
+                ("Car 2", 10, 0, 2),
+                ("Car 3", 0, 0, 0)
+            ]
+        );
+        assert_eq!(drivers[0].finishes, vec![1, 2]);
+    }
+
+    #[test]
+    fn fastest_lap_point_needs_a_top_finish() {
+        let points = PointsSystem::default();
+        let mut result = RaceResult::new(&classification(1), None);
+        assert_eq!(points.points(&result.entries[1]), 18);
+        result.entries[1].fastest_lap = true;
+        assert_eq!(points.points(&result.entries[1]), 19);
+        assert_eq!(points.points(&result.entries[3]), 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Recording the laps of a car as samples by lap distance, from the packets of the same frame
+use crate::telemetry::LapData;
+
+/// A value of one car with the frame identifier of the packet it came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Framed<T> {
+    pub(crate) frame: u32,
+    pub(crate) value: T,
+}
+
+impl<T> Framed<T> {
+    pub(crate) fn new(frame: u32, value: T) -> Self {
+        Framed { frame, value }
+    }
+
+    /// The value if it came from the frame.
+    pub(crate) fn at(&self, frame: u32) -> Option<&T> {
+        (self.frame == frame).then_some(&self.value)
+    }
+}
+
+/// A sample taken at a point of the lap.
+pub(crate) trait DistanceSample {
+    fn lap_distance(&self) -> f32;
+}
+
+/// A lap being recorded, or completed, as samples ordered by lap distance.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SampledLap<S> {
+    pub(crate) lap_number: u8,
+    pub(crate) lap_time_in_ms: u32, // Zero until the lap is completed
+    pub(crate) valid: bool,
+    pub(crate) samples: Vec<S>,
+}
+
+impl<S: DistanceSample> SampledLap<S> {
+    /// Whether the first sample is within `tolerance` metres of the line, laps joined part way
+    /// round aren't complete laps.
+    pub(crate) fn starts_at_line(&self, tolerance: f32) -> bool {
+        self.samples
+            .first()
+            .is_some_and(|s| s.lap_distance() < tolerance)
+    }
+}
+
+/// Follows the lap of a car from the lap data and records the samples of its current lap.
+#[derive(Debug, Clone)]
+pub(crate) struct LapSampler<S> {
+    current: Option<SampledLap<S>>,
+}
+
+impl<S> Default for LapSampler<S> {
+    fn default() -> Self {
+        LapSampler { current: None }
+    }
+}
+
+impl<S: DistanceSample> LapSampler<S> {
+    /// Updates the current lap with the lap data, `valid` being whether the lap still counts as
+    /// of this packet. Returns the previous lap when the car has just completed it.
+    pub(crate) fn update(&mut self, lap: &LapData, valid: bool) -> Option<SampledLap<S>> {
+        if lap.current_lap_num == 0 {
+            return None;
+        }
+        let finished = match self.current.take() {
+            Some(mut current) if current.lap_number == lap.current_lap_num => {
+                current.valid &= valid;
+                self.current = Some(current);
+                return None;
+            }
+            Some(mut current) if current.lap_number.checked_add(1) == Some(lap.current_lap_num) => {
+                current.lap_time_in_ms = lap.last_lap_time_in_ms;
+                Some(current)
+            }
+            _ => None,
+        };
+        self.current = Some(SampledLap {
+            lap_number: lap.current_lap_num,
+            lap_time_in_ms: 0,
+            valid,
+            samples: Vec::new(),
+        });
+        finished
+    }
+
+    /// Adds the sample to the current lap when it is at least `spacing` metres after the last.
+    pub(crate) fn push(&mut self, sample: S, spacing: f32) {
+        if let Some(current) = self.current.as_mut() {
+            match current.samples.last() {
+                Some(last) if sample.lap_distance() < last.lap_distance() + spacing => {}
+                _ => current.samples.push(sample),
+            }
+        }
+    }
+
+    /// Drops the samples after the lap distance, the car having gone back round the lap after a
+    /// flashback.
+    pub(crate) fn truncate(&mut self, lap_distance: f32) {
+        if let Some(current) = self.current.as_mut() {
+            let keep = current
+                .samples
+                .partition_point(|s| s.lap_distance() <= lap_distance);
+            current.samples.truncate(keep);
+        }
+    }
+
+    /// Drops the current lap, recording restarts with the next lap data.
+    pub(crate) fn restart(&mut self) {
+        self.current = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl DistanceSample for f32 {
+        fn lap_distance(&self) -> f32 {
+            *self
+        }
+    }
+
+    fn lap_data(current_lap_num: u8, last_lap_time_in_ms: u32) -> LapData {
+        LapData {
+            current_lap_num,
+            last_lap_time_in_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn value_is_only_paired_within_its_frame() {
+        let framed = Framed::new(7, 1.5);
+        assert_eq!(framed.at(7), Some(&1.5));
+        assert_eq!(framed.at(8), None);
+    }
+
+    #[test]
+    fn samples_closer_than_the_spacing_are_skipped() {
+        let mut sampler = LapSampler::default();
+        sampler.push(0.0, 1.0);
+        assert!(sampler.current.is_none());
+        sampler.update(&lap_data(1, 0), true);
+        for distance in [0.0, 0.5, 1.0, 1.2, 2.5] {
+            sampler.push(distance, 1.0);
+        }
+        assert_eq!(
+            sampler.current.as_ref().unwrap().samples,
+            vec![0.0, 1.0, 2.5]
+        );
+    }
+
+    #[test]
+    fn truncate_drops_the_samples_after_the_distance() {
+        let mut sampler = LapSampler::default();
+        sampler.update(&lap_data(1, 0), true);
+        for distance in [0.0, 10.0, 20.0, 30.0] {
+            sampler.push(distance, 1.0);
+        }
+        sampler.truncate(15.0);
+        sampler.push(12.0, 1.0);
+        assert_eq!(
+            sampler.current.as_ref().unwrap().samples,
+            vec![0.0, 10.0, 12.0]
+        );
+    }
+
+    #[test]
+    fn next_lap_completes_the_current_one() {
+        let mut sampler = LapSampler::default();
+        assert!(sampler.update(&lap_data(1, 0), true).is_none());
+        sampler.push(10.0, 1.0);
+        assert!(sampler.update(&lap_data(1, 0), false).is_none());
+        let finished = sampler.update(&lap_data(2, 90_000), true).unwrap();
+        assert_eq!(finished.lap_number, 1);
+        assert_eq!(finished.lap_time_in_ms, 90_000);
+        assert!(!finished.valid);
+        assert!(!finished.starts_at_line(5.0));
+        assert!(finished.starts_at_line(50.0));
+        let current = sampler.current.as_ref().unwrap();
+        assert_eq!(current.lap_number, 2);
+        assert!(current.valid && current.samples.is_empty());
+    }
+
+    #[test]
+    fn skipped_lap_or_restart_completes_nothing() {
+        let mut sampler = LapSampler::<f32>::default();
+        sampler.update(&lap_data(1, 0), true);
+        assert!(sampler.update(&lap_data(3, 0), true).is_none());
+        sampler.restart();
+        assert!(sampler.current.is_none());
+        assert!(sampler.update(&lap_data(4, 0), true).is_none());
+        assert_eq!(sampler.current.as_ref().unwrap().lap_number, 4);
+    }
+}
@@ -0,0 +1,225 @@
+//! Packet loss and ordering detection based on the frame identifier in the packet header
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::telemetry::PacketHeader;
+
+/// Statistics for a stream of packets of one type within one session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SequenceStats {
+    pub received: u64,     // Packets received
+    pub lost: u64,         // Estimated number of packets missing from gaps in the frame identifiers
+    pub duplicates: u64,   // Packets repeating the frame of the previous packet
+    pub out_of_order: u64, // Packets older than the previous packet
+    pub dropped: u64,      // Stale packets which were dropped
+}
+
+impl SequenceStats {
+    fn add(&mut self, other: &SequenceStats) {
+        self.received += other.received;
+        self.lost += other.lost;
+        self.duplicates += other.duplicates;
+        self.out_of_order += other.out_of_order;
+        self.dropped += other.dropped;
+    }
+}
+
+/// How a packet arrived relative to the previous packet of the same stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    InOrder,
+    Gap(u32), // Arrived after an estimated number of missing packets
+    Duplicate,
+    OutOfOrder,
+}
+
+impl Arrival {
+    /// Duplicate and out of order packets are stale, they would make time go backwards.
+    pub fn is_stale(&self) -> bool {
+        matches!(self, Arrival::Duplicate | Arrival::OutOfOrder)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    last_frame: Option<u32>,
+    last_session_time: f32,
+    stride: Option<u32>, // Smallest frame step seen, the game doesn't send every packet type each frame
+    stats: SequenceStats,
+}
+
+/// Tracks every (session_uid, packet_id) stream to detect gaps, duplicates and out of order
+/// packets. Flashbacks rewind the streams of a session so they aren't reported as out of order.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    drop_stale: bool,
+    streams: HashMap<(u64, u8), Stream>,
+}
+
+impl SequenceTracker {
+    pub fn new(drop_stale: bool) -> Self {
+        SequenceTracker {
+            drop_stale,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Records the packet and returns how it arrived.
+    pub fn track(&mut self, header: &PacketHeader) -> Arrival {
+        let stream = self
+            .streams
+            .entry((header.session_uid, header.packet_id))
+            .or_default();
+        stream.stats.received += 1;
+        let last_frame = match stream.last_frame {
+            Some(last_frame) => last_frame,
+            None if header.session_time < stream.last_session_time => {
+                stream.stats.out_of_order += 1;
+                return Arrival::OutOfOrder;
+            }
+            None => {
+                stream.last_frame = Some(header.frame_identifier);
+                stream.last_session_time = header.session_time;
+                return Arrival::InOrder;
+            }
+        };
+        if header.frame_identifier < last_frame || header.session_time < stream.last_session_time {
+            stream.stats.out_of_order += 1;
+            return Arrival::OutOfOrder;
+        }
+        if header.frame_identifier == last_frame {
+            stream.stats.duplicates += 1;
+            return Arrival::Duplicate;
+        }
+        let step = header.frame_identifier - last_frame;
+        let stride = stream.stride.map_or(step, |stride| stride.min(step));
+        stream.stride = Some(stride);
+        stream.last_frame = Some(header.frame_identifier);
+        stream.last_session_time = header.session_time;
+        let missing = (step / stride).saturating_sub(1);
+        if missing > 0 {
+            stream.stats.lost += missing as u64;
+            return Arrival::Gap(missing);
+        }
+        Arrival::InOrder
+    }
+
+    /// Records the packet and returns whether it should be passed on. Stale packets are only
+    /// rejected when the tracker was created to drop them.
+    pub fn accept(&mut self, header: &PacketHeader) -> bool {
+        let arrival = self.track(header);
+        if self.drop_stale && arrival.is_stale() {
+            if let Some(stream) = self
+                .streams
+                .get_mut(&(header.session_uid, header.packet_id))
+            {
+                stream.stats.dropped += 1;
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Rewinds every stream of the session to the point the game flashed back to. The next packet
+    /// of each stream is in order unless it is older than the point.
+    pub fn flashback(&mut self, session_uid: u64, session_time: f32) {
+        self.streams
+            .iter_mut()
+            .filter(|((uid, _), _)| *uid == session_uid)
+            .for_each(|(_, stream)| {
+                // The game may resume at the frame of the point, that isn't a duplicate
+                stream.last_frame = None;
+                stream.last_session_time = session_time;
+            });
+    }
+
+    /// Statistics of one stream.
+    pub fn stats(&self, session_uid: u64, packet_id: u8) -> Option<SequenceStats> {
+        self.streams
+            .get(&(session_uid, packet_id))
+            .map(|stream| stream.stats)
+    }
+
+    /// Statistics of every stream, keyed by (session_uid, packet_id).
+    pub fn all_stats(&self) -> HashMap<(u64, u8), SequenceStats> {
+        self.streams
+            .iter()
+            .map(|(key, stream)| (*key, stream.stats))
+            .collect()
+    }
+
+    /// Statistics summed over every stream.
+    pub fn total_stats(&self) -> SequenceStats {
+        let mut total = SequenceStats::default();
+        self.streams
+            .values()
+            .for_each(|stream| total.add(&stream.stats));
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(packet_id: u8, frame_identifier: u32, session_time: f32) -> PacketHeader {
+        PacketHeader {
+            packet_format: 2022,
+            game_major_version: 1,
+            game_minor_version: 0,
+            packet_version: 1,
+            packet_id,
+            session_uid: 1,
+            session_time,
+            frame_identifier,
+            player_car_index: 0,
+            secondary_player_car_index: 255,
+        }
+    }
+
+    #[test]
+    fn gaps_duplicates_and_out_of_order() {
+        let mut tracker = SequenceTracker::new(true);
+        assert_eq!(tracker.track(&header(0, 10, 1.0)), Arrival::InOrder);
+        assert_eq!(tracker.track(&header(0, 11, 1.1)), Arrival::InOrder);
+        assert_eq!(tracker.track(&header(0, 14, 1.4)), Arrival::Gap(2));
+        assert_eq!(tracker.track(&header(0, 14, 1.4)), Arrival::Duplicate);
+        assert!(!tracker.accept(&header(0, 12, 1.2)));
+        let stats = tracker.stats(1, 0).unwrap();
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.dropped, 1);
+    }
+
+    #[test]
+    fn streams_are_separate() {
+        let mut tracker = SequenceTracker::new(false);
+        tracker.track(&header(0, 10, 1.0));
+        assert_eq!(tracker.track(&header(2, 5, 0.5)), Arrival::InOrder);
+        assert_eq!(tracker.total_stats().received, 2);
+    }
+
+    #[test]
+    fn flashback_resumes_at_the_point() {
+        let mut tracker = SequenceTracker::new(true);
+        tracker.track(&header(0, 100, 10.0));
+        tracker.track(&header(0, 101, 10.1));
+        tracker.flashback(1, 5.0);
+        // The game resumes at the frame it flashed back to
+        assert!(tracker.accept(&header(0, 50, 5.0)));
+        assert_eq!(tracker.track(&header(0, 51, 5.1)), Arrival::InOrder);
+        assert_eq!(tracker.stats(1, 0).unwrap().duplicates, 0);
+    }
+
+    #[test]
+    fn flashback_drops_packets_from_before_the_point() {
+        let mut tracker = SequenceTracker::new(true);
+        tracker.track(&header(0, 100, 10.0));
+        tracker.flashback(1, 5.0);
+        assert!(!tracker.accept(&header(0, 40, 4.0)));
+        assert!(tracker.accept(&header(0, 50, 5.0)));
+    }
+}
@@ -0,0 +1,256 @@
+//! Session lifecycle detection and grouping of sessions into weekends and seasons
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    packet::{EventPacket, Packet},
+    telemetry::PacketSessionData,
+};
+
+/// A session seen in the data. The link identifiers are known once its session packet arrived.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SessionRecord {
+    pub session_uid: u64,
+    pub season_link_identifier: u32, // Identifier for season - persists across saves
+    pub weekend_link_identifier: u32, // Identifier for weekend - persists across saves
+    pub session_link_identifier: u32, // Identifier for session - persists across saves
+    pub session_type: u8,            // See `PacketSessionData::session_type`
+    pub track_id: i8,
+    pub start_time: f32, // Session time of the first packet
+    pub end_time: f32,   // Session time of the latest packet
+    pub ended: bool,
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EndReason {
+    Finished, // The game sent the session ended event
+    Replaced, // Packets of another session arrived, e.g. after a restart or returning to the menu
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SessionEvent {
+    SessionStarted(SessionRecord),
+    SessionEnded { session_uid: u64, reason: EndReason },
+}
+
+/// Sessions of a weekend, in the order they were seen.
+#[derive(Debug, Clone, Serialize)]
+pub struct Weekend {
+    pub weekend_link_identifier: u32,
+    pub track_id: i8,
+    pub sessions: Vec<SessionRecord>,
+}
+
+/// Weekends of a season, in the order they were seen.
+#[derive(Debug, Clone, Serialize)]
+pub struct Season {
+    pub season_link_identifier: u32,
+    pub weekends: Vec<Weekend>,
+}
+
+/// Detects sessions starting and ending from the session uid in the packet headers, the session
+/// started/ended events and the session packet. A session is reported as started on its session
+/// started event or session packet, whichever arrives first. The link identifiers come from the
+/// session packet, look the session up again if it was started by the event.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    current: Option<u64>,
+    started: Vec<u64>,
+    sessions: HashMap<u64, SessionRecord>,
+    order: Vec<u64>, // Session uids in the order they were seen
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        SessionTracker::default()
+    }
+
+    /// Updates the tracker with the packet, returning the sessions which started or ended.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        let header = packet.header();
+        let session_uid = header.session_uid;
+        if self.current != Some(session_uid) {
+            if let Some(previous) = self.current {
+                self.end(previous, EndReason::Replaced, &mut events);
+            }
+            self.current = Some(session_uid);
+            if !self.sessions.contains_key(&session_uid) {
+                self.order.push(session_uid);
+                self.sessions.insert(
+                    session_uid,
+                    SessionRecord {
+                        session_uid,
+                        start_time: header.session_time,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        if let Some(record) = self.sessions.get_mut(&session_uid) {
+            record.end_time = header.session_time;
+        }
+        match packet {
+            Packet::Session(pkt) => self.apply_session(pkt, &mut events),
+            Packet::Event(EventPacket::SessionStarted(_)) => self.start(session_uid, &mut events),
+            Packet::Event(EventPacket::SessionEnded(_)) => {
+                self.end(session_uid, EndReason::Finished, &mut events)
+            }
+            _ => {}
+        }
+        events
+    }
+
+    fn apply_session(&mut self, pkt: &PacketSessionData, events: &mut Vec<SessionEvent>) {
+        let session_uid = pkt.header.session_uid;
+        if let Some(record) = self.sessions.get_mut(&session_uid) {
+            record.season_link_identifier = pkt.season_link_identifier;
+            record.weekend_link_identifier = pkt.weekend_link_identifier;
+            record.session_link_identifier = pkt.session_link_identifier;
+            record.session_type = pkt.session_type;
+            record.track_id = pkt.track_id;
+        }
+        self.start(session_uid, events);
+    }
+
+    fn start(&mut self, session_uid: u64, events: &mut Vec<SessionEvent>) {
+        if self.started.contains(&session_uid) {
+            return;
+        }
+        if let Some(record) = self.sessions.get(&session_uid) {
+            self.started.push(session_uid);
+            events.push(SessionEvent::SessionStarted(record.clone()));
+        }
+    }
+
+    fn end(&mut self, session_uid: u64, reason: EndReason, events: &mut Vec<SessionEvent>) {
+        if let Some(record) = self.sessions.get_mut(&session_uid) {
+            if !record.ended {
+                record.ended = true;
+                events.push(SessionEvent::SessionEnded {
+                    session_uid,
+                    reason,
+                });
+            }
+        }
+    }
+
+    /// The session packets are currently being received for.
+    pub fn current(&self) -> Option<&SessionRecord> {
+        self.current.and_then(|uid| self.sessions.get(&uid))
+    }
+
+    pub fn session(&self, session_uid: u64) -> Option<&SessionRecord> {
+        self.sessions.get(&session_uid)
+    }
+
+    /// Every session seen, in the order they were seen.
+    pub fn sessions(&self) -> impl Iterator<Item = &SessionRecord> {
+        self.order.iter().filter_map(|uid| self.sessions.get(uid))
+    }
+
+    /// Groups the sessions into weekends and seasons using their link identifiers.
+    pub fn seasons(&self) -> Vec<Season> {
+        let mut seasons: Vec<Season> = Vec::new();
+        for record in self.sessions() {
+            let season = match seasons
+                .iter_mut()
+                .position(|s| s.season_link_identifier == record.season_link_identifier)
+            {
+                Some(idx) => &mut seasons[idx],
+                None => {
+                    seasons.push(Season {
+                        season_link_identifier: record.season_link_identifier,
+                        weekends: Vec::new(),
+                    });
+                    seasons.last_mut().unwrap()
+                }
+            };
+            let weekend = match season
+                .weekends
+                .iter_mut()
+                .position(|w| w.weekend_link_identifier == record.weekend_link_identifier)
+            {
+                Some(idx) => &mut season.weekends[idx],
+                None => {
+                    season.weekends.push(Weekend {
+                        weekend_link_identifier: record.weekend_link_identifier,
+                        track_id: record.track_id,
+                        sessions: Vec::new(),
+                    });
+                    season.weekends.last_mut().unwrap()
+                }
+            };
+            weekend.sessions.push(record.clone());
+        }
+        seasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    fn session(session_uid: u64, session_type: u8, weekend: u32) -> Packet {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 1.0);
+        pkt.season_link_identifier = 7;
+        pkt.weekend_link_identifier = weekend;
+        pkt.session_type = session_type;
+        Packet::Session(Box::new(pkt))
+    }
+
+    fn event(code: &str, session_uid: u64) -> Packet {
+        Packet::Event(testing::event(code, &[], session_uid, 0, 2.0))
+    }
+
+    #[test]
+    fn first_packet_starts_with_the_session_packet() {
+        let mut tracker = SessionTracker::new();
+        assert!(tracker.apply(&event("FTLP", 1)).is_empty());
+        let events = tracker.apply(&session(1, 10, 3));
+        assert!(matches!(
+            &events[..],
+            [SessionEvent::SessionStarted(record)] if record.session_type == 10
+        ));
+        assert!(tracker.apply(&session(1, 10, 3)).is_empty());
+        assert!(tracker.apply(&event("SSTA", 1)).is_empty());
+    }
+
+    #[test]
+    fn session_change_ends_the_previous_session() {
+        let mut tracker = SessionTracker::new();
+        tracker.apply(&event("SSTA", 1));
+        let events = tracker.apply(&session(2, 12, 3));
+        assert_eq!(
+            events[0],
+            SessionEvent::SessionEnded {
+                session_uid: 1,
+                reason: EndReason::Replaced
+            }
+        );
+        let events = tracker.apply(&event("SEND", 2));
+        assert_eq!(
+            events,
+            vec![SessionEvent::SessionEnded {
+                session_uid: 2,
+                reason: EndReason::Finished
+            }]
+        );
+        assert_eq!(tracker.sessions().count(), 2);
+    }
+
+    #[test]
+    fn groups_sessions_into_weekends() {
+        let mut tracker = SessionTracker::new();
+        tracker.apply(&session(1, 5, 3));
+        tracker.apply(&session(2, 10, 3));
+        tracker.apply(&session(3, 10, 4));
+        let seasons = tracker.seasons();
+        assert_eq!(seasons.len(), 1);
+        assert_eq!(seasons[0].weekends.len(), 2);
+        assert_eq!(seasons[0].weekends[0].sessions.len(), 2);
+    }
+}
@@ -0,0 +1,99 @@
+//! Creation and configuration of the UDP sockets the game data is received on
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use error_stack::{IntoReport, Result, ResultExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::errors::TelemetryError;
+
+/// Multicast group to join once the socket is bound.
+#[derive(Debug, Clone)]
+pub enum MulticastGroup {
+    V4 {
+        group: Ipv4Addr,     // Multicast group address
+        interface: Ipv4Addr, // Address of the local interface, 0.0.0.0 = any
+    },
+    V6 {
+        group: Ipv6Addr, // Multicast group address
+        interface: u32,  // Index of the local interface, 0 = any
+    },
+}
+
+/// Options applied to every socket before it is bound.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    pub recv_buffer_size: Option<usize>, // SO_RCVBUF size in bytes, None = OS default
+    pub reuse_address: bool,             // SO_REUSEADDR
+    pub reuse_port: bool,                // SO_REUSEPORT, ignored on platforms without it
+    pub broadcast: bool,                 // SO_BROADCAST, needed for the game's broadcast mode
+    pub multicast_groups: Vec<MulticastGroup>,
+}
+
+/// Binds a UDP socket to the endpoint with the given options applied.
+pub(crate) fn bind(
+    endpoint: SocketAddr,
+    options: &SocketOptions,
+) -> Result<UdpSocket, TelemetryError> {
+    let socket = Socket::new(
+        Domain::for_address(endpoint),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )
+    .report()
+    .change_context_lazy(|| TelemetryError)?;
+    if let Some(size) = options.recv_buffer_size {
+        socket
+            .set_recv_buffer_size(size)
+            .report()
+            .change_context_lazy(|| TelemetryError)?;
+    }
+    socket
+        .set_reuse_address(options.reuse_address)
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket
+        .set_broadcast(options.broadcast)
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    if endpoint.ip().is_ipv6() {
+        // Accept IPv4 mapped traffic too when bound to a v6 address
+        let _ = socket.set_only_v6(false);
+    }
+    socket
+        .bind(&endpoint.into())
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    for group in &options.multicast_groups {
+        match group {
+            MulticastGroup::V4 { group, interface } => socket.join_multicast_v4(group, interface),
+            MulticastGroup::V6 { group, interface } => socket.join_multicast_v6(group, *interface),
+        }
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    }
+    socket
+        .set_nonblocking(true)
+        .report()
+        .change_context_lazy(|| TelemetryError)?;
+    UdpSocket::from_std(socket.into())
+        .report()
+        .change_context_lazy(|| TelemetryError)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> Result<(), TelemetryError> {
+    socket
+        .set_reuse_port(true)
+        .report()
+        .change_context_lazy(|| TelemetryError)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> Result<(), TelemetryError> {
+    log::warn!("SO_REUSEPORT is not supported on this platform, ignoring");
+    Ok(())
+}
@@ -0,0 +1,386 @@
+//! Live view of the session, combining the per car data of every packet type
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{
+        CarDamageData, CarMotionData, CarStatusData, CarTelemetryData, LapData, MarshalZone,
+        PacketHeader, PacketLapData, PacketSessionData, ParticipantData, MAX_CARS,
+    },
+    timing::{CarGap, Timing},
+};
+
+/// Session wide state, taken from the packet headers and the session packet.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionInfo {
+    pub session_uid: u64,
+    pub session_time: f32,     // Session time of the latest packet
+    pub frame_identifier: u32, // Frame identifier of the latest packet
+    pub player_car_index: u8,
+    pub weather: u8, // Weather - 0 = clear, 1 = light cloud, 2 = overcast
+    // 3 = light rain, 4 = heavy rain, 5 = storm
+    pub track_temperature: i8,
+    pub air_temperature: i8,
+    pub total_laps: u8,
+    pub track_length: u16,
+    pub session_type: u8,
+    pub track_id: i8,
+    pub session_time_left: u16,
+    pub safety_car_status: u8, // 0 = no safety car, 1 = full, 2 = virtual, 3 = formation lap
+    pub marshal_zones: Vec<MarshalZone>,
+    pub num_active_cars: u8,
+}
+
+/// Latest data received for one car. None until the packet type has been received.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CarState {
+    pub participant: Option<ParticipantData>,
+    pub lap: Option<LapData>,
+    pub motion: Option<CarMotionData>,
+    pub telemetry: Option<CarTelemetryData>,
+    pub status: Option<CarStatusData>,
+    pub damage: Option<CarDamageData>,
+}
+
+/// Notification of a change in the session state.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum StateChange {
+    SessionReset {
+        session_uid: u64,
+    },
+    WeatherChanged {
+        from: u8,
+        to: u8,
+    },
+    SafetyCarChanged {
+        from: u8,
+        to: u8,
+    },
+    MarshalZoneFlagChanged {
+        zone: usize,
+        from: i8,
+        to: i8,
+    },
+    PositionChanged {
+        car_index: usize,
+        from: u8,
+        to: u8,
+    },
+    LapStarted {
+        car_index: usize,
+        lap: u8,
+    },
+    PitStatusChanged {
+        car_index: usize,
+        from: u8,
+        to: u8,
+    },
+    ResultStatusChanged {
+        car_index: usize,
+        from: u8,
+        to: u8,
+    },
+    Flashback {
+        frame_identifier: u32,
+        session_time: f32,
+    },
+}
+
+/// Current state of the session and every car in it. Feed it every packet with
+/// [`SessionState::apply`], it can be queried or serialized as a snapshot at any time.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionState {
+    session: SessionInfo,
+    cars: Vec<CarState>,
+    timing: Timing,
+    #[serde(skip)]
+    session_received: bool, // Whether a session packet has been applied
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        SessionState {
+            session: SessionInfo::default(),
+            cars: vec![CarState::default(); MAX_CARS],
+            timing: Timing::default(),
+            session_received: false,
+        }
+    }
+}
+
+impl SessionState {
+    pub fn new() -> Self {
+        SessionState::default()
+    }
+
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    pub fn cars(&self) -> &[CarState] {
+        &self.cars
+    }
+
+    pub fn car(&self, car_index: usize) -> Option<&CarState> {
+        self.cars.get(car_index)
+    }
+
+    pub fn player_car(&self) -> Option<&CarState> {
+        self.car(self.session.player_car_index as usize)
+    }
+
+    /// Gap to the leader and interval to the car ahead, updated with every lap data packet.
+    pub fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    pub fn gap(&self, car_index: usize) -> Option<&CarGap> {
+        self.timing.gap(car_index)
+    }
+
+    /// Updates the state with the packet, returning the changes it caused.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+        self.apply_header(packet.header(), &mut changes);
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+            changes.push(StateChange::Flashback {
+                frame_identifier: point.frame_identifier,
+                session_time: point.session_time,
+            });
+        }
+        match packet {
+            Packet::Motion(pkt) => self
+                .cars
+                .iter_mut()
+                .zip(pkt.car_motion_data.iter())
+                .for_each(|(car, motion)| car.motion = Some(motion.clone())),
+            Packet::Session(pkt) => self.apply_session(pkt, &mut changes),
+            Packet::LapData(pkt) => self.apply_lap_data(pkt, &mut changes),
+            Packet::Participants(pkt) => {
+                self.session.num_active_cars = pkt.num_active_cars;
+                self.cars
+                    .iter_mut()
+                    .zip(pkt.participants.iter())
+                    .enumerate()
+                    .for_each(|(idx, (car, participant))| {
+                        car.participant =
+                            (idx < pkt.num_active_cars as usize).then(|| participant.clone())
+                    });
+            }
+            Packet::CarTelemetry(pkt) => self
+                .cars
+                .iter_mut()
+                .zip(pkt.car_telemetry_data.iter())
+                .for_each(|(car, telemetry)| car.telemetry = Some(telemetry.clone())),
+            Packet::CarStatus(pkt) => self
+                .cars
+                .iter_mut()
+                .zip(pkt.car_status_data.iter())
+                .for_each(|(car, status)| car.status = Some(status.clone())),
+            Packet::CarDamage(pkt) => self
+                .cars
+                .iter_mut()
+                .zip(pkt.car_damage_data.iter())
+                .for_each(|(car, damage)| car.damage = Some(damage.clone())),
+            _ => {}
+        }
+        changes
+    }
+
+    fn apply_header(&mut self, header: &PacketHeader, changes: &mut Vec<StateChange>) {
+        if header.session_uid != self.session.session_uid {
+            *self = SessionState::default();
+            self.session.session_uid = header.session_uid;
+            changes.push(StateChange::SessionReset {
+                session_uid: header.session_uid,
+            });
+        }
+        self.session.session_time = header.session_time;
+        self.session.frame_identifier = header.frame_identifier;
+        self.session.player_car_index = header.player_car_index;
+    }
+
+    fn apply_session(&mut self, pkt: &PacketSessionData, changes: &mut Vec<StateChange>) {
+        let zones =
+            &pkt.marshal_zones[..(pkt.num_marshal_zones as usize).min(pkt.marshal_zones.len())];
+        if self.session_received {
+            self.session_changes(pkt, zones, changes);
+        }
+        self.session_received = true;
+        let session = &mut self.session;
+        session.weather = pkt.weather;
+        session.track_temperature = pkt.track_temperature;
+        session.air_temperature = pkt.air_temperature;
+        session.total_laps = pkt.total_laps;
+        session.track_length = pkt.track_length;
+        session.session_type = pkt.session_type;
+        session.track_id = pkt.track_id;
+        session.session_time_left = pkt.session_time_left;
+        session.safety_car_status = pkt.safety_car_status;
+        session.marshal_zones = zones.to_vec();
+        self.timing.set_track_length(pkt.track_length);
+    }
+
+    fn session_changes(
+        &self,
+        pkt: &PacketSessionData,
+        zones: &[MarshalZone],
+        changes: &mut Vec<StateChange>,
+    ) {
+        let session = &self.session;
+        if session.weather != pkt.weather {
+            changes.push(StateChange::WeatherChanged {
+                from: session.weather,
+                to: pkt.weather,
+            });
+        }
+        if session.safety_car_status != pkt.safety_car_status {
+            changes.push(StateChange::SafetyCarChanged {
+                from: session.safety_car_status,
+                to: pkt.safety_car_status,
+            });
+        }
+        for (zone, new) in zones.iter().enumerate() {
+            let from = session
+                .marshal_zones
+                .get(zone)
+                .map_or(0, |old| old.zone_flag);
+            if from != new.zone_flag {
+                changes.push(StateChange::MarshalZoneFlagChanged {
+                    zone,
+                    from,
+                    to: new.zone_flag,
+                });
+            }
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData, changes: &mut Vec<StateChange>) {
+        for (car_index, (car, new)) in self.cars.iter_mut().zip(pkt.lap_data.iter()).enumerate() {
+            if let Some(old) = &car.lap {
+                if old.car_position != new.car_position {
+                    changes.push(StateChange::PositionChanged {
+                        car_index,
+                        from: old.car_position,
+                        to: new.car_position,
+                    });
+                }
+                if old.current_lap_num != new.current_lap_num {
+                    changes.push(StateChange::LapStarted {
+                        car_index,
+                        lap: new.current_lap_num,
+                    });
+                }
+                if old.pit_status != new.pit_status {
+                    changes.push(StateChange::PitStatusChanged {
+                        car_index,
+                        from: old.pit_status,
+                        to: new.pit_status,
+                    });
+                }
+                if old.result_status != new.result_status {
+                    changes.push(StateChange::ResultStatusChanged {
+                        car_index,
+                        from: old.result_status,
+                        to: new.result_status,
+                    });
+                }
+            }
+            car.lap = Some(new.clone());
+        }
+        self.timing.update(pkt);
+    }
+}
+
+impl Rewind for SessionState {
+    /// The per car data is replaced by the packets sent after the flashback, only the session
+    /// clock and the timing traces are moved back.
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session.session_uid {
+            return;
+        }
+        self.session.session_time = point.session_time;
+        self.session.frame_identifier = point.frame_identifier;
+        self.timing.rewind(point);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    fn lap_data(session_uid: u64, session_time: f32, position: u8, lap: u8) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        pkt.lap_data[3].car_position = position;
+        pkt.lap_data[3].current_lap_num = lap;
+        Packet::LapData(Box::new(pkt))
+    }
+
+    fn session(session_uid: u64, weather: u8) -> Packet {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 1.0);
+        pkt.weather = weather;
+        Packet::Session(Box::new(pkt))
+    }
+
+    #[test]
+    fn first_packet_reports_no_changes() {
+        let mut state = SessionState::new();
+        let changes = state.apply(&lap_data(1, 1.0, 5, 1));
+        assert_eq!(changes, vec![StateChange::SessionReset { session_uid: 1 }]);
+        assert!(state.apply(&session(1, 3)).is_empty());
+        assert_eq!(state.session().weather, 3);
+        assert_eq!(state.car(3).unwrap().lap.as_ref().unwrap().car_position, 5);
+    }
+
+    #[test]
+    fn reports_lap_and_position_changes() {
+        let mut state = SessionState::new();
+        state.apply(&lap_data(1, 1.0, 5, 1));
+        let changes = state.apply(&lap_data(1, 2.0, 4, 2));
+        assert!(changes.contains(&StateChange::PositionChanged {
+            car_index: 3,
+            from: 5,
+            to: 4
+        }));
+        assert!(changes.contains(&StateChange::LapStarted {
+            car_index: 3,
+            lap: 2
+        }));
+        state.apply(&session(1, 0));
+        let changes = state.apply(&session(1, 3));
+        assert_eq!(
+            changes,
+            vec![StateChange::WeatherChanged { from: 0, to: 3 }]
+        );
+    }
+
+    #[test]
+    fn new_session_resets_the_state() {
+        let mut state = SessionState::new();
+        state.apply(&session(1, 3));
+        state.apply(&lap_data(1, 1.0, 5, 1));
+        let changes = state.apply(&lap_data(2, 1.0, 2, 1));
+        assert_eq!(changes, vec![StateChange::SessionReset { session_uid: 2 }]);
+        assert_eq!(state.session().weather, 0);
+    }
+
+    #[test]
+    fn flashback_moves_the_clock_back() {
+        let mut state = SessionState::new();
+        state.apply(&lap_data(1, 10.0, 5, 1));
+        let changes = state.apply(&testing::flashback(1, 1000, 10.0, 400, 4.0));
+        assert_eq!(
+            changes,
+            vec![StateChange::Flashback {
+                frame_identifier: 400,
+                session_time: 4.0
+            }]
+        );
+        assert_eq!(state.session().session_time, 4.0);
+        assert_eq!(state.session().frame_identifier, 400);
+    }
+}
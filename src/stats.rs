@@ -0,0 +1,39 @@
+//! Small numeric helpers shared by the analysis modules
+
+/// Least squares fit of a straight line, returns (slope, intercept). Needs at least two points
+/// with different x values.
+pub(crate) fn linear_fit(points: &[(f32, f32)]) -> Option<(f32, f32)> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| *x as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| *y as f64).sum::<f64>() / n;
+    let (cov, var) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+        let dx = *x as f64 - mean_x;
+        (cov + dx * (*y as f64 - mean_y), var + dx * dx)
+    });
+    if var == 0.0 {
+        return None;
+    }
+    let slope = cov / var;
+    Some((slope as f32, (mean_y - slope * mean_x) as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_a_line() {
+        let (slope, intercept) = linear_fit(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]).unwrap();
+        assert!((slope - 2.0).abs() < 1e-6);
+        assert!((intercept - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn needs_two_distinct_x_values() {
+        assert_eq!(linear_fit(&[(1.0, 1.0)]), None);
+        assert_eq!(linear_fit(&[(1.0, 1.0), (1.0, 2.0)]), None);
+    }
+}
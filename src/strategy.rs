@@ -0,0 +1,831 @@
+//! Pit strategy, rejoin prediction and undercut/overcut estimates
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{LapData, PacketLapData, MAX_CARS},
+    timing::Timing,
+    tyres::{DegradationModel, Stint, StintTracker},
+};
+
+/// Time lost to a pit stop in seconds, until one has been measured.
+const DEFAULT_PIT_LOSS: f32 = 22.0;
+/// Wear of the most worn tyre (percentage) a stint may run to.
+const DEFAULT_WEAR_LIMIT: f32 = 70.0;
+
+/// A pit stop measured from the pit lane timer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PitStop {
+    pub car_index: usize,
+    pub lap_number: u8,
+    pub time_in_lane_in_ms: u16,
+    pub lane_distance: f32, // Metres driven while the pit lane timer was running
+    pub loss: Option<f32>, // Seconds lost against driving the distance at lap pace, None if unknown
+    pub session_time: f32, // Session time the car left the pit lane at
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PlannedStop {
+    pub lap_number: u8,      // Lap the car pits at the end of
+    pub actual_compound: u8, // Compound fitted, see `CarStatusData::actual_tyre_compound`
+}
+
+/// A way to the finish of the race.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StrategyPlan {
+    pub car_index: usize,
+    pub stops: Vec<PlannedStop>,
+    pub race_time: f32,          // Seconds to the finish, including the stops
+    pub delta: f32,              // Seconds slower than the best plan
+    pub in_window: Option<bool>, // First stop is in the game's pit window, player car only
+}
+
+/// Where a car pitting now would rejoin.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Rejoin {
+    pub car_index: usize,
+    pub position: u8,
+    pub gap_ahead: Option<f32>,    // Seconds to the car it rejoins behind
+    pub gap_behind: Option<f32>,   // Seconds to the car it rejoins ahead of
+    pub game_position: Option<u8>, // `PacketSessionData::pit_stop_rejoin_position`, player car only
+}
+
+/// Predicted outcome of pitting at a different time to a car ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CutPrediction {
+    pub car_index: usize,
+    pub target_index: usize,
+    pub laps: u8,       // Laps between the two stops
+    pub gap: f32,       // Seconds behind the target now
+    pub gain: f32,      // Seconds gained on the target between the two stops
+    pub gap_after: f32, // Seconds behind the target once both have stopped, negative when ahead
+    pub succeeds: bool,
+}
+
+/// A car in the pit lane.
+#[derive(Debug, Clone, Copy)]
+struct PitLane {
+    time_in_lane_in_ms: u16,
+    entry_distance: f32, // Total distance when the timer started
+    distance: f32,       // Total distance when the timer was last seen running
+}
+
+/// Tyres and race progress of a car, the starting point of its strategy.
+struct CarPlanState {
+    current_lap: u8,
+    laps_left: u8,
+    actual_compound: u8,
+    tyres_age_laps: u8,
+    tyres_wear: f32, // Most worn tyre
+    used: Vec<u8>,   // Compounds used so far
+}
+
+/// Combines the tyre degradation of every compound, the measured pit lane time, the game's pit
+/// window and the live gaps to predict where a car would rejoin after a stop, whether an undercut
+/// or overcut works, and to rank the ways to the finish of the race.
+#[derive(Debug, Clone)]
+pub struct StrategyEngine {
+    session_uid: u64,
+    stints: StintTracker,
+    timing: Timing,
+    player_car_index: usize,
+    total_laps: u8,
+    track_length: u16,
+    pit_stop_window_ideal_lap: u8,
+    pit_stop_window_latest_lap: u8,
+    pit_stop_rejoin_position: u8,
+    laps: Vec<Option<LapData>>,
+    pit_lanes: Vec<Option<PitLane>>, // While the pit lane timer is running
+    pit_stops: Vec<PitStop>,
+    default_pit_loss: f32,
+    wear_limit: f32,
+    require_compound_change: bool,
+}
+
+impl Default for StrategyEngine {
+    fn default() -> Self {
+        StrategyEngine {
+            session_uid: 0,
+            stints: StintTracker::default(),
+            timing: Timing::default(),
+            player_car_index: 0,
+            total_laps: 0,
+            track_length: 0,
+            pit_stop_window_ideal_lap: 0,
+            pit_stop_window_latest_lap: 0,
+            pit_stop_rejoin_position: 0,
+            laps: vec![None; MAX_CARS],
+            pit_lanes: vec![None; MAX_CARS],
+            pit_stops: Vec::new(),
+            default_pit_loss: DEFAULT_PIT_LOSS,
+            wear_limit: DEFAULT_WEAR_LIMIT,
+            require_compound_change: true,
+        }
+    }
+}
+
+impl StrategyEngine {
+    pub fn new() -> Self {
+        StrategyEngine::default()
+    }
+
+    /// Seconds lost to a pit stop until one has been measured. Defaults to 22 seconds.
+    pub fn pit_loss(mut self, seconds: f32) -> Self {
+        self.default_pit_loss = seconds;
+        self
+    }
+
+    /// Wear of the most worn tyre (percentage) a stint may run to. Defaults to 70%.
+    pub fn wear_limit(mut self, wear: f32) -> Self {
+        self.wear_limit = wear;
+        self
+    }
+
+    /// Whether a plan must use two different compounds, unless intermediates or wets are used.
+    /// Defaults to true, as in a dry race.
+    pub fn require_compound_change(mut self, require: bool) -> Self {
+        self.require_compound_change = require;
+        self
+    }
+
+    /// Stints and degradation the strategy is based on.
+    pub fn stints(&self) -> &StintTracker {
+        &self.stints
+    }
+
+    /// Pit stops measured so far, oldest first.
+    pub fn pit_stops(&self) -> &[PitStop] {
+        &self.pit_stops
+    }
+
+    /// Seconds lost to a pit stop, the average of the stops measured so far. The loss of a stop is
+    /// its time in the pit lane less the time to drive the same distance at lap pace.
+    pub fn measured_pit_loss(&self) -> f32 {
+        let losses: Vec<f32> = self.pit_stops.iter().filter_map(|stop| stop.loss).collect();
+        if losses.is_empty() {
+            return self.default_pit_loss;
+        }
+        losses.iter().sum::<f32>() / losses.len() as f32
+    }
+
+    /// Seconds per metre at the average of the car's clean laps, or of every car's if it has none.
+    fn lap_pace(&self, car_index: usize) -> Option<f32> {
+        if self.track_length == 0 {
+            return None;
+        }
+        let clean = |stints: &[Stint]| -> Vec<u32> {
+            stints
+                .iter()
+                .flat_map(|stint| stint.laps.iter())
+                .filter(|lap| lap.valid && !lap.pit_lap && lap.lap_time_in_ms > 0)
+                .map(|lap| lap.lap_time_in_ms)
+                .collect()
+        };
+        let mut lap_times = clean(self.stints.stints(car_index));
+        if lap_times.is_empty() {
+            lap_times = (0..MAX_CARS)
+                .flat_map(|car_index| clean(self.stints.stints(car_index)))
+                .collect();
+        }
+        if lap_times.is_empty() {
+            return None;
+        }
+        let average =
+            lap_times.iter().map(|time| *time as f32).sum::<f32>() / lap_times.len() as f32;
+        Some(average / 1000.0 / self.track_length as f32)
+    }
+
+    /// Updates the strategy with the packet, returning the pit stops completed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<PitStop> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = StrategyEngine {
+                default_pit_loss: self.default_pit_loss,
+                wear_limit: self.wear_limit,
+                require_compound_change: self.require_compound_change,
+                ..StrategyEngine::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            // The stint tracker rewinds itself as it applies the packet
+            self.timing.rewind(&point);
+            self.rewind_strategy(&point);
+        }
+        self.player_car_index = header.player_car_index as usize;
+        self.stints.apply(packet);
+        match packet {
+            Packet::Session(pkt) => {
+                self.total_laps = pkt.total_laps;
+                self.track_length = pkt.track_length;
+                self.pit_stop_window_ideal_lap = pkt.pit_stop_window_ideal_lap;
+                self.pit_stop_window_latest_lap = pkt.pit_stop_window_latest_lap;
+                self.pit_stop_rejoin_position = pkt.pit_stop_rejoin_position;
+                self.timing.set_track_length(pkt.track_length);
+                Vec::new()
+            }
+            Packet::LapData(pkt) => {
+                self.timing.update(pkt);
+                self.apply_lap_data(pkt)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> Vec<PitStop> {
+        let mut stops = Vec::new();
+        for (car_index, lap) in pkt.lap_data.iter().enumerate() {
+            if lap.pit_lane_timer_active == 1 {
+                let pit_lane = self.pit_lanes[car_index].get_or_insert(PitLane {
+                    time_in_lane_in_ms: 0,
+                    entry_distance: lap.total_distance,
+                    distance: lap.total_distance,
+                });
+                pit_lane.time_in_lane_in_ms = lap.pit_lane_time_in_lane_in_ms;
+                pit_lane.distance = lap.total_distance;
+            } else if let Some(pit_lane) = self.pit_lanes[car_index].take() {
+                if pit_lane.time_in_lane_in_ms == 0 {
+                    continue;
+                }
+                let lane_distance = (pit_lane.distance - pit_lane.entry_distance).max(0.0);
+                let time_in_lane = pit_lane.time_in_lane_in_ms as f32 / 1000.0;
+                let stop = PitStop {
+                    car_index,
+                    lap_number: lap.current_lap_num,
+                    time_in_lane_in_ms: pit_lane.time_in_lane_in_ms,
+                    lane_distance,
+                    loss: self
+                        .lap_pace(car_index)
+                        .map(|pace| time_in_lane - lane_distance * pace),
+                    session_time: pkt.header.session_time,
+                };
+                self.pit_stops.push(stop.clone());
+                stops.push(stop);
+            }
+            self.laps[car_index] = Some(lap.clone());
+        }
+        stops
+    }
+
+    /// Seconds the car is behind the leader, zero for the leader, None if it can't be timed yet.
+    fn gap_to_leader(&self, car_index: usize) -> Option<f32> {
+        match self.timing.gaps().first() {
+            Some(leader) if leader.car_index == car_index => Some(0.0),
+            _ => self.timing.gap(car_index)?.gap_to_leader,
+        }
+    }
+
+    /// Predicts where the car would rejoin if it pitted now, None if it can't be timed yet. Cars
+    /// which can't be timed are left out of the prediction.
+    pub fn rejoin(&self, car_index: usize) -> Option<Rejoin> {
+        let rejoin_gap = self.gap_to_leader(car_index)? + self.measured_pit_loss();
+        let others: Vec<f32> = self
+            .timing
+            .gaps()
+            .iter()
+            .filter(|gap| gap.car_index != car_index)
+            .filter_map(|gap| self.gap_to_leader(gap.car_index))
+            .collect();
+        let ahead = others.iter().copied().filter(|gap| *gap <= rejoin_gap);
+        let behind = others.iter().copied().filter(|gap| *gap > rejoin_gap);
+        let game_position = (car_index == self.player_car_index
+            && self.pit_stop_rejoin_position != 0)
+            .then_some(self.pit_stop_rejoin_position);
+        Some(Rejoin {
+            car_index,
+            position: ahead.clone().count() as u8 + 1,
+            gap_ahead: ahead.reduce(f32::max).map(|gap| rejoin_gap - gap),
+            gap_behind: behind.reduce(f32::min).map(|gap| gap - rejoin_gap),
+            game_position,
+        })
+    }
+
+    /// Predicts whether the car gets ahead of the target by pitting now onto the compound, with
+    /// the target pitting the number of laps later. None if either car can't be timed yet.
+    pub fn undercut(
+        &self,
+        car_index: usize,
+        target_index: usize,
+        actual_compound: u8,
+        laps: u8,
+    ) -> Option<CutPrediction> {
+        let target = self.plan_state(target_index)?;
+        let fresh = self.model(car_index, actual_compound)?;
+        let old = self.model(target_index, target.actual_compound)?;
+        let gain = stint_time(&old, target.tyres_age_laps, laps)? - stint_time(&fresh, 0, laps)?;
+        self.cut(car_index, target_index, laps, gain)
+    }
+
+    /// Predicts whether the car gets ahead of the target by staying out the number of laps after
+    /// the target pits onto the compound. None if either car can't be timed yet.
+    pub fn overcut(
+        &self,
+        car_index: usize,
+        target_index: usize,
+        actual_compound: u8,
+        laps: u8,
+    ) -> Option<CutPrediction> {
+        let car = self.plan_state(car_index)?;
+        let old = self.model(car_index, car.actual_compound)?;
+        let fresh = self.model(target_index, actual_compound)?;
+        let gain = stint_time(&fresh, 0, laps)? - stint_time(&old, car.tyres_age_laps, laps)?;
+        self.cut(car_index, target_index, laps, gain)
+    }
+
+    fn cut(
+        &self,
+        car_index: usize,
+        target_index: usize,
+        laps: u8,
+        gain: f32,
+    ) -> Option<CutPrediction> {
+        let gap = self.gap_to_leader(car_index)? - self.gap_to_leader(target_index)?;
+        Some(CutPrediction {
+            car_index,
+            target_index,
+            laps,
+            gap,
+            gain,
+            gap_after: gap - gain,
+            succeeds: gap - gain < 0.0,
+        })
+    }
+
+    /// Ranks the ways to the finish of the race with no, one or two stops, fastest first. Only
+    /// compounds with a lap time model are considered, the best stop laps are picked for each
+    /// sequence of compounds.
+    pub fn recommendations(&self, car_index: usize) -> Vec<StrategyPlan> {
+        let state = match self.plan_state(car_index) {
+            Some(state) => state,
+            None => return Vec::new(),
+        };
+        let current = match self.model(car_index, state.actual_compound) {
+            Some(model) => model,
+            None => return Vec::new(),
+        };
+        let compounds: Vec<(u8, DegradationModel)> = self
+            .stints
+            .compound_models()
+            .into_keys()
+            .filter_map(|compound| Some((compound, self.model(car_index, compound)?)))
+            .collect();
+        let pit_loss = self.measured_pit_loss();
+        let laps_left = state.laps_left;
+
+        // Best plan for each sequence of compounds
+        let mut best: HashMap<Vec<u8>, (f32, Vec<PlannedStop>)> = HashMap::new();
+        let mut consider = |time: f32, stops: Vec<PlannedStop>| {
+            let key: Vec<u8> = stops.iter().map(|stop| stop.actual_compound).collect();
+            if !self.compounds_allowed(&state.used, &key) {
+                return;
+            }
+            match best.get(&key) {
+                Some((best_time, _)) if *best_time <= time => {}
+                _ => {
+                    best.insert(key, (time, stops));
+                }
+            }
+        };
+        let stop = |laps_driven: u8, actual_compound: u8| PlannedStop {
+            lap_number: state.current_lap + laps_driven - 1,
+            actual_compound,
+        };
+
+        if self.fits(&current, state.tyres_wear, laps_left) {
+            if let Some(time) = stint_time(&current, state.tyres_age_laps, laps_left) {
+                consider(time, Vec::new());
+            }
+        }
+        for first_laps in 1..laps_left {
+            if !self.fits(&current, state.tyres_wear, first_laps) {
+                break;
+            }
+            let first = match stint_time(&current, state.tyres_age_laps, first_laps) {
+                Some(time) => time + pit_loss,
+                None => break,
+            };
+            for (second_compound, second) in compounds.iter() {
+                let rest = laps_left - first_laps;
+                let new_wear = second.wear_at(0.0);
+                if self.fits(second, new_wear, rest) {
+                    if let Some(time) = stint_time(second, 0, rest) {
+                        consider(first + time, vec![stop(first_laps, *second_compound)]);
+                    }
+                }
+                for second_laps in 1..rest {
+                    if !self.fits(second, new_wear, second_laps) {
+                        break;
+                    }
+                    let second_time = match stint_time(second, 0, second_laps) {
+                        Some(time) => first + time + pit_loss,
+                        None => break,
+                    };
+                    let third_laps = rest - second_laps;
+                    for (third_compound, third) in compounds.iter() {
+                        if !self.fits(third, third.wear_at(0.0), third_laps) {
+                            continue;
+                        }
+                        if let Some(time) = stint_time(third, 0, third_laps) {
+                            consider(
+                                second_time + time,
+                                vec![
+                                    stop(first_laps, *second_compound),
+                                    stop(first_laps + second_laps, *third_compound),
+                                ],
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut plans: Vec<StrategyPlan> = best
+            .into_values()
+            .map(|(race_time, stops)| StrategyPlan {
+                car_index,
+                in_window: self.in_window(car_index, &stops),
+                stops,
+                race_time,
+                delta: 0.0,
+            })
+            .collect();
+        plans.sort_by(|a, b| a.race_time.total_cmp(&b.race_time));
+        let fastest = plans.first().map_or(0.0, |plan| plan.race_time);
+        plans
+            .iter_mut()
+            .for_each(|plan| plan.delta = plan.race_time - fastest);
+        plans
+    }
+
+    fn plan_state(&self, car_index: usize) -> Option<CarPlanState> {
+        let lap = self.laps.get(car_index)?.as_ref()?;
+        let stint = self.stints.current_stint(car_index)?;
+        if self.total_laps == 0 || lap.current_lap_num > self.total_laps {
+            return None;
+        }
+        Some(CarPlanState {
+            current_lap: lap.current_lap_num,
+            laps_left: self.total_laps - lap.current_lap_num + 1,
+            actual_compound: stint.actual_compound,
+            tyres_age_laps: stint.start_age_laps.saturating_add(stint.laps.len() as u8),
+            tyres_wear: self
+                .stints
+                .tyres_wear(car_index)
+                .map_or(0.0, |wear| wear.iter().copied().fold(0.0, f32::max)),
+            used: self
+                .stints
+                .stints(car_index)
+                .iter()
+                .map(|stint| stint.actual_compound)
+                .collect(),
+        })
+    }
+
+    /// Degradation of the compound on the car, from its own stints if it has lap times on the
+    /// compound, otherwise from every car's.
+    fn model(&self, car_index: usize, actual_compound: u8) -> Option<DegradationModel> {
+        self.stints
+            .car_model(car_index, actual_compound)
+            .filter(|model| model.lap_time_at_fit.is_some())
+            .or_else(|| self.stints.compound_model(actual_compound))
+            .filter(|model| model.lap_time_at_fit.is_some())
+    }
+
+    /// Whether a stint of the number of laps stays under the wear limit.
+    fn fits(&self, model: &DegradationModel, start_wear: f32, laps: u8) -> bool {
+        match model.laps_until(start_wear, self.wear_limit) {
+            Some(limit) => laps as f32 <= limit,
+            None => true,
+        }
+    }
+
+    fn compounds_allowed(&self, used: &[u8], planned: &[u8]) -> bool {
+        if !self.require_compound_change {
+            return true;
+        }
+        let mut all: Vec<u8> = used.iter().chain(planned).copied().collect();
+        // Intermediates and wets, F1 classic and F2 wets
+        if all.iter().any(|c| matches!(c, 7 | 8 | 10 | 15)) {
+            return true;
+        }
+        all.sort_unstable();
+        all.dedup();
+        all.len() >= 2
+    }
+
+    fn in_window(&self, car_index: usize, stops: &[PlannedStop]) -> Option<bool> {
+        if car_index != self.player_car_index || self.pit_stop_window_latest_lap == 0 {
+            return None;
+        }
+        Some(stops.first().is_some_and(|stop| {
+            stop.lap_number >= self.pit_stop_window_ideal_lap
+                && stop.lap_number <= self.pit_stop_window_latest_lap
+        }))
+    }
+
+    fn rewind_strategy(&mut self, point: &FlashbackPoint) {
+        self.pit_stops
+            .retain(|stop| !point.supersedes(point.session_uid, stop.session_time));
+        self.pit_lanes
+            .iter_mut()
+            .for_each(|pit_lane| *pit_lane = None);
+    }
+}
+
+impl Rewind for StrategyEngine {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.stints.rewind(point);
+        self.timing.rewind(point);
+        self.rewind_strategy(point);
+    }
+}
+
+/// Seconds to drive the number of laps on tyres starting at the age.
+fn stint_time(model: &DegradationModel, start_age_laps: u8, laps: u8) -> Option<f32> {
+    let (n, age) = (laps as f32, start_age_laps as f32);
+    let first = model.lap_time_at(age + 1.0)?;
+    let per_lap = model.lap_time_per_lap?;
+    // Sum of a linear lap time over the laps
+    Some((n * first + per_lap * n * (n - 1.0) / 2.0) / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarDamageData, PacketCarStatusData, PacketSessionData},
+        testing,
+    };
+
+    const TRACK_LENGTH: f32 = 5000.0;
+    const SOFT: u8 = 16;
+    const MEDIUM: u8 = 17;
+
+    fn session(session_uid: u64) -> Packet {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 0.0);
+        pkt.total_laps = 20;
+        pkt.track_length = TRACK_LENGTH as u16;
+        Packet::Session(Box::new(pkt))
+    }
+
+    fn car_status(session_uid: u64, session_time: f32, age: u8) -> Packet {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        pkt.car_status_data[0].actual_tyre_compound = 16;
+        pkt.car_status_data[0].visual_tyre_compound = 16;
+        pkt.car_status_data[0].tyres_age_laps = age;
+        Packet::CarStatus(Box::new(pkt))
+    }
+
+    /// Lap data of car 0, in the pit lane for `in_lane` seconds when given.
+    fn lap_data(
+        session_uid: u64,
+        session_time: f32,
+        total_distance: f32,
+        in_lane: Option<f32>,
+    ) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        let lap = &mut pkt.lap_data[0];
+        lap.total_distance = total_distance;
+        lap.current_lap_num = (total_distance / TRACK_LENGTH) as u8 + 1;
+        lap.lap_distance = total_distance % TRACK_LENGTH;
+        lap.last_lap_time_in_ms = 90_000;
+        lap.car_position = 1;
+        lap.result_status = 2;
+        if let Some(in_lane) = in_lane {
+            lap.pit_status = 1;
+            lap.pit_lane_timer_active = 1;
+            lap.pit_lane_time_in_lane_in_ms = (in_lane * 1000.0) as u16;
+        }
+        Packet::LapData(Box::new(pkt))
+    }
+
+    /// Three 90 second laps from the line.
+    fn drive(engine: &mut StrategyEngine, session_uid: u64) {
+        engine.apply(&session(session_uid));
+        engine.apply(&car_status(session_uid, 0.0, 0));
+        engine.apply(&lap_data(session_uid, 0.0, 0.0, None));
+        for lap in 1..=3u8 {
+            let session_time = lap as f32 * 90.0;
+            engine.apply(&car_status(session_uid, session_time, lap));
+            let distance = lap as f32 * TRACK_LENGTH;
+            engine.apply(&lap_data(session_uid, session_time, distance, None));
+        }
+    }
+
+    /// Drives 400 metres of pit lane in 25 seconds, returning the stops completed.
+    fn pit(engine: &mut StrategyEngine, session_uid: u64) -> Vec<PitStop> {
+        let (start, time) = (3.0 * TRACK_LENGTH + 4800.0, 360.0);
+        let mut stops = Vec::new();
+        for step in 0..=5 {
+            let in_lane = step as f32 * 5.0;
+            let distance = start + step as f32 * 80.0;
+            stops.extend(engine.apply(&lap_data(
+                session_uid,
+                time + in_lane,
+                distance,
+                Some(in_lane),
+            )));
+        }
+        stops.extend(engine.apply(&lap_data(session_uid, time + 26.0, start + 420.0, None)));
+        stops
+    }
+
+    /// Lap time in milliseconds of a lap completed on tyres of the age.
+    fn race_lap_time(actual_compound: u8, age: u8) -> u32 {
+        match actual_compound {
+            SOFT => 90_000 + 200 * age as u32,
+            _ => 90_500 + 150 * age as u32,
+        }
+    }
+
+    /// Cars on a new set of the compound from the start, the leader first, each starting at the
+    /// total distance and driving at 50 m/s for the seconds. Tyres wear 1% per lap.
+    fn race(engine: &mut StrategyEngine, session_uid: u64, cars: &[(u8, f32)], seconds: u16) {
+        engine.apply(&session(session_uid));
+        for second in 0..=seconds {
+            let session_time = second as f32;
+            let mut status: PacketCarStatusData =
+                testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+            let mut damage: PacketCarDamageData =
+                testing::packet(PacketId::CarDamage, session_uid, 0, session_time);
+            let mut laps: PacketLapData =
+                testing::packet(PacketId::LapData, session_uid, 0, session_time);
+            for (car_index, &(actual_compound, start)) in cars.iter().enumerate() {
+                let total_distance = start + session_time * 50.0;
+                let age = (total_distance / TRACK_LENGTH) as u8;
+                let car_status = &mut status.car_status_data[car_index];
+                car_status.actual_tyre_compound = actual_compound;
+                car_status.visual_tyre_compound = actual_compound;
+                car_status.tyres_age_laps = age;
+                damage.car_damage_data[car_index].tyres_wear = [age as f32; 4];
+                let lap = &mut laps.lap_data[car_index];
+                lap.total_distance = total_distance;
+                lap.lap_distance = total_distance % TRACK_LENGTH;
+                lap.current_lap_num = age + 1;
+                lap.last_lap_time_in_ms = race_lap_time(actual_compound, age);
+                lap.car_position = car_index as u8 + 1;
+                lap.result_status = 2;
+            }
+            engine.apply(&Packet::CarStatus(Box::new(status)));
+            engine.apply(&Packet::CarDamage(Box::new(damage)));
+            engine.apply(&Packet::LapData(Box::new(laps)));
+        }
+    }
+
+    /// Three laps of two cars on softs a second apart and a car on mediums behind.
+    fn soft_race(engine: &mut StrategyEngine) {
+        race(
+            engine,
+            1,
+            &[(SOFT, 1000.0), (SOFT, 950.0), (MEDIUM, 0.0)],
+            300,
+        );
+    }
+
+    #[test]
+    fn rejoin_counts_the_timed_cars_ahead() {
+        let mut engine = StrategyEngine::new().pit_loss(25.0);
+        // Gaps of 10, 30 and 40 seconds, the last car can't be timed yet
+        let cars = [
+            (SOFT, 3000.0),
+            (SOFT, 2500.0),
+            (SOFT, 1500.0),
+            (SOFT, 1000.0),
+            (SOFT, 0.0),
+        ];
+        race(&mut engine, 1, &cars, 50);
+        let rejoin = engine.rejoin(1).unwrap();
+        assert_eq!(rejoin.position, 3);
+        assert!((rejoin.gap_ahead.unwrap() - 5.0).abs() < 0.01);
+        assert!((rejoin.gap_behind.unwrap() - 5.0).abs() < 0.01);
+        assert_eq!(rejoin.game_position, None);
+        assert_eq!(engine.rejoin(4), None);
+    }
+
+    #[test]
+    fn undercut_needs_enough_laps_on_the_fresh_set() {
+        let mut engine = StrategyEngine::new();
+        soft_race(&mut engine);
+        // 600 ms gained over 3 laps, 1250 ms over 5
+        let short = engine.undercut(1, 0, MEDIUM, 3).unwrap();
+        assert!((short.gap - 1.0).abs() < 0.01);
+        assert!((short.gain - 0.6).abs() < 0.01);
+        assert!(!short.succeeds);
+        let long = engine.undercut(1, 0, MEDIUM, 5).unwrap();
+        assert!((long.gain - 1.25).abs() < 0.01);
+        assert!((long.gap_after + 0.25).abs() < 0.01);
+        assert!(long.succeeds);
+    }
+
+    #[test]
+    fn overcut_keeps_the_place_while_the_old_set_holds_on() {
+        let mut engine = StrategyEngine::new();
+        soft_race(&mut engine);
+        let short = engine.overcut(0, 1, MEDIUM, 3).unwrap();
+        assert!((short.gap + 1.0).abs() < 0.01);
+        assert!((short.gain + 0.6).abs() < 0.01);
+        assert!(short.succeeds);
+        assert!(!engine.overcut(0, 1, MEDIUM, 5).unwrap().succeeds);
+    }
+
+    /// Plans of the leader with the pit loss, checking they are ranked fastest first.
+    fn plans(pit_loss: f32) -> Vec<StrategyPlan> {
+        let mut engine = StrategyEngine::new().pit_loss(pit_loss);
+        soft_race(&mut engine);
+        let plans = engine.recommendations(0);
+        assert_eq!(plans[0].delta, 0.0);
+        assert!(plans.windows(2).all(|pair| pair[0].delta <= pair[1].delta));
+        // The softs alone don't change compound
+        assert!(plans.iter().all(|plan| !plan.stops.is_empty()));
+        plans
+    }
+
+    #[test]
+    fn two_stops_are_faster_when_the_stop_is_quick() {
+        let plans = plans(5.0);
+        assert_eq!(plans[0].stops.len(), 2);
+        // 7 laps on the softs and 10 on the mediums take 1553.05 seconds
+        let one_stop = plans.iter().find(|plan| plan.stops.len() == 1).unwrap();
+        assert_eq!(
+            one_stop.stops,
+            vec![PlannedStop {
+                lap_number: 10,
+                actual_compound: MEDIUM
+            }]
+        );
+        assert!((one_stop.race_time - 1558.05).abs() < 0.01);
+        assert!((one_stop.delta - 1.9).abs() < 0.01);
+    }
+
+    #[test]
+    fn one_stop_is_faster_when_the_stop_is_slow() {
+        let plans = plans(30.0);
+        assert_eq!(
+            plans[0].stops,
+            vec![PlannedStop {
+                lap_number: 10,
+                actual_compound: MEDIUM
+            }]
+        );
+        assert!((plans[0].race_time - 1583.05).abs() < 0.01);
+        let two_stops = plans.iter().find(|plan| plan.stops.len() == 2).unwrap();
+        assert!((two_stops.delta - 23.1).abs() < 0.01);
+    }
+
+    #[test]
+    fn pit_loss_is_the_default_until_a_stop_is_measured() {
+        let engine = StrategyEngine::new().pit_loss(20.0);
+        assert_eq!(engine.measured_pit_loss(), 20.0);
+    }
+
+    #[test]
+    fn pit_loss_subtracts_the_lane_distance_at_lap_pace() {
+        let mut engine = StrategyEngine::new();
+        drive(&mut engine, 1);
+        let stops = pit(&mut engine, 1);
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].time_in_lane_in_ms, 25_000);
+        assert!((stops[0].lane_distance - 400.0).abs() < 0.01);
+        // 400 metres take 7.2 seconds at 90 seconds per 5000 metre lap
+        assert!((engine.measured_pit_loss() - 17.8).abs() < 0.01);
+    }
+
+    #[test]
+    fn pit_loss_needs_the_lap_pace() {
+        let mut engine = StrategyEngine::new().pit_loss(20.0);
+        engine.apply(&session(1));
+        let stops = pit(&mut engine, 1);
+        assert_eq!(stops[0].loss, None);
+        assert_eq!(engine.measured_pit_loss(), 20.0);
+    }
+
+    #[test]
+    fn flashback_drops_the_stop() {
+        let mut engine = StrategyEngine::new().pit_loss(20.0);
+        drive(&mut engine, 1);
+        pit(&mut engine, 1);
+        engine.apply(&testing::flashback(1, 1000, 386.0, 900, 300.0));
+        assert!(engine.pit_stops().is_empty());
+        assert_eq!(engine.measured_pit_loss(), 20.0);
+    }
+
+    #[test]
+    fn session_change_keeps_the_settings() {
+        let mut engine = StrategyEngine::new().pit_loss(20.0).wear_limit(60.0);
+        drive(&mut engine, 1);
+        pit(&mut engine, 1);
+        engine.apply(&session(2));
+        assert!(engine.pit_stops().is_empty());
+        assert_eq!(engine.measured_pit_loss(), 20.0);
+        assert_eq!(engine.wear_limit, 60.0);
+    }
+}
@@ -4,7 +4,10 @@ use std::fmt::Debug;
 use binread::{self, BinRead};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+/// Number of cars in the per car arrays of the packets
+pub const MAX_CARS: usize = 22;
+
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketHeader {
     pub packet_format: u16,
     pub game_major_version: u8,
@@ -18,7 +21,7 @@ pub struct PacketHeader {
     pub secondary_player_car_index: u8,
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct CarStatusData {
     pub traction_control: u8, // Traction control - 0 = off, 1 = medium, 2 = full
     pub anti_lock_brakes: u8, // 0 (off) - 1 (on)
@@ -86,13 +89,13 @@ impl Default for CarStatusData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketCarStatusData {
     pub header: PacketHeader, // Header
     pub car_status_data: [CarStatusData; 22],
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct CarMotionData {
     pub world_position_x: f32,
     pub world_position_y: f32,
@@ -139,7 +142,7 @@ impl Default for CarMotionData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketMotionData {
     pub header: PacketHeader, // Header
 
@@ -163,7 +166,7 @@ pub struct PacketMotionData {
     pub front_wheels_angle: f32,       // Current front wheels angle in radians
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct FinalClassificationData {
     pub position: u8,      // Finishing position
     pub num_laps: u8,      // Number of laps completed
@@ -204,14 +207,14 @@ impl Default for FinalClassificationData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketFinalClassificationData {
     pub header: PacketHeader,
     pub num_cars: u8,
     pub classification_data: [FinalClassificationData; 22],
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct MarshalZone {
     pub zone_start: f32, // Fraction (0..1) of way through the lap the marshal zone starts
     pub zone_flag: i8,   // -1 = invalid/unknown, 0 = none, 1 = green, 2 = blue, 3 = yellow, 4 = red
@@ -226,7 +229,7 @@ impl Default for MarshalZone {
     }
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct WeatherForecastSample {
     pub session_type: u8, // 0 = unknown, 1 = P1, 2 = P2, 3 = P3, 4 = Short P, 5 = Q1
     // 6 = Q2, 7 = Q3, 8 = Short Q, 9 = OSQ, 10 = R, 11 = R2
@@ -241,7 +244,7 @@ pub struct WeatherForecastSample {
     pub rain_percentage: u8,          // Rain percentage (0-100)
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketSessionData {
     pub header: PacketHeader, // Header
 
@@ -296,7 +299,7 @@ pub struct PacketSessionData {
                                           // 5 = Medium Long, 6 = Long, 7 = Full
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct LapData {
     pub last_lap_time_in_ms: u32,    // Last lap time in milliseconds
     pub current_lap_time_in_ms: u32, // Current time around the lap in milliseconds
@@ -360,7 +363,7 @@ impl Default for LapData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketLapData {
     pub header: PacketHeader,         // Header
     pub lap_data: [LapData; 22],      // Lap data for all cars on track
@@ -368,7 +371,7 @@ pub struct PacketLapData {
     pub time_trial_rival_car_idx: u8, // Index of Rival car in time trial (255 if invalid)
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct ParticipantData {
     pub ai_controlled: u8, // Whether the vehicle is AI (1) or Human (0) controlled
     pub driver_id: u8,     // Driver id - see appendix, 255 if network human
@@ -399,7 +402,7 @@ impl Default for ParticipantData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketParticipantsData {
     pub header: PacketHeader, // Header
     pub num_active_cars: u8,  // Number of active cars in the data – should match number of
@@ -407,7 +410,7 @@ pub struct PacketParticipantsData {
     pub participants: [ParticipantData; 22],
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct CarSetupData {
     pub front_wing: u8,                 // Front wing aero
     pub rear_wing: u8,                  // Rear wing aero
@@ -461,13 +464,13 @@ impl Default for CarSetupData {
         }
     }
 }
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketCarSetupData {
     pub header: PacketHeader,
     pub car_setups: [CarSetupData; 22],
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct CarTelemetryData {
     pub speed: u16,                         // Speed of car in kilometres per hour
     pub throttle: f32,                      // Amount of throttle applied (0.0 to 1.0)
@@ -510,7 +513,7 @@ impl Default for CarTelemetryData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketCarTelemetryData {
     pub header: PacketHeader,
     pub car_telemetry_data: [CarTelemetryData; 22],
@@ -523,7 +526,7 @@ pub struct PacketCarTelemetryData {
                                               // 0 if no gear suggested
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct LobbyInfoData {
     pub ai_controlled: u8, // Whether the vehicle is AI (1) or Human (0) controlled
     pub team_id: u8,       // Team id - see appendix (255 if no team currently selected)
@@ -548,14 +551,14 @@ impl Default for LobbyInfoData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketLobbyInfoData {
     pub header: PacketHeader,
     pub num_players: u8,
     pub lobby_players: [LobbyInfoData; 22],
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct CarDamageData {
     pub tyres_wear: [f32; 4],        // Tyre wear (percentage)
     pub tyres_damage: [u8; 4],       // Tyre damage (percentage)
@@ -607,13 +610,13 @@ impl Default for CarDamageData {
     }
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketCarDamageData {
     pub header: PacketHeader,
     pub car_damage_data: [CarDamageData; 22],
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct LapHistoryData {
     pub lap_time_in_ms: u32,     // Lap time in milliseconds
     pub sector1_time_in_ms: u16, // Sector 1 time in milliseconds
@@ -623,14 +626,14 @@ pub struct LapHistoryData {
                                  // 0x04 bit set-sector 2 valid, 0x08 bit set-sector 3 valid
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct TyreStintHistoryData {
     pub end_lap: u8,              // Lap the tyre usage ends on (255 of current tyre)
     pub tyre_actual_compound: u8, // Actual tyres used by this driver
     pub tyre_visual_compound: u8, // Visual tyres used by this driver
 }
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketSessionHistoryData {
     pub header: PacketHeader,
     pub car_idx: u8,               // Index of the car this lap data relates to
@@ -647,7 +650,7 @@ pub struct PacketSessionHistoryData {
 
 pub trait Event {}
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct EventFastestLap {
     pub vehicle_idx: u8, // Vehicle index of car achieving fastest lap
     pub lap_time: f32,   // Lap time is in seconds
@@ -664,28 +667,28 @@ impl Default for EventFastestLap {
     }
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventRetirement {
     pub vehicle_idx: u8, // Vehicle index of car retiring
 }
 
 impl Event for EventRetirement {}
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventTeamMateInPits {
     pub vehicle_idx: u8, // Vehicle index of team mate
 }
 
 impl Event for EventTeamMateInPits {}
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventRaceWinner {
     pub vehicle_idx: u8, // Vehicle index of the race winner
 }
 
 impl Event for EventRaceWinner {}
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventPenalty {
     pub penalty_type: u8,      // Penalty type – see Appendices
     pub infringement_type: u8, // Infringement type – see Appendices
@@ -698,7 +701,7 @@ pub struct EventPenalty {
 
 impl Event for EventPenalty {}
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct EventSpeedTrap {
     pub vehicle_idx: u8, // Vehicle index of the vehicle triggering speed trap
     pub speed: f32,      // Top speed achieved in kilometres per hour
@@ -725,28 +728,28 @@ impl Default for EventSpeedTrap {
     }
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventStartLights {
     pub num_lights: u8, // Number of lights showing
 }
 
 impl Event for EventStartLights {}
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventDriveThroughPenaltyServed {
     pub vehicle_idx: u8, // Vehicle index of the vehicle serving drive through
 }
 
 impl Event for EventDriveThroughPenaltyServed {}
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventStopGoPenaltyServed {
     pub vehicle_idx: u8, // Vehicle index of the vehicle serving stop go
 }
 
 impl Event for EventStopGoPenaltyServed {}
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct EventFlashback {
     pub flashback_frame_identifier: u32, // Frame identifier flashed back to
     pub flashback_session_time: f32,     // Session time flashed back to
@@ -763,7 +766,7 @@ impl Default for EventFlashback {
     }
 }
 
-#[derive(Debug, Default, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
 pub struct EventButtons {
     pub button_status: u32, // Bit flags specifying which buttons are being pressed
                             // currently - see appendices
@@ -771,7 +774,12 @@ pub struct EventButtons {
 
 impl Event for EventButtons {}
 
-#[derive(Debug, BinRead, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, BinRead, Serialize, Deserialize)]
+pub struct EventNoDetails {} // Used by events without details, e.g. SSTA, SEND, DRSE
+
+impl Event for EventNoDetails {}
+
+#[derive(Debug, Clone, BinRead, Serialize, Deserialize)]
 pub struct PacketEventData<T>
 where
     T: BinRead<Args = ()> + Serialize + Event,
@@ -0,0 +1,72 @@
+//! Packets for the unit tests, decoded from zeroed datagrams so that only the fields a test is
+//! about need to be set
+use binread::{io::Cursor, BinRead, BinReaderExt};
+
+use crate::packet::{EventPacket, Packet, PacketId};
+
+const DATAGRAM_SIZE: usize = 2048;
+
+fn datagram(
+    packet_id: PacketId,
+    session_uid: u64,
+    frame_identifier: u32,
+    session_time: f32,
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; DATAGRAM_SIZE];
+    buffer[0..2].copy_from_slice(&2022u16.to_le_bytes());
+    buffer[2] = 1; // Game major version
+    buffer[4] = 1; // Packet version
+    buffer[5] = packet_id as u8;
+    buffer[6..14].copy_from_slice(&session_uid.to_le_bytes());
+    buffer[14..18].copy_from_slice(&session_time.to_le_bytes());
+    buffer[18..22].copy_from_slice(&frame_identifier.to_le_bytes());
+    buffer[23] = 255; // No secondary player
+    buffer
+}
+
+/// A packet of the type with every field after the header zeroed.
+pub(crate) fn packet<T: BinRead<Args = ()>>(
+    packet_id: PacketId,
+    session_uid: u64,
+    frame_identifier: u32,
+    session_time: f32,
+) -> T {
+    let buffer = datagram(packet_id, session_uid, frame_identifier, session_time);
+    Cursor::new(&buffer[..]).read_le().unwrap()
+}
+
+/// An event packet with the code and the bytes of its details.
+pub(crate) fn event(
+    code: &str,
+    details: &[u8],
+    session_uid: u64,
+    frame_identifier: u32,
+    session_time: f32,
+) -> EventPacket {
+    let mut buffer = datagram(PacketId::Event, session_uid, frame_identifier, session_time);
+    buffer[24..28].copy_from_slice(code.as_bytes());
+    buffer[28..28 + details.len()].copy_from_slice(details);
+    match Packet::decode(&buffer).unwrap() {
+        Some(Packet::Event(event)) => event,
+        _ => panic!("{} isn't a supported event", code),
+    }
+}
+
+/// A flashback to the frame and session time.
+pub(crate) fn flashback(
+    session_uid: u64,
+    frame_identifier: u32,
+    session_time: f32,
+    to_frame: u32,
+    to_time: f32,
+) -> Packet {
+    let mut details = to_frame.to_le_bytes().to_vec();
+    details.extend(to_time.to_le_bytes());
+    Packet::Event(event(
+        "FLBK",
+        &details,
+        session_uid,
+        frame_identifier,
+        session_time,
+    ))
+}
@@ -0,0 +1,222 @@
+//! Live gap to the leader and interval to the car ahead
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    telemetry::{PacketLapData, MAX_CARS},
+};
+
+/// Minimum distance in metres between two samples of a trace.
+const SAMPLE_SPACING: f32 = 5.0;
+/// Distance in metres of trace kept behind each car, long enough to time cars a lap down.
+const TRACE_LENGTH: f32 = 20000.0;
+
+/// Timing of one car relative to the others.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CarGap {
+    pub car_index: usize,
+    pub position: u8,
+    pub gap_to_leader: Option<f32>, // Seconds, None for the leader or when it can't be timed
+    pub interval: Option<f32>,      // Seconds to the car one position ahead
+    pub laps_down: u8,              // Laps behind the leader
+}
+
+/// Session time at which a car reached a total distance.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    distance: f32,
+    session_time: f32,
+}
+
+/// Computes gaps and intervals from the total distance every car has travelled. The session time
+/// each car passed a distance is recorded, so the gap to a car ahead is the time since it was
+/// where the car behind is now, interpolated between samples.
+#[derive(Debug, Clone, Serialize)]
+pub struct Timing {
+    #[serde(skip)]
+    traces: Vec<Vec<Sample>>,
+    gaps: Vec<CarGap>,
+    #[serde(skip)]
+    track_length: u16,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            traces: vec![Vec::new(); MAX_CARS],
+            gaps: Vec::new(),
+            track_length: 0,
+        }
+    }
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Timing::default()
+    }
+
+    /// Track length in metres, used to work out how many laps down a car is.
+    pub fn set_track_length(&mut self, track_length: u16) {
+        self.track_length = track_length;
+    }
+
+    /// Gaps of the running cars, ordered by position.
+    pub fn gaps(&self) -> &[CarGap] {
+        &self.gaps
+    }
+
+    pub fn gap(&self, car_index: usize) -> Option<&CarGap> {
+        self.gaps.iter().find(|gap| gap.car_index == car_index)
+    }
+
+    /// Records the position of every car and recomputes the gaps.
+    pub fn update(&mut self, pkt: &PacketLapData) {
+        let session_time = pkt.header.session_time;
+        for (trace, lap) in self.traces.iter_mut().zip(pkt.lap_data.iter()) {
+            let distance = lap.total_distance;
+            match trace.last() {
+                Some(last) if distance < last.distance + SAMPLE_SPACING => {}
+                _ => trace.push(Sample {
+                    distance,
+                    session_time,
+                }),
+            }
+            let oldest = distance - TRACE_LENGTH;
+            let stale = trace.iter().take_while(|s| s.distance < oldest).count();
+            trace.drain(..stale);
+        }
+
+        let mut running: Vec<(usize, u8, f32)> = pkt
+            .lap_data
+            .iter()
+            .enumerate()
+            // Result status 2 = active
+            .filter(|(_, lap)| lap.car_position > 0 && lap.result_status == 2)
+            .map(|(idx, lap)| (idx, lap.car_position, lap.total_distance))
+            .collect();
+        running.sort_by_key(|(_, position, _)| *position);
+
+        let leader = running.first().copied();
+        self.gaps = running
+            .iter()
+            .enumerate()
+            .map(|(idx, &(car_index, position, distance))| {
+                let ahead = idx.checked_sub(1).map(|ahead| running[ahead]);
+                let (gap_to_leader, laps_down) = match leader {
+                    Some((leader_idx, _, leader_distance)) if leader_idx != car_index => (
+                        self.time_since(leader_idx, distance, session_time),
+                        self.laps_between(leader_distance, distance),
+                    ),
+                    _ => (None, 0),
+                };
+                CarGap {
+                    car_index,
+                    position,
+                    gap_to_leader,
+                    interval: ahead.and_then(|(ahead_idx, _, _)| {
+                        self.time_since(ahead_idx, distance, session_time)
+                    }),
+                    laps_down,
+                }
+            })
+            .collect();
+    }
+
+    /// Seconds since the car was at the total distance.
+    fn time_since(&self, car_index: usize, distance: f32, session_time: f32) -> Option<f32> {
+        let trace = &self.traces[car_index];
+        let next = trace.partition_point(|s| s.distance < distance);
+        let after = trace.get(next)?;
+        let passed_at = match next.checked_sub(1).map(|prev| trace[prev]) {
+            Some(before) if after.distance > before.distance => {
+                let ratio = (distance - before.distance) / (after.distance - before.distance);
+                before.session_time + ratio * (after.session_time - before.session_time)
+            }
+            Some(_) => after.session_time,
+            // Before the start of the trace
+            None if after.distance - distance < SAMPLE_SPACING => after.session_time,
+            None => return None,
+        };
+        Some((session_time - passed_at).max(0.0))
+    }
+
+    fn laps_between(&self, ahead_distance: f32, distance: f32) -> u8 {
+        if self.track_length == 0 {
+            return 0;
+        }
+        ((ahead_distance - distance) / self.track_length as f32)
+            .floor()
+            .clamp(0.0, u8::MAX as f32) as u8
+    }
+}
+
+impl Rewind for Timing {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        self.traces
+            .iter_mut()
+            .for_each(|trace| trace.retain(|s| s.session_time <= point.session_time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    /// Two cars, the leader 100 metres ahead, both at 50 m/s.
+    fn lap_data(session_time: f32) -> PacketLapData {
+        let mut pkt: PacketLapData = testing::packet(PacketId::LapData, 1, 0, session_time);
+        for (car_index, (position, offset)) in [(1, 100.0), (2, 0.0)].into_iter().enumerate() {
+            let lap = &mut pkt.lap_data[car_index];
+            lap.car_position = position;
+            lap.result_status = 2;
+            lap.total_distance = offset + session_time * 50.0;
+        }
+        pkt
+    }
+
+    #[test]
+    fn first_packet_has_no_gaps() {
+        let mut timing = Timing::new();
+        timing.update(&lap_data(10.0));
+        assert_eq!(timing.gaps().len(), 2);
+        assert_eq!(timing.gap(0).unwrap().gap_to_leader, None);
+        assert_eq!(timing.gap(1).unwrap().gap_to_leader, None);
+    }
+
+    #[test]
+    fn gap_is_the_time_since_the_car_ahead_was_there() {
+        let mut timing = Timing::new();
+        for tenth in 100..=140 {
+            timing.update(&lap_data(tenth as f32 / 10.0));
+        }
+        let gap = timing.gap(1).unwrap();
+        assert!((gap.gap_to_leader.unwrap() - 2.0).abs() < 0.01);
+        assert!((gap.interval.unwrap() - 2.0).abs() < 0.01);
+        assert_eq!(gap.laps_down, 0);
+    }
+
+    #[test]
+    fn laps_down_uses_the_track_length() {
+        let mut timing = Timing::new();
+        timing.set_track_length(40);
+        timing.update(&lap_data(10.0));
+        assert_eq!(timing.gap(1).unwrap().laps_down, 2);
+    }
+
+    #[test]
+    fn flashback_drops_samples_after_the_point() {
+        let mut timing = Timing::new();
+        for tenth in 100..=140 {
+            timing.update(&lap_data(tenth as f32 / 10.0));
+        }
+        timing.rewind(&FlashbackPoint {
+            session_uid: 1,
+            frame_identifier: 0,
+            session_time: 11.0,
+        });
+        assert!(timing.traces[0].iter().all(|s| s.session_time <= 11.0));
+        timing.update(&lap_data(12.0));
+        assert!((timing.gap(1).unwrap().gap_to_leader.unwrap() - 2.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,546 @@
+//! Track maps built from the world positions of the cars
+use std::{collections::BTreeMap, fmt::Write as _, path::Path};
+
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    errors::TelemetryError,
+    flashback::{FlashbackPoint, Rewind},
+    json::{load_json, save_json},
+    packet::Packet,
+    sampling::{DistanceSample, Framed, LapSampler},
+    telemetry::{PacketLapData, MAX_CARS},
+};
+
+/// Distance in metres between two points of the centerline.
+const POINT_SPACING: f32 = 5.0;
+/// Minimum distance in metres between two recorded positions of a lap.
+const SAMPLE_SPACING: f32 = 1.0;
+/// A lap must start and end within this many metres of the line to be used.
+const LINE_TOLERANCE: f32 = 50.0;
+
+/// A point of the centerline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrackPoint {
+    pub lap_distance: f32, // Metres
+    pub x: f32,            // World position x in metres
+    pub z: f32,            // World position z in metres
+}
+
+/// Where a world position is around the lap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TrackProjection {
+    pub lap_distance: f32, // Metres
+    pub offset: f32,       // Distance from the centerline in metres
+}
+
+/// Centerline of a track, averaged over clean laps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackMap {
+    pub track_id: i8,
+    pub track_length: u16,
+    pub laps: u32, // Number of laps averaged
+    pub points: Vec<TrackPoint>,
+}
+
+impl TrackMap {
+    /// Smallest x and z, and the larger of the width and height.
+    fn bounds(&self) -> (f32, f32, f32) {
+        let (min_x, max_x, min_z, max_z) = self.points.iter().fold(
+            (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+            |(min_x, max_x, min_z, max_z), p| {
+                (
+                    min_x.min(p.x),
+                    max_x.max(p.x),
+                    min_z.min(p.z),
+                    max_z.max(p.z),
+                )
+            },
+        );
+        let scale = (max_x - min_x).max(max_z - min_z).max(f32::EPSILON);
+        (min_x, min_z, scale)
+    }
+
+    /// Scales a world position to the map, between 0 and 1 along the longer side of the track,
+    /// so that car positions line up with [`TrackMap::normalized`].
+    pub fn normalize(&self, x: f32, z: f32) -> (f32, f32) {
+        let (min_x, min_z, scale) = self.bounds();
+        ((x - min_x) / scale, (z - min_z) / scale)
+    }
+
+    /// The centerline scaled between 0 and 1 along the longer side of the track.
+    pub fn normalized(&self) -> Vec<(f32, f32)> {
+        self.points
+            .iter()
+            .map(|p| self.normalize(p.x, p.z))
+            .collect()
+    }
+
+    /// World position at the lap distance, interpolated between points.
+    pub fn position_at(&self, lap_distance: f32) -> Option<(f32, f32)> {
+        let lap_distance = lap_distance.rem_euclid(self.track_length.max(1) as f32);
+        let next = self
+            .points
+            .partition_point(|p| p.lap_distance < lap_distance);
+        let after = self.points.get(next).or_else(|| self.points.first())?;
+        let before = match next.checked_sub(1) {
+            Some(prev) => self.points[prev],
+            None => return Some((after.x, after.z)),
+        };
+        let mut span = after.lap_distance - before.lap_distance;
+        if span <= 0.0 {
+            // Wrapping around the line
+            span += self.track_length as f32;
+        }
+        let ratio = ((lap_distance - before.lap_distance) / span).clamp(0.0, 1.0);
+        Some((
+            before.x + ratio * (after.x - before.x),
+            before.z + ratio * (after.z - before.z),
+        ))
+    }
+
+    /// Projects a world position onto the nearest point of the centerline.
+    pub fn project(&self, x: f32, z: f32) -> Option<TrackProjection> {
+        let track_length = self.track_length as f32;
+        let segments = self
+            .points
+            .iter()
+            .zip(self.points.iter().cycle().skip(1))
+            .take(self.points.len());
+        segments
+            .map(|(a, b)| {
+                let (dx, dz) = (b.x - a.x, b.z - a.z);
+                let length = dx * dx + dz * dz;
+                let t = if length > 0.0 {
+                    (((x - a.x) * dx + (z - a.z) * dz) / length).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let (px, pz) = (a.x + t * dx, a.z + t * dz);
+                let mut span = b.lap_distance - a.lap_distance;
+                if span < 0.0 {
+                    span += track_length;
+                }
+                TrackProjection {
+                    lap_distance: (a.lap_distance + t * span) % track_length.max(1.0),
+                    offset: ((x - px).powi(2) + (z - pz).powi(2)).sqrt(),
+                }
+            })
+            .min_by(|a, b| a.offset.total_cmp(&b.offset))
+    }
+
+    /// The centerline as an SVG path, the longer side of the track `size` units long.
+    pub fn to_svg(&self, size: f32) -> String {
+        let points = self.normalized();
+        let (width, height) = points
+            .iter()
+            .fold((0.0f32, 0.0f32), |(w, h), (x, z)| (w.max(*x), h.max(*z)));
+        let mut path = String::new();
+        for (idx, (x, z)) in points.iter().enumerate() {
+            let command = if idx == 0 { 'M' } else { 'L' };
+            let _ = write!(path, "{}{:.2} {:.2} ", command, x * size, z * size);
+        }
+        path.push('Z');
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {:.2} {:.2}\">\
+             <path d=\"{}\" fill=\"none\" stroke=\"black\" stroke-width=\"{:.2}\"/></svg>",
+            width * size,
+            height * size,
+            path,
+            size / 200.0
+        )
+    }
+
+    /// The centerline as a GeoJSON like feature, a closed line string of world x and z positions
+    /// in metres with the lap distance of every point in the properties.
+    pub fn to_geojson(&self) -> Value {
+        let mut coordinates: Vec<[f32; 2]> = self.points.iter().map(|p| [p.x, p.z]).collect();
+        if let Some(first) = coordinates.first().copied() {
+            coordinates.push(first);
+        }
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coordinates,
+            },
+            "properties": {
+                "track_id": self.track_id,
+                "track_length": self.track_length,
+                "laps": self.laps,
+                "lap_distance": self.points.iter().map(|p| p.lap_distance).collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<TrackMap, TelemetryError> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TelemetryError> {
+        save_json(self, path)
+    }
+}
+
+impl DistanceSample for TrackPoint {
+    fn lap_distance(&self) -> f32 {
+        self.lap_distance
+    }
+}
+
+/// Sum of the positions of every lap at each point of the centerline.
+#[derive(Debug, Clone, Default)]
+struct TrackSums {
+    track_length: u16,
+    laps: u32,
+    sums: Vec<(f32, f32)>,
+}
+
+/// Position of a recorded lap at the lap distance, interpolated between points. Past the last
+/// point the lap is closed back to the first.
+fn lap_position_at(
+    points: &[TrackPoint],
+    lap_distance: f32,
+    track_length: f32,
+) -> Option<(f32, f32)> {
+    let next = points.partition_point(|p| p.lap_distance < lap_distance);
+    let after = match points.get(next) {
+        Some(after) => *after,
+        None => {
+            let first = points.first()?;
+            TrackPoint {
+                lap_distance: first.lap_distance + track_length,
+                ..*first
+            }
+        }
+    };
+    let before = match next.checked_sub(1) {
+        Some(prev) => points[prev],
+        None => return Some((after.x, after.z)),
+    };
+    if after.lap_distance <= before.lap_distance {
+        return Some((after.x, after.z));
+    }
+    let ratio = (lap_distance - before.lap_distance) / (after.lap_distance - before.lap_distance);
+    Some((
+        before.x + ratio * (after.x - before.x),
+        before.z + ratio * (after.z - before.z),
+    ))
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarPositions {
+    lap_distance: Option<Framed<f32>>, // Lap distance of the latest lap data
+    position: Option<Framed<(f32, f32)>>, // World x and z of the latest motion
+    laps: LapSampler<TrackPoint>,      // Valid while not invalidated or in the pits
+}
+
+impl CarPositions {
+    /// Records the position once the lap data and motion of the same frame have both arrived.
+    fn pair(&mut self) {
+        if let Some(point) = self.point() {
+            self.laps.push(point, SAMPLE_SPACING);
+        }
+    }
+
+    fn point(&self) -> Option<TrackPoint> {
+        let lap_distance = self.lap_distance.as_ref()?;
+        let (x, z) = *self.position.as_ref()?.at(lap_distance.frame)?;
+        Some(TrackPoint {
+            lap_distance: lap_distance.value,
+            x,
+            z,
+        })
+    }
+}
+
+/// Builds the centerline of each track from the world positions of every car against their lap
+/// distance, averaged over clean laps. Maps are kept per track id across sessions.
+#[derive(Debug, Clone)]
+pub struct TrackMapBuilder {
+    session_uid: u64,
+    track_id: Option<i8>,
+    track_length: u16,
+    cars: Vec<CarPositions>,
+    tracks: BTreeMap<i8, TrackSums>,
+}
+
+impl Default for TrackMapBuilder {
+    fn default() -> Self {
+        TrackMapBuilder {
+            session_uid: 0,
+            track_id: None,
+            track_length: 0,
+            cars: vec![CarPositions::default(); MAX_CARS],
+            tracks: BTreeMap::new(),
+        }
+    }
+}
+
+impl TrackMapBuilder {
+    pub fn new() -> Self {
+        TrackMapBuilder::default()
+    }
+
+    /// Tracks a map has been built for.
+    pub fn track_ids(&self) -> impl Iterator<Item = i8> + '_ {
+        self.tracks.keys().copied()
+    }
+
+    pub fn map(&self, track_id: i8) -> Option<TrackMap> {
+        let track = self.tracks.get(&track_id)?;
+        if track.laps == 0 {
+            return None;
+        }
+        Some(TrackMap {
+            track_id,
+            track_length: track.track_length,
+            laps: track.laps,
+            points: track
+                .sums
+                .iter()
+                .enumerate()
+                .map(|(idx, (x, z))| TrackPoint {
+                    lap_distance: idx as f32 * POINT_SPACING,
+                    x: x / track.laps as f32,
+                    z: z / track.laps as f32,
+                })
+                .collect(),
+        })
+    }
+
+    /// Updates the maps with the packet, returning the number of clean laps added.
+    pub fn apply(&mut self, packet: &Packet) -> usize {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = TrackMapBuilder {
+                tracks: std::mem::take(&mut self.tracks),
+                ..TrackMapBuilder::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::Session(pkt) => {
+                self.track_id = (pkt.track_id >= 0).then_some(pkt.track_id);
+                self.track_length = pkt.track_length;
+                0
+            }
+            Packet::LapData(pkt) => self.apply_lap_data(pkt),
+            Packet::Motion(pkt) => {
+                let frame = header.frame_identifier;
+                for (car, motion) in self.cars.iter_mut().zip(pkt.car_motion_data.iter()) {
+                    car.position = Some(Framed::new(
+                        frame,
+                        (motion.world_position_x, motion.world_position_z),
+                    ));
+                    car.pair();
+                }
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    fn apply_lap_data(&mut self, pkt: &PacketLapData) -> usize {
+        let mut added = 0;
+        for (car, lap) in self.cars.iter_mut().zip(pkt.lap_data.iter()) {
+            let frame = pkt.header.frame_identifier;
+            car.lap_distance =
+                (lap.lap_distance >= 0.0).then_some(Framed::new(frame, lap.lap_distance));
+            let clean = lap.current_lap_invalid == 0 && lap.pit_status == 0;
+            if let Some(finished) = car.laps.update(lap, clean) {
+                if let (Some(track_id), true) = (self.track_id, finished.valid) {
+                    let track = self.tracks.entry(track_id).or_default();
+                    if add_lap(track, &finished.samples, self.track_length) {
+                        added += 1;
+                    }
+                }
+            }
+            car.pair();
+        }
+        added
+    }
+}
+
+impl Rewind for TrackMapBuilder {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        // Laps already added are still good geometry, only the laps in progress are dropped
+        for car in self.cars.iter_mut() {
+            car.laps.restart();
+        }
+    }
+}
+
+/// Adds a lap to the sums if it covers the whole lap.
+fn add_lap(track: &mut TrackSums, points: &[TrackPoint], track_length: u16) -> bool {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return false,
+    };
+    if track_length == 0
+        || first.lap_distance > LINE_TOLERANCE
+        || last.lap_distance < track_length as f32 - LINE_TOLERANCE
+    {
+        return false;
+    }
+    if track.track_length != track_length {
+        *track = TrackSums {
+            track_length,
+            laps: 0,
+            sums: vec![(0.0, 0.0); (track_length as f32 / POINT_SPACING).ceil() as usize],
+        };
+    }
+    for (idx, sum) in track.sums.iter_mut().enumerate() {
+        let lap_distance = idx as f32 * POINT_SPACING;
+        if let Some((x, z)) = lap_position_at(points, lap_distance, track_length as f32) {
+            sum.0 += x;
+            sum.1 += z;
+        }
+    }
+    track.laps += 1;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketMotionData, PacketSessionData},
+        testing,
+    };
+
+    const TRACK_LENGTH: u16 = 1000;
+
+    fn session(builder: &mut TrackMapBuilder, session_uid: u64) {
+        let mut pkt: PacketSessionData = testing::packet(PacketId::Session, session_uid, 0, 0.0);
+        pkt.track_id = 3;
+        pkt.track_length = TRACK_LENGTH;
+        builder.apply(&Packet::Session(Box::new(pkt)));
+    }
+
+    /// Lap data and motion of the same frame for car 0, on a straight line along x.
+    fn frame(
+        builder: &mut TrackMapBuilder,
+        session_uid: u64,
+        frame: u32,
+        lap: u8,
+        lap_distance: f32,
+        pit_status: u8,
+    ) -> usize {
+        let session_time = frame as f32 / 10.0;
+        let mut lap_data: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, frame, session_time);
+        lap_data.lap_data[0].current_lap_num = lap;
+        lap_data.lap_data[0].lap_distance = lap_distance;
+        lap_data.lap_data[0].pit_status = pit_status;
+        let mut motion: PacketMotionData =
+            testing::packet(PacketId::Motion, session_uid, frame, session_time);
+        motion.car_motion_data[0].world_position_x = lap_distance;
+        motion.car_motion_data[0].world_position_z = 10.0;
+        let added = builder.apply(&Packet::LapData(Box::new(lap_data)));
+        builder.apply(&Packet::Motion(Box::new(motion)));
+        added
+    }
+
+    /// Drives car 0 round the lap from `start` metres and over the line.
+    fn drive_lap(
+        builder: &mut TrackMapBuilder,
+        session_uid: u64,
+        first_frame: u32,
+        lap: u8,
+        start: u32,
+        pit_status: u8,
+    ) -> usize {
+        for (idx, metres) in (start..TRACK_LENGTH as u32).step_by(10).enumerate() {
+            let frame_identifier = first_frame + idx as u32;
+            frame(
+                builder,
+                session_uid,
+                frame_identifier,
+                lap,
+                metres as f32,
+                pit_status,
+            );
+        }
+        let last_frame = first_frame + (TRACK_LENGTH as u32 - start) / 10;
+        frame(builder, session_uid, last_frame, lap + 1, 0.0, 0)
+    }
+
+    #[test]
+    fn clean_lap_builds_the_map() {
+        let mut builder = TrackMapBuilder::new();
+        session(&mut builder, 1);
+        assert_eq!(drive_lap(&mut builder, 1, 0, 1, 0, 0), 1);
+        let map = builder.map(3).unwrap();
+        assert_eq!((map.laps, map.points.len()), (1, 200));
+        assert_eq!(
+            map.points[20],
+            TrackPoint {
+                lap_distance: 100.0,
+                x: 100.0,
+                z: 10.0
+            }
+        );
+        let projection = map.project(250.0, 13.0).unwrap();
+        assert!((projection.lap_distance - 250.0).abs() < 0.01);
+        assert!((projection.offset - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn partial_and_pit_laps_are_skipped() {
+        let mut builder = TrackMapBuilder::new();
+        session(&mut builder, 1);
+        assert_eq!(drive_lap(&mut builder, 1, 0, 1, 500, 0), 0);
+        assert_eq!(drive_lap(&mut builder, 1, 100, 2, 0, 1), 0);
+        assert!(builder.map(3).is_none());
+    }
+
+    #[test]
+    fn motion_of_another_frame_isnt_paired() {
+        let mut builder = TrackMapBuilder::new();
+        frame(&mut builder, 1, 0, 1, 0.0, 0);
+        assert!(builder.cars[0].point().is_some());
+        let mut lap_data: PacketLapData = testing::packet(PacketId::LapData, 1, 1, 0.1);
+        lap_data.lap_data[0].current_lap_num = 1;
+        lap_data.lap_data[0].lap_distance = 10.0;
+        builder.apply(&Packet::LapData(Box::new(lap_data)));
+        assert!(builder.cars[0].point().is_none());
+    }
+
+    #[test]
+    fn flashback_restarts_the_lap_in_progress() {
+        let mut builder = TrackMapBuilder::new();
+        session(&mut builder, 1);
+        drive_lap(&mut builder, 1, 0, 1, 0, 0);
+        for metres in (0..500).step_by(10) {
+            frame(&mut builder, 1, 100 + metres / 10, 2, metres as f32, 0);
+        }
+        builder.apply(&testing::flashback(1, 150, 15.0, 140, 14.0));
+        // Lap 2 is only part of a lap once recording restarts
+        for metres in (500..1000).step_by(10) {
+            frame(&mut builder, 1, 150 + metres / 10, 2, metres as f32, 0);
+        }
+        assert_eq!(frame(&mut builder, 1, 300, 3, 0.0, 0), 0);
+        assert_eq!(builder.map(3).unwrap().laps, 1);
+    }
+
+    #[test]
+    fn maps_are_kept_across_sessions() {
+        let mut builder = TrackMapBuilder::new();
+        session(&mut builder, 1);
+        drive_lap(&mut builder, 1, 0, 1, 0, 0);
+        session(&mut builder, 2);
+        assert_eq!(drive_lap(&mut builder, 2, 0, 1, 0, 0), 1);
+        assert_eq!(builder.map(3).unwrap().laps, 2);
+        assert_eq!(builder.track_ids().collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -0,0 +1,403 @@
+//! Tyre stint tracking and tyre degradation model
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    laps::{CompletedLap, LapEvent, LapTracker},
+    packet::Packet,
+    stats::linear_fit,
+    telemetry::{CarStatusData, MAX_CARS},
+};
+
+/// A lap driven during a stint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StintLap {
+    pub lap_number: u8,
+    pub tyres_age_laps: u8,
+    pub lap_time_in_ms: u32,
+    pub tyres_wear: [f32; 4], // Wear at the end of the lap (percentage), RL, RR, FL, FR
+    pub valid: bool,
+    pub pit_lap: bool, // In or out lap
+    pub session_time: f32,
+}
+
+impl StintLap {
+    /// Wear of the most worn tyre.
+    pub fn max_wear(&self) -> f32 {
+        self.tyres_wear.iter().copied().fold(0.0, f32::max)
+    }
+}
+
+/// A continuous run on one set of tyres.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stint {
+    pub car_index: usize,
+    pub actual_compound: u8, // See `CarStatusData::actual_tyre_compound`
+    pub visual_compound: u8, // See `CarStatusData::visual_tyre_compound`
+    pub start_age_laps: u8,  // Age of the tyres when they were fitted, non-zero for used sets
+    pub laps: Vec<StintLap>,
+    pub session_time: f32, // Session time the stint started at
+}
+
+/// Wear and lap time degradation of a compound, fitted against the age of the tyres.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DegradationModel {
+    pub actual_compound: u8,
+    pub wear_per_lap: f32, // Wear of the most worn tyre (percentage) per lap
+    pub wear_at_fit: f32,  // Wear of a new set, from the fit
+    pub lap_time_per_lap: Option<f32>, // Lap time lost per lap of tyre age in milliseconds
+    pub lap_time_at_fit: Option<f32>, // Lap time on a new set in milliseconds, from the fit
+    pub laps: usize,       // Number of laps the model was fitted with
+}
+
+impl DegradationModel {
+    /// Expected wear of the most worn tyre at the tyre age.
+    pub fn wear_at(&self, tyres_age_laps: f32) -> f32 {
+        self.wear_at_fit + self.wear_per_lap * tyres_age_laps
+    }
+
+    /// Expected lap time in milliseconds at the tyre age.
+    pub fn lap_time_at(&self, tyres_age_laps: f32) -> Option<f32> {
+        Some(self.lap_time_at_fit? + self.lap_time_per_lap? * tyres_age_laps)
+    }
+
+    /// Laps until the most worn tyre reaches the wear threshold, starting from the current wear.
+    pub fn laps_until(&self, current_wear: f32, wear_threshold: f32) -> Option<f32> {
+        if self.wear_per_lap <= 0.0 {
+            return None;
+        }
+        Some(((wear_threshold - current_wear) / self.wear_per_lap).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CarTyres {
+    status: Option<CarStatusData>,
+    tyres_wear: [f32; 4],
+    stints: Vec<Stint>,
+}
+
+/// Builds the stint history of every car from the car status, car damage and lap data packets,
+/// and fits per compound wear and lap time degradation.
+#[derive(Debug, Clone)]
+pub struct StintTracker {
+    session_uid: u64,
+    laps: LapTracker,
+    cars: Vec<CarTyres>,
+}
+
+impl Default for StintTracker {
+    fn default() -> Self {
+        StintTracker {
+            session_uid: 0,
+            laps: LapTracker::default(),
+            cars: vec![CarTyres::default(); MAX_CARS],
+        }
+    }
+}
+
+impl StintTracker {
+    pub fn new() -> Self {
+        StintTracker::default()
+    }
+
+    /// Stints of the car, oldest first.
+    pub fn stints(&self, car_index: usize) -> &[Stint] {
+        self.cars.get(car_index).map_or(&[], |car| &car.stints[..])
+    }
+
+    pub fn current_stint(&self, car_index: usize) -> Option<&Stint> {
+        self.stints(car_index).last()
+    }
+
+    /// Current tyre wear of the car (percentage), RL, RR, FL, FR.
+    pub fn tyres_wear(&self, car_index: usize) -> Option<[f32; 4]> {
+        self.cars.get(car_index).map(|car| car.tyres_wear)
+    }
+
+    /// Updates the stints with the packet, returning the stints which started.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<Stint> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = StintTracker::default();
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind_stints(&point);
+        }
+        for event in self.laps.apply(packet) {
+            match event {
+                LapEvent::Completed(lap) => self.lap_completed(&lap),
+                LapEvent::Corrected(lap) => self.lap_corrected(&lap),
+            }
+        }
+        let session_time = header.session_time;
+        let mut started = Vec::new();
+        match packet {
+            Packet::CarStatus(pkt) => {
+                for (car_index, (car, status)) in self
+                    .cars
+                    .iter_mut()
+                    .zip(pkt.car_status_data.iter())
+                    .enumerate()
+                {
+                    // A new set is fitted when the compound changes or the tyres get younger
+                    let new_set = match car.stints.last() {
+                        Some(stint) => {
+                            stint.actual_compound != status.actual_tyre_compound
+                                || stint.visual_compound != status.visual_tyre_compound
+                                || car
+                                    .status
+                                    .as_ref()
+                                    .is_some_and(|old| status.tyres_age_laps < old.tyres_age_laps)
+                        }
+                        None => status.actual_tyre_compound != 0,
+                    };
+                    if new_set {
+                        let stint = Stint {
+                            car_index,
+                            actual_compound: status.actual_tyre_compound,
+                            visual_compound: status.visual_tyre_compound,
+                            start_age_laps: status.tyres_age_laps,
+                            laps: Vec::new(),
+                            session_time,
+                        };
+                        car.stints.push(stint.clone());
+                        started.push(stint);
+                    }
+                    car.status = Some(status.clone());
+                }
+            }
+            Packet::CarDamage(pkt) => self
+                .cars
+                .iter_mut()
+                .zip(pkt.car_damage_data.iter())
+                .for_each(|(car, damage)| car.tyres_wear = damage.tyres_wear),
+            _ => {}
+        }
+        started
+    }
+
+    fn lap_completed(&mut self, lap: &CompletedLap) {
+        let car = &mut self.cars[lap.car_index];
+        let tyres_wear = car.tyres_wear;
+        if let Some(stint) = car.stints.last_mut() {
+            stint.laps.push(StintLap {
+                lap_number: lap.lap_number,
+                tyres_age_laps: lap.tyres_age_laps,
+                lap_time_in_ms: lap.lap_time_in_ms,
+                tyres_wear,
+                valid: lap.valid,
+                pit_lap: lap.pit_in || lap.pit_out,
+                session_time: lap.session_time,
+            });
+        }
+    }
+
+    fn lap_corrected(&mut self, lap: &CompletedLap) {
+        self.cars[lap.car_index]
+            .stints
+            .iter_mut()
+            .flat_map(|stint| stint.laps.iter_mut())
+            .filter(|stint_lap| stint_lap.lap_number == lap.lap_number)
+            .for_each(|stint_lap| {
+                stint_lap.lap_time_in_ms = lap.lap_time_in_ms;
+                stint_lap.valid = lap.valid;
+            });
+    }
+
+    /// Degradation of a compound fitted from the stints of one car.
+    pub fn car_model(&self, car_index: usize, actual_compound: u8) -> Option<DegradationModel> {
+        fit(actual_compound, self.stints(car_index).iter())
+    }
+
+    /// Degradation of a compound fitted from the stints of every car.
+    pub fn compound_model(&self, actual_compound: u8) -> Option<DegradationModel> {
+        fit(
+            actual_compound,
+            self.cars.iter().flat_map(|car| car.stints.iter()),
+        )
+    }
+
+    /// Degradation of every compound used, fitted from the stints of every car.
+    pub fn compound_models(&self) -> BTreeMap<u8, DegradationModel> {
+        let mut compounds: Vec<u8> = self
+            .cars
+            .iter()
+            .flat_map(|car| car.stints.iter().map(|stint| stint.actual_compound))
+            .collect();
+        compounds.sort_unstable();
+        compounds.dedup();
+        compounds
+            .into_iter()
+            .filter_map(|compound| Some((compound, self.compound_model(compound)?)))
+            .collect()
+    }
+
+    /// Laps until the most worn tyre of the car reaches the wear threshold, using the car's own
+    /// degradation on its current compound.
+    pub fn laps_until_wear(&self, car_index: usize, wear_threshold: f32) -> Option<f32> {
+        let stint = self.current_stint(car_index)?;
+        let model = self.car_model(car_index, stint.actual_compound)?;
+        let current_wear = self
+            .tyres_wear(car_index)?
+            .iter()
+            .copied()
+            .fold(0.0, f32::max);
+        model.laps_until(current_wear, wear_threshold)
+    }
+
+    fn rewind_stints(&mut self, point: &FlashbackPoint) {
+        for car in self.cars.iter_mut() {
+            // The tyres get younger again after a flashback, that isn't a new set
+            car.status = None;
+            car.stints
+                .retain(|stint| !point.supersedes(point.session_uid, stint.session_time));
+            for stint in car.stints.iter_mut() {
+                stint
+                    .laps
+                    .retain(|lap| !point.supersedes(point.session_uid, lap.session_time));
+            }
+        }
+    }
+}
+
+impl Rewind for StintTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.laps.rewind(point);
+        self.rewind_stints(point);
+    }
+}
+
+fn fit<'a>(
+    actual_compound: u8,
+    stints: impl Iterator<Item = &'a Stint>,
+) -> Option<DegradationModel> {
+    let laps: Vec<&StintLap> = stints
+        .filter(|stint| stint.actual_compound == actual_compound)
+        .flat_map(|stint| stint.laps.iter())
+        .collect();
+    let wear: Vec<(f32, f32)> = laps
+        .iter()
+        .map(|lap| (lap.tyres_age_laps as f32, lap.max_wear()))
+        .collect();
+    let (wear_per_lap, wear_at_fit) = linear_fit(&wear)?;
+    let lap_times: Vec<(f32, f32)> = laps
+        .iter()
+        .filter(|lap| lap.valid && !lap.pit_lap && lap.lap_time_in_ms > 0)
+        .map(|lap| (lap.tyres_age_laps as f32, lap.lap_time_in_ms as f32))
+        .collect();
+    let lap_time_fit = linear_fit(&lap_times);
+    Some(DegradationModel {
+        actual_compound,
+        wear_per_lap,
+        wear_at_fit,
+        lap_time_per_lap: lap_time_fit.map(|(slope, _)| slope),
+        lap_time_at_fit: lap_time_fit.map(|(_, intercept)| intercept),
+        laps: laps.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketId,
+        telemetry::{PacketCarDamageData, PacketCarStatusData, PacketLapData},
+        testing,
+    };
+
+    const SOFT: u8 = 16;
+    const MEDIUM: u8 = 17;
+
+    fn car_status(session_uid: u64, session_time: f32, compound: u8, age: u8) -> Packet {
+        let mut pkt: PacketCarStatusData =
+            testing::packet(PacketId::CarStatus, session_uid, 0, session_time);
+        pkt.car_status_data[0].actual_tyre_compound = compound;
+        pkt.car_status_data[0].visual_tyre_compound = compound;
+        pkt.car_status_data[0].tyres_age_laps = age;
+        Packet::CarStatus(Box::new(pkt))
+    }
+
+    fn car_damage(session_uid: u64, session_time: f32, wear: f32) -> Packet {
+        let mut pkt: PacketCarDamageData =
+            testing::packet(PacketId::CarDamage, session_uid, 0, session_time);
+        pkt.car_damage_data[0].tyres_wear = [wear; 4];
+        Packet::CarDamage(Box::new(pkt))
+    }
+
+    fn lap_data(session_uid: u64, session_time: f32, lap: u8, last_lap_time_in_ms: u32) -> Packet {
+        let mut pkt: PacketLapData =
+            testing::packet(PacketId::LapData, session_uid, 0, session_time);
+        pkt.lap_data[0].current_lap_num = lap;
+        pkt.lap_data[0].last_lap_time_in_ms = last_lap_time_in_ms;
+        Packet::LapData(Box::new(pkt))
+    }
+
+    /// Drives laps 1 to `laps` on a new set, wearing 2% and losing 100 ms per lap.
+    fn drive(tracker: &mut StintTracker, session_uid: u64, compound: u8, laps: u8) {
+        tracker.apply(&car_status(session_uid, 0.0, compound, 0));
+        tracker.apply(&lap_data(session_uid, 0.0, 1, 0));
+        for lap in 1..=laps {
+            let session_time = lap as f32 * 90.0;
+            tracker.apply(&car_damage(session_uid, session_time, lap as f32 * 2.0));
+            tracker.apply(&car_status(session_uid, session_time, compound, lap));
+            let lap_time = 90_000 + lap as u32 * 100;
+            tracker.apply(&lap_data(session_uid, session_time, lap + 1, lap_time));
+        }
+    }
+
+    #[test]
+    fn first_packet_starts_a_stint() {
+        let mut tracker = StintTracker::new();
+        let started = tracker.apply(&car_status(1, 0.0, SOFT, 2));
+        assert_eq!(started.len(), 1);
+        assert_eq!(started[0].start_age_laps, 2);
+        assert!(tracker.apply(&car_status(1, 1.0, SOFT, 2)).is_empty());
+    }
+
+    #[test]
+    fn fits_the_degradation_of_the_compound() {
+        let mut tracker = StintTracker::new();
+        drive(&mut tracker, 1, SOFT, 5);
+        assert_eq!(tracker.current_stint(0).unwrap().laps.len(), 5);
+        let model = tracker.compound_model(SOFT).unwrap();
+        assert!((model.wear_per_lap - 2.0).abs() < 0.01);
+        assert!((model.lap_time_per_lap.unwrap() - 100.0).abs() < 0.1);
+        assert!((tracker.laps_until_wear(0, 50.0).unwrap() - 20.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn pit_stop_starts_a_new_stint() {
+        let mut tracker = StintTracker::new();
+        drive(&mut tracker, 1, SOFT, 3);
+        let started = tracker.apply(&car_status(1, 300.0, MEDIUM, 0));
+        assert_eq!(started[0].actual_compound, MEDIUM);
+        assert_eq!(tracker.stints(0).len(), 2);
+    }
+
+    #[test]
+    fn flashback_drops_laps_without_a_new_stint() {
+        let mut tracker = StintTracker::new();
+        drive(&mut tracker, 1, SOFT, 5);
+        tracker.apply(&testing::flashback(1, 5000, 450.0, 1000, 100.0));
+        assert_eq!(tracker.current_stint(0).unwrap().laps.len(), 1);
+        // The tyres are younger after the flashback, that isn't a new set
+        assert!(tracker.apply(&car_status(1, 100.0, SOFT, 1)).is_empty());
+        assert_eq!(tracker.stints(0).len(), 1);
+    }
+
+    #[test]
+    fn session_change_resets_the_stints() {
+        let mut tracker = StintTracker::new();
+        drive(&mut tracker, 1, SOFT, 3);
+        tracker.apply(&car_status(2, 0.0, MEDIUM, 0));
+        assert_eq!(tracker.stints(0).len(), 1);
+        assert_eq!(tracker.compound_model(SOFT), None);
+    }
+}
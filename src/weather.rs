@@ -0,0 +1,443 @@
+//! Weather forecast timeline, forecast accuracy and rain alerts
+use serde::Serialize;
+
+use crate::{
+    flashback::{FlashbackPoint, Rewind},
+    packet::Packet,
+    telemetry::{PacketSessionData, WeatherForecastSample},
+};
+
+/// Rain percentages which raise an alert when the forecast crosses them.
+const RAIN_THRESHOLDS: [u8; 3] = [30, 50, 80];
+
+/// The forecast for one session type as it was at a point in the session.
+#[derive(Debug, Clone, Serialize)]
+pub struct Forecast {
+    pub session_type: u8,                    // See `PacketSessionData::session_type`
+    pub session_time: f32,                   // Session time the forecast was received at
+    pub samples: Vec<WeatherForecastSample>, // By time offset
+}
+
+impl Forecast {
+    /// Highest rain percentage of the samples up to the time offset in minutes.
+    pub fn max_rain_percentage(&self, within: u8) -> u8 {
+        self.samples
+            .iter()
+            .filter(|sample| sample.time_offset <= within)
+            .map(|sample| sample.rain_percentage)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn same_as(&self, samples: &[WeatherForecastSample]) -> bool {
+        self.samples.len() == samples.len()
+            && self.samples.iter().zip(samples.iter()).all(|(a, b)| {
+                a.time_offset == b.time_offset
+                    && a.weather == b.weather
+                    && a.track_temperature == b.track_temperature
+                    && a.air_temperature == b.air_temperature
+                    && a.rain_percentage == b.rain_percentage
+            })
+    }
+}
+
+/// The weather at a point in the session.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ActualWeather {
+    pub session_time: f32,
+    pub weather: u8, // See `PacketSessionData::weather`
+    pub track_temperature: i8,
+    pub air_temperature: i8,
+}
+
+/// A forecast sample of the current session compared with the weather once its time came.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ForecastCheck {
+    pub forecast_at: f32, // Session time the forecast was received at
+    pub time_offset: u8,  // Minutes ahead the forecast was for
+    pub forecast_weather: u8,
+    pub rain_percentage: u8,
+    pub actual_weather: u8,
+}
+
+impl ForecastCheck {
+    /// Session time the forecast was for.
+    fn due(&self) -> f32 {
+        self.forecast_at + self.time_offset as f32 * 60.0
+    }
+
+    /// Whether the forecast got rain, or no rain, right.
+    pub fn rain_correct(&self) -> bool {
+        (self.forecast_weather >= 3) == (self.actual_weather >= 3)
+    }
+}
+
+/// The rain percentage of a forecast sample crossing a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RainAlert {
+    pub session_type: u8,
+    pub time_offset: u8, // Minutes ahead of the session time
+    pub threshold: u8,
+    pub rain_percentage: u8,
+    pub rising: bool, // Crossed upwards, rain became more likely
+    pub session_time: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum WeatherEvent {
+    ForecastChanged(Forecast),
+    WeatherChanged { from: u8, to: u8, session_time: f32 },
+    RainAlert(RainAlert),
+}
+
+/// Builds a timeline of the weather forecast of every session type from the session packets,
+/// keeping each version of the forecast to see how it evolves, and the actual weather of the
+/// session to check the forecasts of the current session type against. Raises a [`RainAlert`]
+/// when the rain percentage of a sample crosses one of the thresholds, by default 30, 50 and
+/// 80 percent.
+#[derive(Debug, Clone)]
+pub struct WeatherTracker {
+    session_uid: u64,
+    thresholds: Vec<u8>,
+    session_type: u8,
+    forecasts: Vec<Forecast>,
+    actual: Vec<ActualWeather>,
+    pending: Vec<(f32, ForecastCheck)>, // Session time the check is due and the check
+    checks: Vec<ForecastCheck>,
+}
+
+impl Default for WeatherTracker {
+    fn default() -> Self {
+        WeatherTracker {
+            session_uid: 0,
+            thresholds: RAIN_THRESHOLDS.to_vec(),
+            session_type: 0,
+            forecasts: Vec::new(),
+            actual: Vec::new(),
+            pending: Vec::new(),
+            checks: Vec::new(),
+        }
+    }
+}
+
+impl WeatherTracker {
+    pub fn new() -> Self {
+        WeatherTracker::default()
+    }
+
+    /// Rain percentages which raise an alert when crossed.
+    pub fn rain_thresholds(mut self, thresholds: &[u8]) -> Self {
+        self.thresholds = thresholds.to_vec();
+        self
+    }
+
+    /// Latest forecast of the session type.
+    pub fn forecast(&self, session_type: u8) -> Option<&Forecast> {
+        self.forecasts
+            .iter()
+            .rev()
+            .find(|forecast| forecast.session_type == session_type)
+    }
+
+    /// Every version of the forecast of the session type, oldest first.
+    pub fn history(&self, session_type: u8) -> Vec<&Forecast> {
+        self.forecasts
+            .iter()
+            .filter(|forecast| forecast.session_type == session_type)
+            .collect()
+    }
+
+    /// The weather each time it changed this session, oldest first.
+    pub fn actual(&self) -> &[ActualWeather] {
+        &self.actual
+    }
+
+    /// Forecasts of the current session type checked against the weather so far.
+    pub fn checks(&self) -> &[ForecastCheck] {
+        &self.checks
+    }
+
+    /// Fraction of the checked forecasts which got rain right, None before any was checked.
+    pub fn rain_accuracy(&self) -> Option<f32> {
+        if self.checks.is_empty() {
+            return None;
+        }
+        let correct = self.checks.iter().filter(|c| c.rain_correct()).count();
+        Some(correct as f32 / self.checks.len() as f32)
+    }
+
+    /// Updates the timeline with the packet, returning what changed.
+    pub fn apply(&mut self, packet: &Packet) -> Vec<WeatherEvent> {
+        let header = packet.header();
+        if header.session_uid != self.session_uid {
+            *self = WeatherTracker {
+                thresholds: self.thresholds.clone(),
+                ..Default::default()
+            };
+            self.session_uid = header.session_uid;
+        }
+        if let Some(point) = FlashbackPoint::from_packet(packet) {
+            self.rewind(&point);
+        }
+        match packet {
+            Packet::Session(pkt) => self.apply_session(pkt),
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply_session(&mut self, pkt: &PacketSessionData) -> Vec<WeatherEvent> {
+        let session_time = pkt.header.session_time;
+        self.session_type = pkt.session_type;
+        let mut events = Vec::new();
+
+        match self.actual.last() {
+            Some(last) if last.weather == pkt.weather => {}
+            last => {
+                if let Some(last) = last {
+                    events.push(WeatherEvent::WeatherChanged {
+                        from: last.weather,
+                        to: pkt.weather,
+                        session_time,
+                    });
+                }
+                self.actual.push(ActualWeather {
+                    session_time,
+                    weather: pkt.weather,
+                    track_temperature: pkt.track_temperature,
+                    air_temperature: pkt.air_temperature,
+                });
+            }
+        }
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|(due, _)| *due <= session_time);
+        self.pending = pending;
+        for (_, mut check) in due {
+            check.actual_weather = pkt.weather;
+            self.checks.push(check);
+        }
+
+        let num_samples =
+            (pkt.num_weather_forecast_samples as usize).min(pkt.weather_forecast_samples.len());
+        let samples = &pkt.weather_forecast_samples[..num_samples];
+        let mut session_types: Vec<u8> = samples.iter().map(|s| s.session_type).collect();
+        session_types.sort_unstable();
+        session_types.dedup();
+        for session_type in session_types {
+            let mut samples: Vec<WeatherForecastSample> = samples
+                .iter()
+                .filter(|sample| sample.session_type == session_type)
+                .cloned()
+                .collect();
+            samples.sort_by_key(|sample| sample.time_offset);
+            let previous = self.forecast(session_type);
+            if previous.is_some_and(|previous| previous.same_as(&samples)) {
+                continue;
+            }
+            if let Some(previous) = previous {
+                events.extend(
+                    self.rain_alerts(previous, &samples, session_time)
+                        .into_iter()
+                        .map(WeatherEvent::RainAlert),
+                );
+            }
+            if session_type == self.session_type {
+                // Every version of the forecast is checked against the weather at its time
+                self.pending
+                    .extend(samples.iter().filter(|s| s.time_offset > 0).map(|sample| {
+                        let check = ForecastCheck {
+                            forecast_at: session_time,
+                            time_offset: sample.time_offset,
+                            forecast_weather: sample.weather,
+                            rain_percentage: sample.rain_percentage,
+                            actual_weather: 0,
+                        };
+                        (check.due(), check)
+                    }));
+            }
+            let forecast = Forecast {
+                session_type,
+                session_time,
+                samples,
+            };
+            self.forecasts.push(forecast.clone());
+            events.push(WeatherEvent::ForecastChanged(forecast));
+        }
+        events
+    }
+
+    /// Samples of the same time offset whose rain percentage crossed a threshold.
+    fn rain_alerts(
+        &self,
+        previous: &Forecast,
+        samples: &[WeatherForecastSample],
+        session_time: f32,
+    ) -> Vec<RainAlert> {
+        let mut alerts = Vec::new();
+        for sample in samples {
+            let before = match previous
+                .samples
+                .iter()
+                .find(|s| s.time_offset == sample.time_offset)
+            {
+                Some(before) => before.rain_percentage,
+                None => continue,
+            };
+            let now = sample.rain_percentage;
+            for threshold in self.thresholds.iter().copied() {
+                let rising = before < threshold && now >= threshold;
+                let falling = before >= threshold && now < threshold;
+                if rising || falling {
+                    alerts.push(RainAlert {
+                        session_type: sample.session_type,
+                        time_offset: sample.time_offset,
+                        threshold,
+                        rain_percentage: now,
+                        rising,
+                        session_time,
+                    });
+                }
+            }
+        }
+        alerts
+    }
+}
+
+impl Rewind for WeatherTracker {
+    fn rewind(&mut self, point: &FlashbackPoint) {
+        if point.session_uid != self.session_uid {
+            return;
+        }
+        self.forecasts
+            .retain(|forecast| !point.supersedes(point.session_uid, forecast.session_time));
+        self.actual
+            .retain(|actual| !point.supersedes(point.session_uid, actual.session_time));
+        // Checks due after the point are made again, unless the forecast itself is gone
+        let (superseded, checks): (Vec<_>, Vec<_>) = self
+            .checks
+            .drain(..)
+            .partition(|check| point.supersedes(point.session_uid, check.due()));
+        self.checks = checks;
+        self.pending
+            .extend(superseded.into_iter().map(|check| (check.due(), check)));
+        self.pending
+            .retain(|(_, check)| !point.supersedes(point.session_uid, check.forecast_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{packet::PacketId, testing};
+
+    /// A race session packet with the weather and a forecast of the rain percentages in 5 and
+    /// 10 minutes, light rain from 50 percent.
+    fn session(
+        tracker: &mut WeatherTracker,
+        session_uid: u64,
+        session_time: f32,
+        weather: u8,
+        rain: [u8; 2],
+    ) -> Vec<WeatherEvent> {
+        let mut pkt: PacketSessionData =
+            testing::packet(PacketId::Session, session_uid, 0, session_time);
+        pkt.session_type = 10;
+        pkt.weather = weather;
+        pkt.num_weather_forecast_samples = 2;
+        for (sample, (time_offset, rain_percentage)) in pkt
+            .weather_forecast_samples
+            .iter_mut()
+            .zip([(5, rain[0]), (10, rain[1])])
+        {
+            sample.session_type = 10;
+            sample.time_offset = time_offset;
+            sample.rain_percentage = rain_percentage;
+            sample.weather = if rain_percentage >= 50 { 3 } else { 0 };
+        }
+        tracker.apply(&Packet::Session(Box::new(pkt)))
+    }
+
+    #[test]
+    fn first_forecast_raises_no_alerts() {
+        let mut tracker = WeatherTracker::new();
+        let events = session(&mut tracker, 1, 1.0, 0, [60, 90]);
+        assert!(matches!(events[..], [WeatherEvent::ForecastChanged(_)]));
+        assert_eq!(tracker.actual().len(), 1);
+        // An unchanged forecast isn't a new version
+        assert!(session(&mut tracker, 1, 2.0, 0, [60, 90]).is_empty());
+        assert_eq!(tracker.history(10).len(), 1);
+    }
+
+    #[test]
+    fn rain_crossing_a_threshold_raises_an_alert() {
+        let mut tracker = WeatherTracker::new().rain_thresholds(&[50]);
+        session(&mut tracker, 1, 1.0, 0, [20, 40]);
+        let events = session(&mut tracker, 1, 2.0, 0, [20, 55]);
+        let alerts: Vec<&RainAlert> = events
+            .iter()
+            .filter_map(|event| match event {
+                WeatherEvent::RainAlert(alert) => Some(alert),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            alerts,
+            vec![&RainAlert {
+                session_type: 10,
+                time_offset: 10,
+                threshold: 50,
+                rain_percentage: 55,
+                rising: true,
+                session_time: 2.0,
+            }]
+        );
+        assert_eq!(tracker.forecast(10).unwrap().max_rain_percentage(5), 20);
+        assert_eq!(tracker.history(10).len(), 2);
+    }
+
+    #[test]
+    fn forecasts_are_checked_against_the_weather() {
+        let mut tracker = WeatherTracker::new();
+        session(&mut tracker, 1, 0.0, 0, [60, 10]);
+        assert_eq!(tracker.rain_accuracy(), None);
+        let events = session(&mut tracker, 1, 300.0, 3, [60, 10]);
+        assert!(matches!(
+            events[..],
+            [WeatherEvent::WeatherChanged { from: 0, to: 3, .. }]
+        ));
+        session(&mut tracker, 1, 600.0, 3, [60, 10]);
+        assert_eq!(tracker.checks().len(), 2);
+        // Rain in 5 minutes was right, dry in 10 minutes wasn't
+        assert_eq!(tracker.rain_accuracy(), Some(0.5));
+    }
+
+    #[test]
+    fn flashback_drops_the_later_forecasts_and_checks() {
+        let mut tracker = WeatherTracker::new();
+        session(&mut tracker, 1, 0.0, 0, [60, 10]);
+        session(&mut tracker, 1, 300.0, 3, [70, 80]);
+        assert_eq!(tracker.checks().len(), 1);
+        tracker.apply(&testing::flashback(1, 0, 301.0, 0, 200.0));
+        assert_eq!(tracker.history(10).len(), 1);
+        assert_eq!(tracker.actual().len(), 1);
+        assert!(tracker.checks().is_empty());
+        // The first forecast is still checked as its times come again
+        session(&mut tracker, 1, 600.0, 0, [60, 10]);
+        assert_eq!(tracker.checks().len(), 2);
+        assert_eq!(tracker.rain_accuracy(), Some(0.5));
+    }
+
+    #[test]
+    fn session_change_keeps_the_thresholds() {
+        let mut tracker = WeatherTracker::new().rain_thresholds(&[50]);
+        session(&mut tracker, 1, 1.0, 0, [20, 40]);
+        session(&mut tracker, 2, 1.0, 0, [20, 40]);
+        assert_eq!(tracker.history(10).len(), 1);
+        let events = session(&mut tracker, 2, 2.0, 0, [35, 40]);
+        // 30 percent isn't a threshold any more
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, WeatherEvent::RainAlert(_))));
+    }
+}